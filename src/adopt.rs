@@ -0,0 +1,132 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{command, mounts, state::State, sysroot, Device};
+
+// configuration of the original bash cryptboot
+const LEGACY_CONFIG: &str = "/etc/cryptboot.conf";
+const CRYPTTAB: &str = "/etc/crypttab";
+const FSTAB: &str = "/etc/fstab";
+// directories manual setups commonly move into encrypted boot
+const RELOCATABLE: &[&str] = &["/usr/share/secureboot", "/var/lib/sbctl", "/etc/secureboot"];
+
+/// Boot layout of a system set up by bash cryptboot or by hand
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub mapper: String,
+    pub boot_device: Device,
+    pub boot_mountpoint: PathBuf,
+    pub efi_device: Device,
+    pub efi_mountpoint: PathBuf,
+    // secure boot keys created by cryptboot-efikeys, relative to boot
+    pub keys_dir: Option<PathBuf>,
+}
+
+// shell variables of cryptboot.conf (KEY="value"), expanding $VAR references
+fn parse_conf(conf: &str) -> BTreeMap<String, String> {
+    let mut vars: BTreeMap<String, String> = BTreeMap::new();
+    for line in conf.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some((k, v)) = line.split_once('=') else {
+            continue;
+        };
+        let mut v = v.trim().trim_matches('"').trim_matches('\'').to_string();
+        for (name, value) in vars.iter() {
+            v = v
+                .replace(&format!("${{{name}}}"), value)
+                .replace(&format!("${name}"), value);
+        }
+        vars.insert(k.trim().into(), v);
+    }
+    vars
+}
+
+// `field` of the first line of a crypttab or fstab like `table` whose
+// `key` field is `value`
+fn table_lookup(table: &str, key: usize, value: &str, field: usize) -> Option<String> {
+    table
+        .lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .find(|f| f.get(key).map(|k| mounts::unescape(k)) == Some(value.into()))
+        .and_then(|f| f.get(field).map(|s| s.to_string()))
+}
+
+/// Finds boot layout from bash cryptboot configuration when present, and
+/// from crypttab, fstab and mounts
+pub fn detect() -> anyhow::Result<Layout> {
+    let vars = parse_conf(&fs::read_to_string(sysroot::path(LEGACY_CONFIG)).unwrap_or_default());
+    let var = |name: &str, default: &str| vars.get(name).cloned().unwrap_or(default.into());
+
+    let mapper = var("BOOT_CRYPT_NAME", "cryptboot");
+    let boot_mountpoint = PathBuf::from(var("BOOT_DIR", "/boot"));
+    let efi_mountpoint = PathBuf::from(var("EFI_DIR", "/boot/efi"));
+
+    let crypttab = fs::read_to_string(sysroot::path(CRYPTTAB)).unwrap_or_default();
+    let boot_device = table_lookup(&crypttab, 0, &mapper, 1)
+        .ok_or(anyhow!("no {CRYPTTAB} entry for {mapper}"))?
+        .parse()?;
+
+    let fstab = fs::read_to_string(sysroot::path(FSTAB)).unwrap_or_default();
+    let efi_device = match table_lookup(&fstab, 1, &efi_mountpoint.to_string_lossy(), 0) {
+        Some(d) => d,
+        None => mounts::find_by_target(sysroot::path(&efi_mountpoint))?
+            .map(|m| m.source)
+            .ok_or(anyhow!(
+                "no {FSTAB} entry nor mount for {}",
+                efi_mountpoint.to_string_lossy()
+            ))?,
+    }
+    .parse()?;
+
+    // only bash cryptboot creates keys there
+    let keys_dir = PathBuf::from(var(
+        "EFI_KEYS_DIR",
+        &boot_mountpoint.join("efikeys").to_string_lossy(),
+    ));
+    let keys_dir = keys_dir
+        .strip_prefix(&boot_mountpoint)
+        .ok()
+        .filter(|_| !vars.is_empty())
+        .map(Path::to_path_buf);
+
+    Ok(Layout {
+        mapper,
+        boot_device,
+        boot_mountpoint,
+        efi_device,
+        efi_mountpoint,
+        keys_dir,
+    })
+}
+
+/// Records directories already moved into boot (mounted at `boot_mountpoint`
+/// within sysroot) as relocations
+pub fn record_relocations(boot_mountpoint: &Path) -> anyhow::Result<()> {
+    for dir in RELOCATABLE {
+        let src = sysroot::path(dir);
+        let Ok(target) = fs::read_link(&src) else {
+            continue;
+        };
+        if !target.starts_with(boot_mountpoint) {
+            continue;
+        }
+        if command::dry_run() {
+            command::dry_run_note(format!("record relocation of {dir}"));
+            continue;
+        }
+        State::update(|s| s.add_relocation(Path::new(dir), &target))?;
+        eprintln!(
+            "recorded relocation of {dir} to {}",
+            target.to_string_lossy()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::{fs, io, os::unix::fs::MetadataExt, path::Path};
+
+use crate::{boot, command, grub::Grub, health::Report, luks};
+
+// below this grub unlocks boot fast but brute forcing the passphrase is cheap
+const MIN_PBKDF2_ITERATIONS: u64 = 100_000;
+
+/// Reports files not owned by root or which others may modify, `secret`
+/// files must not be readable by others either. Missing files are ignored.
+pub fn permissions(r: &mut Report, files: &[(&Path, bool)]) {
+    let mut issues = vec![];
+    let mut checked = 0;
+
+    for (path, secret) in files {
+        let meta = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                issues.push(format!("{}: {e}", path.to_string_lossy()));
+                continue;
+            }
+        };
+        checked += 1;
+
+        let mode = meta.mode() & 0o777;
+        if meta.uid() != 0 {
+            issues.push(format!(
+                "{} is owned by uid {}",
+                path.to_string_lossy(),
+                meta.uid()
+            ));
+        }
+        if mode & 0o022 != 0 {
+            issues.push(format!(
+                "{} is writable by group or others ({mode:o})",
+                path.to_string_lossy()
+            ));
+        }
+        if *secret && mode & 0o077 != 0 {
+            issues.push(format!(
+                "{} is readable by group or others ({mode:o})",
+                path.to_string_lossy()
+            ));
+        }
+    }
+
+    match issues.is_empty() {
+        true => r.ok("permissions", format!("{checked} files checked")),
+        false => r.fail("permissions", issues.join(", ")),
+    }
+}
+
+/// Reports LUKS parameters of boot, `grub` being set when grub must unlock it
+pub fn luks(r: &mut Report, header: &luks::Header, grub: Option<&Grub>) {
+    match (header.version, grub) {
+        (2, Some(g)) if !g.supports_luks2() => {
+            r.fail("luks-version", "LUKS2 cannot be unlocked by installed grub")
+        }
+        (1, _) => r.warn(
+            "luks-version",
+            "LUKS1 header has no checksum and no redundant copy",
+        ),
+        (v, _) => r.ok("luks-version", format!("LUKS{v}")),
+    }
+
+    let mut issues = vec![];
+    for s in &header.keyslots {
+        if grub.is_some_and(|g| !g.supports_pbkdf(&s.pbkdf)) {
+            issues.push(format!(
+                "keyslot {} uses {} grub cannot derive",
+                s.id, s.pbkdf
+            ));
+        }
+        if let Some(i) = s.iterations.filter(|i| *i < MIN_PBKDF2_ITERATIONS) {
+            issues.push(format!("keyslot {} has only {i} pbkdf2 iterations", s.id));
+        }
+    }
+    match issues.is_empty() {
+        true => r.ok(
+            "luks-keyslots",
+            format!("{} keyslots checked", header.keyslots.len()),
+        ),
+        false => r.warn("luks-keyslots", issues.join(", ")),
+    }
+}
+
+/// Compares efi identifiers recorded at configure time with those of the device
+pub fn efi_ids(r: &mut Report, efi: &boot::Efi) {
+    if efi.uuid.is_none() && efi.partuuid.is_none() {
+        r.warn(
+            "config-drift",
+            "no efi identifier recorded, device changes cannot be detected",
+        );
+        return;
+    }
+
+    let mut drift = vec![];
+    for (tag, expected) in [("UUID", &efi.uuid), ("PARTUUID", &efi.partuuid)] {
+        let Some(expected) = expected else {
+            continue;
+        };
+        match command::blkid_tag(&efi.device, tag) {
+            Ok(found) if found.as_ref() == Some(expected) => {}
+            Ok(found) => drift.push(format!(
+                "efi {tag} is {} instead of {expected}",
+                found.unwrap_or("unknown".into())
+            )),
+            Err(e) => {
+                r.fail("config-drift", e.to_string());
+                return;
+            }
+        }
+    }
+    match drift.is_empty() {
+        true => r.ok("config-drift", "efi device matches configuration"),
+        false => r.fail("config-drift", drift.join(", ")),
+    }
+}
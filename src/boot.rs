@@ -1,17 +1,287 @@
-use std::path::PathBuf;
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
 
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 
-use crate::{command, Device};
+use crate::{
+    command, events, i18n, luks, mounts, prompt,
+    state::{self, State},
+    sysroot, timing, Device,
+};
 
-const BOOT_MAPPER_NAME: &str = "cryptboot-boot";
+pub const BOOT_MAPPER_NAME: &str = "cryptboot-boot";
+const DEFAULT_UNLOCK_COOLDOWN: u64 = 300;
+// secure boot state, so that TPM2 unlocks only with our signed boot chain
+pub const DEFAULT_TPM2_PCRS: &str = "7";
+// systemd-creds name of sealed key files, checked when unsealing
+const KEYFILE_CREDENTIAL: &str = "cryptboot-boot-key";
+// processes installing kernels unmounting boot would corrupt
+const PACKAGE_MANAGERS: &[&str] = &["pacman", "dpkg", "apt", "apt-get", "dnf", "rpm", "zypper"];
+const PACKAGE_MANAGER_WAIT: Duration = Duration::from_secs(300);
+const DEFAULT_UMOUNT_RETRIES: u32 = 3;
+
+/// Encryption format of boot device
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerType {
+    #[default]
+    Luks,
+    Plain,
+    Bitlk,
+}
+
+impl ContainerType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Luks => "luks",
+            Self::Plain => "plain",
+            Self::Bitlk => "bitlk",
+        }
+    }
+}
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Config {
     // luks device containing boot
-    pub device: PathBuf,
+    pub device: Device,
     pub mountpoint: PathBuf,
     pub efi: Efi,
+    // maximum number of seconds boot stays decrypted during operations
+    // unmounting it (grub-install, run ...)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_exposure: Option<u64>,
+    // LUKS header backup (cryptsetup luksHeaderBackup) monitored by healthcheck
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_backup: Option<PathBuf>,
+    // consecutive failed unlocks after which unlocking requires waiting
+    // unlock_cooldown seconds (or --force)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_failed_unlocks: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unlock_cooldown: Option<u64>,
+    #[serde(default)]
+    pub container_type: ContainerType,
+    // additional cryptsetup open arguments (i.e. --cipher for plain containers)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub open_args: Vec<String>,
+    // ask passphrase through systemd password agents even from a terminal,
+    // they are always used without terminal
+    #[serde(default)]
+    pub ask_password: bool,
+    // PCRs a TPM2 keyslot is bound to (see enroll tpm2)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tpm2_pcrs: Option<String>,
+    // tokens tried in order before asking the passphrase, defaults to every
+    // enrolled one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Vec<Token>>,
+    // key file unlocking boot without prompt (i.e. unattended maintenance),
+    // it must be owned by root and readable by root only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyfile: Option<PathBuf>,
+    #[serde(default)]
+    pub keyfile_seal: Seal,
+    // mount(8) options of the decrypted boot filesystem (i.e. noexec,nodev,nosuid)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mount_options: String,
+    // filesystem type (i.e. ext4), probed with blkid when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<String>,
+    // create missing boot and efi mountpoints instead of failing to mount
+    #[serde(default)]
+    pub create_mountpoints: bool,
+    // unmount attempts made while the mapper or mounts are still active
+    // after unmounting (defaults to DEFAULT_UMOUNT_RETRIES)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umount_retries: Option<u32>,
+    // once retries are exhausted, lazily detach remaining mounts and defer
+    // mapper removal until their last user is gone
+    #[serde(default)]
+    pub umount_escalate: bool,
+    // device-mapper name boot is opened as (defaults to BOOT_MAPPER_NAME),
+    // set when adopting a setup whose crypttab, fstab or hooks use another one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mapper_name: Option<String>,
+}
+
+impl Config {
+    /// Device-mapper name boot is opened as
+    pub fn mapper_name(&self) -> &str {
+        self.mapper_name.as_deref().unwrap_or(BOOT_MAPPER_NAME)
+    }
+}
+
+/// Makes sure nobody but root can read or replace a key file
+pub fn check_keyfile<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let meta =
+        fs::metadata(path).map_err(|e| anyhow!("key file {}: {e}", path.to_string_lossy()))?;
+    if meta.uid() != 0 {
+        return Err(anyhow!(
+            "key file {} is not owned by root",
+            path.to_string_lossy()
+        ));
+    }
+    if meta.mode() & 0o377 != 0 {
+        return Err(anyhow!(
+            "key file {} has mode {:o}, expected 400",
+            path.to_string_lossy(),
+            meta.mode() & 0o7777
+        ));
+    }
+    Ok(())
+}
+
+/// Creates mountpoint `path` with `mode` when it does not exist yet
+pub fn create_mountpoint<P: AsRef<Path>>(path: P, mode: u32) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        return Ok(());
+    }
+    if command::dry_run() {
+        command::dry_run_note(format!("mkdir -m {mode:o} {}", path.to_string_lossy()));
+        return Ok(());
+    }
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(mode)
+        .create(path)
+        .map_err(|e| {
+            anyhow!(
+                "failed to create mountpoint {}: {e}",
+                path.to_string_lossy()
+            )
+        })
+}
+
+/// Random key made of hex digits so that it can be piped as a passphrase
+pub fn generate_key() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 32];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Writes a key file only readable by root, refusing to replace an existing one
+pub fn write_keyfile<P: AsRef<Path>>(path: P, key: &str) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if command::dry_run() {
+        command::dry_run_note(format!("write {}", path.to_string_lossy()));
+        return Ok(());
+    }
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o400)
+        .open(path)
+        .map_err(|e| anyhow!("key file {}: {e}", path.to_string_lossy()))?;
+    f.write_all(key.as_bytes())?;
+    Ok(f.sync_all()?)
+}
+
+/// Stores `key` at `path` protected as `seal` says
+pub fn store_keyfile<P: AsRef<Path>>(path: P, key: &str, seal: Seal) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let Some(with_key) = seal.with_key() else {
+        return write_keyfile(path, key);
+    };
+    if path.exists() {
+        return Err(anyhow!(
+            "key file {} already exists",
+            path.to_string_lossy()
+        ));
+    }
+    command::creds_encrypt(KEYFILE_CREDENTIAL, with_key, key.as_bytes(), path)?;
+    if !command::dry_run() {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o400))?;
+    }
+    Ok(())
+}
+
+/// LUKS2 token unlocking boot without passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Token {
+    Tpm2,
+    // security key, which may need to be touched
+    Fido2,
+}
+
+impl Token {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tpm2 => "systemd-tpm2",
+            Self::Fido2 => "systemd-fido2",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Tpm2 => "TPM2",
+            Self::Fido2 => "FIDO2",
+        }
+    }
+}
+
+/// How the key file is protected on disk
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Seal {
+    // raw key, whoever reads the file unlocks boot
+    #[default]
+    None,
+    // sealed by this machine TPM2
+    Tpm2,
+    // sealed by TPM2 and the host key of the root filesystem
+    HostTpm2,
+}
+
+impl Seal {
+    // systemd-creds --with-key value
+    fn with_key(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Tpm2 => Some("tpm2"),
+            Self::HostTpm2 => Some("host+tpm2"),
+        }
+    }
+
+    /// What an attacker needs to unlock boot with the key file
+    pub fn threat_model(&self) -> &'static str {
+        match self {
+            Self::None => "key file is stored in clear, a copy of it unlocks boot anywhere",
+            Self::Tpm2 => "key file is sealed by TPM2, it only unlocks boot on this machine",
+            Self::HostTpm2 => {
+                "key file is sealed by TPM2 and host key, it only unlocks boot on this machine with its root filesystem"
+            }
+        }
+    }
+}
+
+/// Checks run when mounting boot
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Checks {
+    // repair filesystems of boot and efi with fsck -a before mounting them,
+    // aborting on errors fsck cannot fix
+    pub fsck: bool,
+}
+
+/// How efi partition is mounted along with boot
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EfiMode {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    // efi is not mounted
+    Skip,
 }
 
 #[derive(Debug, Clone)]
@@ -19,6 +289,13 @@ pub struct EncryptedBoot {
     pub config: Config,
     pub name: String,
     pub umount_on_drop: bool,
+    // set when mounted by a transaction (see txn command)
+    pub keep_mounted: bool,
+    pub efi_mode: EfiMode,
+    // ignore unlock cool-down
+    pub force: bool,
+    // check filesystems before mounting them
+    pub fsck: bool,
 }
 
 impl Default for EncryptedBoot {
@@ -27,14 +304,22 @@ impl Default for EncryptedBoot {
             config: Default::default(),
             name: Default::default(),
             umount_on_drop: true,
+            keep_mounted: false,
+            efi_mode: Default::default(),
+            force: false,
+            fsck: false,
         }
     }
 }
 
 impl Drop for EncryptedBoot {
     fn drop(&mut self) {
-        if self.umount_on_drop {
-            self.umount().unwrap()
+        // failures are recorded by umount, panicking here could abort the
+        // process while unwinding
+        if self.umount_on_drop && !self.keep_mounted {
+            if let Err(e) = self.umount() {
+                events::warn(format!("failed to unmount boot: {e}"));
+            }
         }
     }
 }
@@ -42,10 +327,163 @@ impl Drop for EncryptedBoot {
 impl EncryptedBoot {
     pub fn from_config(config: Config) -> Self {
         Self {
+            name: config.mapper_name().into(),
             config,
-            name: BOOT_MAPPER_NAME.into(),
             umount_on_drop: false,
+            keep_mounted: false,
+            efi_mode: EfiMode::ReadWrite,
+            force: false,
+            fsck: false,
+        }
+    }
+
+    /// Device holding the decrypted boot filesystem once unlocked
+    pub fn mapper(&self) -> Device {
+        Device::Mapper(self.name.clone())
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn fsck(mut self, fsck: bool) -> Self {
+        self.fsck = fsck;
+        self
+    }
+
+    /// Refuses to unlock while in cool-down after too many failed attempts
+    fn check_unlock_cooldown(&self) -> anyhow::Result<()> {
+        let Some(max) = self.config.max_failed_unlocks else {
+            return Ok(());
+        };
+
+        if self.force {
+            return Ok(());
         }
+
+        let state = State::load()?;
+        if let Some(last) = state.last_failed_unlock {
+            let cooldown = self
+                .config
+                .unlock_cooldown
+                .unwrap_or(DEFAULT_UNLOCK_COOLDOWN);
+            let remaining = (last + cooldown).saturating_sub(state::now());
+            if state.failed_unlocks >= max && remaining > 0 {
+                return Err(anyhow!(
+                    "{} failed unlock attempts, retry in {remaining}s or use --force",
+                    state.failed_unlocks
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether boot device has a keyslot enrolled for `token`
+    fn has_token(&self, token: Token) -> bool {
+        if self.config.container_type != ContainerType::Luks {
+            return false;
+        }
+        let dev = &self.config.device;
+        state::cached_probe(&format!("cryptsetup:tokens:{dev}"), || {
+            Ok(luks::dump(dev)?.tokens)
+        })
+        .is_ok_and(|t| t.iter().any(|t| t == token.as_str()))
+    }
+
+    pub fn unlock(&self) -> anyhow::Result<()> {
+        self.check_unlock_cooldown()?;
+
+        let mut args = vec![
+            "--type".to_string(),
+            self.config.container_type.as_str().into(),
+        ];
+        args.extend(self.config.open_args.iter().cloned());
+
+        // tokens save typing the passphrase, which is still asked if they
+        // cannot unlock (i.e. PCRs changed, security key missing)
+        let tokens = self
+            .config
+            .tokens
+            .clone()
+            .unwrap_or(vec![Token::Tpm2, Token::Fido2]);
+        for token in tokens.into_iter().filter(|t| self.has_token(*t)) {
+            let mut token_args = args.clone();
+            token_args.extend(["--token-only", "--token-type", token.as_str()].map(String::from));
+            match command::cryptsetup_open(&self.config.device, &self.name, &token_args, None) {
+                Ok(()) => {
+                    if let Err(e) = State::update(|s| s.unlock_succeeded()) {
+                        events::warn(format!("failed to record unlock attempt: {e}"));
+                    }
+                    return Ok(());
+                }
+                Err(e) => events::warn(format!("{} unlock failed: {e}", token.name())),
+            }
+        }
+
+        if let Some(keyfile) = &self.config.keyfile {
+            let keyfile = sysroot::path(keyfile);
+            let res = check_keyfile(&keyfile).and_then(|_| {
+                let dev = &self.config.device;
+                match self.config.keyfile_seal {
+                    Seal::None => {
+                        let mut key_args = args.clone();
+                        key_args.push(format!("--key-file={}", keyfile.to_string_lossy()));
+                        command::cryptsetup_open(dev, &self.name, &key_args, None)
+                    }
+                    // unsealed key never touches disk
+                    _ => {
+                        let key = command::creds_decrypt(KEYFILE_CREDENTIAL, &keyfile)?;
+                        command::cryptsetup_open(dev, &self.name, &args, Some(&key))
+                    }
+                }
+            });
+            match res {
+                Ok(()) => {
+                    if let Err(e) = State::update(|s| s.unlock_succeeded()) {
+                        events::warn(format!("failed to record unlock attempt: {e}"));
+                    }
+                    return Ok(());
+                }
+                Err(e) => events::warn(format!("key file unlock failed, asking passphrase: {e}")),
+            }
+        }
+
+        // desktop password dialogs answer when run from GUI package managers
+        let passphrase = prompt::passphrase(
+            "boot",
+            &i18n::tr(
+                "ask-passphrase",
+                &[("device", &self.config.device.to_string())],
+            ),
+            self.config.ask_password,
+        )?;
+
+        let res = command::cryptsetup_open(
+            &self.config.device.clone(),
+            &self.name,
+            &args,
+            passphrase.as_deref(),
+        );
+
+        let update = match &res {
+            Ok(_) => State::update(|s| s.unlock_succeeded()),
+            Err(e) if matches!(e.downcast_ref(), Some(command::Error::NoKey)) => {
+                State::update(|s| s.unlock_failed())
+            }
+            Err(_) => Ok(()),
+        };
+
+        if let Err(e) = update {
+            events::warn(format!("failed to record unlock attempt: {e}"));
+        }
+
+        res
+    }
+
+    pub fn efi_mode(mut self, mode: EfiMode) -> Self {
+        self.efi_mode = mode;
+        self
     }
 
     pub fn umount_on_drop(mut self) -> Self {
@@ -53,45 +491,353 @@ impl EncryptedBoot {
         self
     }
 
+    /// Boot mounted for a transaction must survive the commands run inside it
+    pub fn keep_mounted(mut self, keep: bool) -> Self {
+        self.keep_mounted = keep;
+        self
+    }
+
     pub fn mount(&mut self) -> anyhow::Result<()> {
         // we mount encrypted partition
-        command::cryptsetup_open(&Device::Path(self.config.device.clone()), BOOT_MAPPER_NAME)?;
-        // we mount the decrypted device
-        command::mount(&Device::Mapper(self.name.clone()), &self.config.mountpoint)?;
-        // we mount efi
-        self.config.efi.mount()?;
+        timing::time("unlock", || self.unlock())?;
+        if self.fsck {
+            timing::time("fsck", || -> anyhow::Result<()> {
+                command::fsck(&self.mapper())?;
+                if self.efi_mode != EfiMode::Skip {
+                    // never repair a device which is not our efi partition
+                    self.config.efi.verify()?;
+                    command::fsck(&self.config.efi.device)?;
+                }
+                Ok(())
+            })?;
+        }
+        timing::time("mount", || -> anyhow::Result<()> {
+            if self.config.create_mountpoints {
+                create_mountpoint(&self.config.mountpoint, 0o700)?;
+            }
+            // we mount the decrypted device
+            command::mount(
+                &Device::Mapper(self.name.clone()),
+                &self.config.mountpoint,
+                self.config.fstype.as_deref(),
+                &[&self.config.mount_options],
+            )?;
+            // efi mountpoint lives in boot filesystem
+            if self.config.create_mountpoints && self.efi_mode != EfiMode::Skip {
+                create_mountpoint(&self.config.efi.mountpoint, 0o755)?;
+            }
+            // we mount efi
+            match self.efi_mode {
+                EfiMode::ReadWrite => self.config.efi.mount(&[]),
+                EfiMode::ReadOnly => self.config.efi.mount(&["ro"]),
+                EfiMode::Skip => Ok(()),
+            }
+        })?;
+
+        if let Err(e) = State::update(|s| s.exposure_started()) {
+            events::warn(format!("failed to record boot exposure: {e}"));
+        }
         Ok(())
     }
 
+    // waits for package managers still using boot or efi (i.e. writing a
+    // kernel) to be done before pulling them out
+    fn wait_package_managers(&self) {
+        if command::dry_run() {
+            return;
+        }
+        let dirs = [
+            self.config.mountpoint.as_path(),
+            self.config.efi.mountpoint.as_path(),
+        ];
+        let deadline = Instant::now() + PACKAGE_MANAGER_WAIT;
+        let mut warned = false;
+        loop {
+            let users = match mounts::users(&dirs, PACKAGE_MANAGERS) {
+                Ok(u) if u.is_empty() => return,
+                Ok(u) => u,
+                Err(e) => {
+                    events::warn(format!("failed to look for processes using boot: {e}"));
+                    return;
+                }
+            };
+            let names = users
+                .iter()
+                .map(|(pid, name)| format!("{name} ({pid})"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            if Instant::now() >= deadline || command::deadline_exceeded() {
+                events::warn(format!("unmounting boot while still used by {names}"));
+                return;
+            }
+            if !warned {
+                events::warn(format!("waiting for {names} to stop using boot"));
+                warned = true;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
     pub fn umount(&self) -> anyhow::Result<()> {
+        self.wait_package_managers();
+        let res = (|| {
+            // we don't care a too much if this one fails
+            let _ = self.config.efi.umount(false);
+            // we always unmount everything
+            command::umount(&self.config.mountpoint, true)?;
+            command::cryptsetup_close(&self.name, false)
+        })();
+        self.verify_closed(res)
+    }
+
+    /// What is still exposed of boot and efi, as seen by the kernel: the
+    /// mapper and any mount under boot or efi mountpoints
+    pub fn leftovers(&self) -> anyhow::Result<Vec<String>> {
+        let mut out = vec![];
+        // device-mapper name of every dm device
+        for e in fs::read_dir("/sys/class/block")? {
+            let name = fs::read_to_string(e?.path().join("dm").join("name")).unwrap_or_default();
+            if name.trim() == self.name {
+                out.push(format!("mapper {} is open", self.name));
+            }
+        }
+        for m in mounts::mounts()? {
+            if m.target.starts_with(&self.config.mountpoint)
+                || m.target.starts_with(&self.config.efi.mountpoint)
+            {
+                out.push(format!("{} is mounted", m.target.to_string_lossy()));
+            }
+        }
+        Ok(out)
+    }
+
+    // lazily detaches remaining mounts, deepest first, and defers closing
+    // the mapper until they are released
+    fn escalate(&self) -> anyhow::Result<()> {
+        let mut targets = mounts::mounts()?
+            .into_iter()
+            .map(|m| m.target)
+            .filter(|t| {
+                t.starts_with(&self.config.mountpoint) || t.starts_with(&self.config.efi.mountpoint)
+            })
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|t| std::cmp::Reverse(t.components().count()));
+        targets.dedup();
+        for t in targets {
+            command::umount_detach(&t)?;
+        }
+        command::cryptsetup_close_deferred(&self.name)
+    }
+
+    /// Makes sure nothing of boot is left exposed after an unmount attempt
+    /// `res`, retrying and escalating as configured. The outcome is recorded
+    /// in state so that an unmount never silently fails.
+    fn verify_closed(&self, res: anyhow::Result<()>) -> anyhow::Result<()> {
+        if command::dry_run() {
+            return res;
+        }
+
+        // an unverifiable unmount is recorded as exposed
+        let check = || {
+            self.leftovers()
+                .unwrap_or_else(|e| vec![format!("unmount cannot be verified: {e}")])
+        };
+        let mut leftovers = check();
+        let retries = self.config.umount_retries.unwrap_or(DEFAULT_UMOUNT_RETRIES);
+        for _ in 0..retries {
+            if leftovers.is_empty() {
+                break;
+            }
+            events::warn(format!(
+                "boot still exposed after unmount ({}), retrying",
+                leftovers.join(", ")
+            ));
+            thread::sleep(Duration::from_secs(1));
+            self.close_silently();
+            leftovers = check();
+        }
+        if !leftovers.is_empty() && self.config.umount_escalate {
+            events::warn(format!(
+                "boot still exposed after {retries} retries, detaching it lazily"
+            ));
+            if let Err(e) = self.escalate() {
+                events::warn(format!("failed to detach boot: {e}"));
+            }
+            leftovers = check();
+        }
+
+        let closed = leftovers.is_empty();
+        let check = state::UmountCheck {
+            at: state::now(),
+            leftovers: leftovers.clone(),
+        };
+        if let Err(e) = State::update(|s| {
+            s.last_umount = Some(check);
+            if closed {
+                s.exposure_ended();
+            }
+        }) {
+            events::warn(format!("failed to record unmount check: {e}"));
+        }
+
+        match (closed, res) {
+            (true, _) => Ok(()),
+            (false, Err(e)) => Err(anyhow!("{e}, boot left exposed: {}", leftovers.join(", "))),
+            (false, Ok(())) => Err(anyhow!("boot left exposed: {}", leftovers.join(", "))),
+        }
+    }
+
+    // unmount attempt ignoring failures, as done on cleanup
+    fn close_silently(&self) {
         // we don't care a too much if this one fails
-        let _ = self.config.efi.umount(&[]);
+        let _ = self.config.efi.umount(true);
         // we always unmount everything
-        command::umount(&self.config.mountpoint, &["-R"])?;
-        command::cryptsetup_close(BOOT_MAPPER_NAME, false)
+        let _ = command::umount(&self.config.mountpoint, true);
+        let _ = command::cryptsetup_close(&self.name, true);
+    }
+
+    /// Returns true if the mapper is open and both boot and efi are mounted
+    pub fn is_mounted(&self) -> anyhow::Result<bool> {
+        Ok(Device::Mapper(self.name.clone()).is_valid()
+            && mounts::is_mounted(&self.config.mountpoint)?
+            && (self.efi_mode == EfiMode::Skip || mounts::is_mounted(&self.config.efi.mountpoint)?))
+    }
+
+    /// Returns true if boot is mounted from the mapper of the configured device
+    /// and efi from its device with the expected mode, so that it can be reused
+    pub fn is_mounted_from_config(&self) -> anyhow::Result<bool> {
+        let mapper = self.mapper();
+        if !mapper.is_valid() {
+            return Ok(false);
+        }
+
+        // device-mapper lists the devices a mapper is built on
+        let Some(dm) = fs::canonicalize(mapper.full_path())?
+            .file_name()
+            .map(|n| n.to_os_string())
+        else {
+            return Ok(false);
+        };
+        let dev = fs::canonicalize(self.config.device.full_path())?;
+        let slaves = PathBuf::from("/sys/class/block").join(dm).join("slaves");
+        if !fs::read_dir(slaves)?
+            .filter_map(|e| e.ok())
+            .any(|e| Some(e.file_name().as_os_str()) == dev.file_name())
+        {
+            return Ok(false);
+        }
+
+        let same_source = |target: &Path, dev: &Device, ro: Option<bool>| -> anyhow::Result<bool> {
+            Ok(mounts::find_by_target(target)?.is_some_and(|m| {
+                fs::canonicalize(&m.source).ok() == fs::canonicalize(dev.full_path()).ok()
+                    && ro.is_none_or(|ro| m.options.split(',').any(|o| o == "ro") == ro)
+            }))
+        };
+        let efi = match self.efi_mode {
+            EfiMode::ReadWrite => Some(false),
+            EfiMode::ReadOnly => Some(true),
+            EfiMode::Skip => None,
+        };
+        Ok(same_source(&self.config.mountpoint, &mapper, None)?
+            && (efi.is_none()
+                || same_source(&self.config.efi.mountpoint, &self.config.efi.device, efi)?))
+    }
+
+    /// Returns true if any of the mapper, boot or efi is still active
+    pub fn is_partially_mounted(&self) -> anyhow::Result<bool> {
+        Ok(Device::Mapper(self.name.clone()).is_valid()
+            || mounts::is_mounted(&self.config.mountpoint)?
+            || mounts::is_mounted(&self.config.efi.mountpoint)?)
     }
 
     pub fn reset(&self) {
-        // we don't care a too much if this one fails
-        let _ = self.config.efi.umount(&["-qR"]);
-        // we always unmount everything
-        let _ = command::umount(&self.config.mountpoint, &["-qR"]);
-        let _ = command::cryptsetup_close(BOOT_MAPPER_NAME, true);
+        self.wait_package_managers();
+        self.close_silently();
+        if let Err(e) = self.verify_closed(Ok(())) {
+            events::warn(e.to_string());
+        }
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("efi device {device} {tag} mismatch, expected {expected} but found {found} (was the device repartitioned?)")]
+    EfiMismatch {
+        device: String,
+        tag: &'static str,
+        expected: String,
+        found: String,
+    },
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Efi {
-    pub device: PathBuf,
+    pub device: Device,
     pub mountpoint: PathBuf,
+    // filesystem and partition uuids recorded at configure time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partuuid: Option<String>,
+    // how efi is mounted by the mount command, operations always mount it
+    // read-write and unmount it when they are done
+    #[serde(default)]
+    pub mount_mode: EfiMode,
+    // mount(8) options of efi (i.e. umask=0077 so that only root reads it)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mount_options: String,
+    // filesystem type (i.e. vfat), probed with blkid when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<String>,
 }
 
 impl Efi {
-    fn mount(&self) -> anyhow::Result<()> {
-        command::mount(&Device::Path(self.device.clone()), &self.mountpoint)
+    /// Records the identifiers of the current efi device
+    pub fn probe_ids(&mut self) -> anyhow::Result<()> {
+        let dev = self.device.clone();
+        self.uuid = command::blkid_tag(&dev, "UUID")?;
+        self.partuuid = command::blkid_tag(&dev, "PARTUUID")?;
+        Ok(())
+    }
+
+    /// Makes sure efi device is still the one recorded in configuration
+    fn verify(&self) -> anyhow::Result<()> {
+        let dev = self.device.clone();
+
+        for (tag, expected) in [("UUID", &self.uuid), ("PARTUUID", &self.partuuid)] {
+            let Some(expected) = expected else {
+                continue;
+            };
+
+            // probed live, a swapped partition must never pass as cached
+            let found = command::blkid_tag(&dev, tag)?.unwrap_or_default();
+            if &found != expected {
+                return Err(Error::EfiMismatch {
+                    device: dev.to_string(),
+                    tag,
+                    expected: expected.clone(),
+                    found,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mount(&self, options: &[&str]) -> anyhow::Result<()> {
+        self.verify()?;
+        let mut options = options.to_vec();
+        options.push(&self.mount_options);
+        command::mount(
+            &self.device.clone(),
+            &self.mountpoint,
+            self.fstype.as_deref(),
+            &options,
+        )
     }
 
-    fn umount(&self, args: &[&str]) -> anyhow::Result<()> {
-        command::umount(&self.mountpoint, args)
+    fn umount(&self, recursive: bool) -> anyhow::Result<()> {
+        command::umount(&self.mountpoint, recursive)
     }
 }
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::boot;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Kind {
+    // grub unlocking encrypted boot by itself
+    #[default]
+    Grub,
+    // systemd-boot booting kernels or UKIs stored on EFI partition
+    SystemdBoot,
+    // firmware booting kernel EFI stub directly
+    Efistub,
+}
+
+/// Bootloader installed on EFI partition by the grub-install command
+pub(crate) trait Bootloader {
+    /// Generates boot menu configuration
+    fn mkconfig(&self, cfg: &boot::Config) -> anyhow::Result<()>;
+
+    /// Installs bootloader on EFI partition
+    fn install(&self, cfg: &boot::Config) -> anyhow::Result<()>;
+
+    /// Arms boot counting for the freshly installed boot chain
+    fn arm_boot_counter(&self, _cfg: &boot::Config) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Flags the current boot as successful
+    fn mark_good(&self, _cfg: &boot::Config) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Files and directories written by mkconfig and install
+    fn managed_files(&self, cfg: &boot::Config) -> Vec<PathBuf>;
+
+    /// EFI binaries written by install which must be signed
+    fn signed_files(&self, _cfg: &boot::Config) -> Vec<PathBuf> {
+        vec![]
+    }
+
+    fn version(&self) -> anyhow::Result<String>;
+}
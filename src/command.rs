@@ -1,7 +1,154 @@
 use anyhow::anyhow;
-use std::{ffi::OsStr, path::PathBuf, process::Stdio};
+use std::{
+    ffi::{CString, OsStr},
+    io::Write,
+    os::unix::{ffi::OsStrExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::Device;
+use crate::{events, mounts, output, topology, Device};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("cryptsetup open failed: no key available with this passphrase")]
+    NoKey,
+}
+
+static DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+// dry-run notes recorded instead of printed, see [record_notes]
+static NOTES: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// In dry-run mode commands changing the system are printed instead of executed
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed)
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Records dry-run notes until [take_notes] is called instead of printing them
+pub fn record_notes() {
+    *NOTES.lock().unwrap() = Some(vec![]);
+}
+
+/// Returns recorded notes and stops recording
+pub fn take_notes() -> Vec<String> {
+    NOTES.lock().unwrap().take().unwrap_or_default()
+}
+
+/// Prints what would have been done, on stderr in JSON output mode to keep stdout parsable
+pub fn dry_run_note<S: AsRef<str>>(msg: S) {
+    if let Some(notes) = NOTES.lock().unwrap().as_mut() {
+        notes.push(msg.as_ref().into());
+        return;
+    }
+    match output::json() {
+        true => eprintln!("dry-run: {}", msg.as_ref()),
+        false => println!("dry-run: {}", msg.as_ref()),
+    }
+}
+
+// prints the command and the environment we set (PATH excepted)
+fn print_dry_run(cmd: &std::process::Command) {
+    let mut line = String::new();
+    for (k, v) in cmd.get_envs() {
+        if let (Some(v), false) = (v, k == "PATH") {
+            line.push_str(&format!("{}={:?} ", k.to_string_lossy(), v));
+        }
+    }
+    line.push_str(&cmd.get_program().to_string_lossy());
+    for a in cmd.get_args() {
+        line.push_str(&format!(" {:?}", a));
+    }
+    dry_run_note(line);
+}
+
+/// Runs a cleanup command, not subject to the deadline
+fn cleanup_status(cmd: &mut std::process::Command) -> anyhow::Result<ExitStatus> {
+    if dry_run() {
+        print_dry_run(cmd);
+        return Ok(ExitStatus::from_raw(0));
+    }
+    Ok(cmd.status()?)
+}
+
+/// Sets a deadline after which commands run through [status] are killed,
+/// the earliest deadline wins when called several times
+pub fn set_deadline(deadline: Instant) {
+    let mut d = DEADLINE.lock().unwrap();
+    *d = Some(d.map_or(deadline, |cur| cur.min(deadline)));
+}
+
+/// Whether the deadline set with [set_deadline] is reached
+pub fn deadline_exceeded() -> bool {
+    DEADLINE
+        .lock()
+        .unwrap()
+        .is_some_and(|d| Instant::now() >= d)
+}
+
+/// Runs a command to completion and kills it if the deadline is reached.
+/// Cleanup commands (umount, cryptsetup close) must not go through this
+/// function so that they can still run once the deadline is exceeded.
+pub fn status(cmd: &mut std::process::Command) -> anyhow::Result<ExitStatus> {
+    status_with_input(cmd, None)
+}
+
+/// Same as [status], writing `input` to the command standard input
+pub fn status_with_input(
+    cmd: &mut std::process::Command,
+    input: Option<&[u8]>,
+) -> anyhow::Result<ExitStatus> {
+    if dry_run() {
+        print_dry_run(cmd);
+        return Ok(ExitStatus::from_raw(0));
+    }
+
+    // stdout is reserved to the JSON result
+    if output::json() {
+        cmd.stdout(std::io::stderr());
+    }
+
+    let deadline = *DEADLINE.lock().unwrap();
+    let program = cmd.get_program().to_string_lossy().to_string();
+    if deadline_exceeded() {
+        return Err(anyhow!("deadline exceeded before running {program}"));
+    }
+
+    if input.is_some() {
+        cmd.stdin(Stdio::piped());
+    }
+    let mut child = cmd.spawn()?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // stdin is closed when dropped so that the command sees end of input
+        stdin.write_all(input)?;
+    }
+
+    let Some(deadline) = deadline else {
+        return Ok(child.wait()?);
+    };
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("deadline exceeded while running {program}"));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
 
 pub fn command<S: AsRef<OsStr>>(program: S) -> std::process::Command {
     let mut cmd = std::process::Command::new(program);
@@ -9,15 +156,28 @@ pub fn command<S: AsRef<OsStr>>(program: S) -> std::process::Command {
     cmd
 }
 
-pub fn cryptsetup_open<S: AsRef<str>>(dev: &Device, name: S) -> anyhow::Result<()> {
+/// Opens `dev` as `name`, cryptsetup prompts for the passphrase unless one is given
+pub fn cryptsetup_open<S: AsRef<str>, A: AsRef<OsStr>>(
+    dev: &Device,
+    name: S,
+    args: &[A],
+    passphrase: Option<&str>,
+) -> anyhow::Result<()> {
     if !dev.is_valid() {
         return Err(anyhow!("cryptsetup open error invalid device: {}", dev));
     }
-    let status = command("cryptsetup")
-        .arg("open")
-        .arg(dev.full_path())
-        .arg(name.as_ref())
-        .status()?;
+    let mut cmd = command("cryptsetup");
+    cmd.arg("open").args(args);
+    if passphrase.is_some() {
+        cmd.arg("--key-file=-");
+    }
+    cmd.arg(dev.full_path()).arg(name.as_ref());
+    let status = status_with_input(&mut cmd, passphrase.map(|p| p.as_bytes()))?;
+
+    // cryptsetup exits with code 2 on wrong passphrase
+    if status.code() == Some(2) {
+        return Err(Error::NoKey.into());
+    }
 
     if !status.success() {
         return Err(anyhow!("cryptsetup open failed: {}", status));
@@ -25,6 +185,159 @@ pub fn cryptsetup_open<S: AsRef<str>>(dev: &Device, name: S) -> anyhow::Result<(
     Ok(())
 }
 
+/// Encrypts `input` into credential file `output`, `with_key` selecting what
+/// it is sealed with (i.e. tpm2, host+tpm2)
+pub fn creds_encrypt<P: AsRef<Path>>(
+    name: &str,
+    with_key: &str,
+    input: &[u8],
+    output: P,
+) -> anyhow::Result<()> {
+    let status = status_with_input(
+        command("systemd-creds")
+            .arg("encrypt")
+            .arg(format!("--name={name}"))
+            .arg(format!("--with-key={with_key}"))
+            .arg("-")
+            .arg(output.as_ref()),
+        Some(input),
+    )?;
+    if !status.success() {
+        return Err(anyhow!("systemd-creds encrypt failed: {status}"));
+    }
+    Ok(())
+}
+
+/// Decrypts credential file `input`, nothing is decrypted in dry-run mode
+pub fn creds_decrypt<P: AsRef<Path>>(name: &str, input: P) -> anyhow::Result<String> {
+    let mut cmd = command("systemd-creds");
+    cmd.arg("decrypt")
+        .arg(format!("--name={name}"))
+        .arg(input.as_ref())
+        .arg("-");
+    if dry_run() {
+        print_dry_run(&cmd);
+        return Ok(String::new());
+    }
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(anyhow!("systemd-creds decrypt failed: {}", output.status));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Asks a question through systemd ask-password agents, `echo` shows the answer as typed
+pub fn ask_password<S: AsRef<str>>(id: &str, message: S, echo: bool) -> anyhow::Result<String> {
+    let mut cmd = command("systemd-ask-password");
+    cmd.arg("--no-tty")
+        .arg(format!("--id=cryptboot:{id}"))
+        .arg("--icon=drive-harddisk");
+    if echo {
+        cmd.arg("--echo");
+    }
+    let output = cmd.arg(message.as_ref()).output()?;
+    if !output.status.success() {
+        return Err(anyhow!("systemd-ask-password failed: {}", output.status));
+    }
+
+    let mut answer = String::from_utf8(output.stdout)?;
+    if answer.ends_with('\n') {
+        answer.pop();
+    }
+    Ok(answer)
+}
+
+pub fn cryptsetup_uuid(dev: &Device) -> anyhow::Result<String> {
+    let output = command("cryptsetup")
+        .arg("luksUUID")
+        .arg(dev.full_path())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "cryptsetup luksUUID failed on {}: {}",
+            dev,
+            output.status
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Numbers of the firmware boot entries labelled `label`
+pub fn efi_boot_entries(label: &str) -> anyhow::Result<Vec<String>> {
+    let output = command("efibootmgr").output()?;
+    if !output.status.success() {
+        return Err(anyhow!("efibootmgr failed: {}", output.status));
+    }
+
+    // Boot0003* Linux (cryptboot)	HD(1,GPT,...)/File(\EFI\cryptboot\linux.efi)
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|l| l.strip_prefix("Boot"))
+        .filter_map(|l| {
+            let (num, rest) = l.split_at_checked(4)?;
+            let entry = rest.trim_start_matches('*').trim_start();
+            (entry.split('\t').next() == Some(label)).then(|| num.to_string())
+        })
+        .collect())
+}
+
+/// Replaces the firmware boot entries labelled `label` with one loading
+/// `loader` (i.e. \EFI\cryptboot\linux.efi) from partition `efi` with
+/// optional `args`
+pub fn efi_boot_entry(
+    efi: &Device,
+    label: &str,
+    loader: &str,
+    args: Option<&str>,
+) -> anyhow::Result<()> {
+    for num in efi_boot_entries(label)? {
+        let status = status(
+            command("efibootmgr")
+                .arg("--quiet")
+                .args(["--bootnum", &num])
+                .arg("--delete-bootnum"),
+        )?;
+        if !status.success() {
+            return Err(anyhow!("failed to delete boot entry {num}: {status}"));
+        }
+    }
+
+    let (disk, part) = topology::partition(efi.full_path())?;
+    let mut cmd = command("efibootmgr");
+    cmd.arg("--quiet")
+        .arg("--create")
+        .arg("--disk")
+        .arg(disk)
+        .args(["--part", &part.to_string()])
+        .args(["--label", label])
+        .args(["--loader", loader]);
+    if let Some(args) = args {
+        cmd.args(["--unicode", args]);
+    }
+
+    let status = status(&mut cmd)?;
+    if !status.success() {
+        return Err(anyhow!("efibootmgr --create failed: {status}"));
+    }
+    Ok(())
+}
+
+/// Removes mapper `name` once its last user closes it
+pub fn cryptsetup_close_deferred<S: AsRef<OsStr>>(name: S) -> anyhow::Result<()> {
+    let status = cleanup_status(
+        command("cryptsetup")
+            .arg("close")
+            .arg("--deferred")
+            .arg(name.as_ref()),
+    )?;
+    if !status.success() {
+        return Err(anyhow!("cryptsetup close --deferred failed: {}", status));
+    }
+    Ok(())
+}
+
 pub fn cryptsetup_close<S: AsRef<OsStr>>(name: S, silent: bool) -> anyhow::Result<()> {
     let mut cmd = command("cryptsetup");
 
@@ -34,7 +347,7 @@ pub fn cryptsetup_close<S: AsRef<OsStr>>(name: S, silent: bool) -> anyhow::Resul
         cmd.stderr(Stdio::null()).stdout(Stdio::null());
     }
 
-    let status = cmd.status()?;
+    let status = cleanup_status(&mut cmd)?;
 
     if !status.success() {
         return Err(anyhow!("cryptsetup close failed: {}", status));
@@ -42,7 +355,61 @@ pub fn cryptsetup_close<S: AsRef<OsStr>>(name: S, silent: bool) -> anyhow::Resul
     Ok(())
 }
 
-pub fn mount(dev: &Device, mountpoint: &PathBuf) -> anyhow::Result<()> {
+// mount options passed as mount(2) flags, others are filesystem data
+const MOUNT_FLAGS: &[(&str, libc::c_ulong)] = &[
+    ("ro", libc::MS_RDONLY),
+    ("nosuid", libc::MS_NOSUID),
+    ("nodev", libc::MS_NODEV),
+    ("noexec", libc::MS_NOEXEC),
+    ("sync", libc::MS_SYNCHRONOUS),
+    ("dirsync", libc::MS_DIRSYNC),
+    ("noatime", libc::MS_NOATIME),
+    ("nodiratime", libc::MS_NODIRATIME),
+    ("relatime", libc::MS_RELATIME),
+    ("strictatime", libc::MS_STRICTATIME),
+];
+
+fn cstring<S: AsRef<OsStr>>(s: S) -> anyhow::Result<CString> {
+    Ok(CString::new(s.as_ref().as_bytes())?)
+}
+
+// filesystems tried when blkid cannot tell, like mount(8) does
+fn kernel_filesystems() -> Vec<String> {
+    std::fs::read_to_string("/proc/filesystems")
+        .unwrap_or_default()
+        .lines()
+        .filter(|l| !l.starts_with("nodev"))
+        .map(|l| l.trim().to_string())
+        .collect()
+}
+
+/// Mounts `dev` on `mountpoint` with mount(2), `options` being mount(8) -o
+/// options (i.e. ro, noexec, umask=0077). Filesystem type is probed with
+/// blkid when `fstype` is not given.
+pub fn mount(
+    dev: &Device,
+    mountpoint: &PathBuf,
+    fstype: Option<&str>,
+    options: &[&str],
+) -> anyhow::Result<()> {
+    let options: Vec<_> = options
+        .iter()
+        .flat_map(|o| o.split(','))
+        .filter(|o| !o.is_empty())
+        .collect();
+    if dry_run() {
+        let opts = match options.is_empty() {
+            true => String::new(),
+            false => format!(" -o {}", options.join(",")),
+        };
+        let t = fstype.map(|t| format!(" -t {t}")).unwrap_or_default();
+        dry_run_note(format!(
+            "mount{t}{opts} {} {}",
+            dev.full_path().to_string_lossy(),
+            mountpoint.to_string_lossy()
+        ));
+        return Ok(());
+    }
     if !dev.is_valid() {
         return Err(anyhow!("mount error invalid device: {}", dev));
     }
@@ -52,28 +419,455 @@ pub fn mount(dev: &Device, mountpoint: &PathBuf) -> anyhow::Result<()> {
             mountpoint.to_string_lossy()
         ));
     }
-    let status = command("mount")
+
+    let mut flags = 0;
+    let mut data = vec![];
+    for o in options {
+        match MOUNT_FLAGS.iter().find(|(name, _)| *name == o) {
+            Some((_, f)) => flags |= f,
+            None if matches!(o, "rw" | "defaults") => {}
+            None => data.push(o),
+        }
+    }
+
+    let source = cstring(dev.full_path())?;
+    let target = cstring(mountpoint)?;
+    let data = cstring(data.join(","))?;
+    // stale superblocks of former filesystems may fool blkid
+    let fstypes = match fstype {
+        Some(t) => vec![t.into()],
+        // every filesystem the kernel knows is tried when probing fails
+        None => match blkid_tag(dev, "TYPE") {
+            Ok(Some(t)) => vec![t],
+            _ => kernel_filesystems(),
+        },
+    };
+
+    let mut err = std::io::Error::from_raw_os_error(libc::ENODEV);
+    for fstype in fstypes {
+        let fstype = cstring(&fstype)?;
+        let res = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                fstype.as_ptr(),
+                flags,
+                data.as_ptr() as *const libc::c_void,
+            )
+        };
+        if res == 0 {
+            return Ok(());
+        }
+        err = std::io::Error::last_os_error();
+        // wrong filesystem type, others are real errors
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            break;
+        }
+    }
+    Err(anyhow!(
+        "failed to mount {} on {}: {err}",
+        dev,
+        mountpoint.to_string_lossy()
+    ))
+}
+
+/// Checks and repairs what can safely be repaired on `dev` (fsck -a), which
+/// must not be mounted
+pub fn fsck(dev: &Device) -> anyhow::Result<()> {
+    let status = status(command("fsck").arg("-a").arg(dev.full_path()))?;
+    // fsck exit code is a bit mask, 1 and 2 mean errors were corrected
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(c @ (1..=3)) => {
+            events::warn(format!("fsck corrected errors on {dev} (exit code {c})"));
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "fsck found errors it cannot fix on {dev}: {status}"
+        )),
+    }
+}
+
+/// Returns the value of a blkid tag (UUID, PARTUUID, TYPE ...) for a device
+pub fn blkid_tag<S: AsRef<str>>(dev: &Device, tag: S) -> anyhow::Result<Option<String>> {
+    let output = command("blkid")
+        .arg("-s")
+        .arg(tag.as_ref())
+        .arg("-o")
+        .arg("value")
+        .arg(dev.full_path())
+        .output()?;
+
+    // blkid exits with status 2 when tag is not found
+    if output.status.code() == Some(2) {
+        return Ok(None);
+    }
+
+    if !output.status.success() {
+        return Err(anyhow!("blkid failed on {}: {}", dev, output.status));
+    }
+
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok((!value.is_empty()).then_some(value))
+}
+
+/// Returns the size in bytes of a block device
+pub fn blockdev_size(dev: &Device) -> anyhow::Result<u64> {
+    let output = command("blockdev")
+        .arg("--getsize64")
         .arg(dev.full_path())
-        .arg(mountpoint)
-        .status()?;
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!("blockdev failed on {}: {}", dev, output.status));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().parse()?)
+}
+
+/// Returns the number of reserved blocks of an ext2/3/4 filesystem
+pub fn ext_reserved_blocks(dev: &Device) -> anyhow::Result<u64> {
+    let output = command("tune2fs").arg("-l").arg(dev.full_path()).output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!("tune2fs failed on {}: {}", dev, output.status));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|l| l.strip_prefix("Reserved block count:"))
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or(anyhow!("failed to parse tune2fs output"))
+}
+
+pub fn grub_editenv<P: AsRef<Path>>(env_file: P, args: &[&str]) -> anyhow::Result<()> {
+    let status = status(command("grub-editenv").arg(env_file.as_ref()).args(args))?;
+    if !status.success() {
+        return Err(anyhow!("grub-editenv {} failed: {status}", args.join(" ")));
+    }
+    Ok(())
+}
+
+pub fn sbctl(args: &[&str]) -> anyhow::Result<()> {
+    let status = status(command("sbctl").args(args))?;
+    if !status.success() {
+        return Err(anyhow!("sbctl {} failed: {status}", args.join(" ")));
+    }
+    Ok(())
+}
+
+/// Loads a kernel to be booted by the next kexec, `file_load` uses
+/// kexec_file_load which checks kernel signature under lockdown
+pub fn kexec_load<P: AsRef<Path>, Q: AsRef<Path>>(
+    kernel: P,
+    initrd: Option<Q>,
+    cmdline: &str,
+    file_load: bool,
+) -> anyhow::Result<()> {
+    let mut cmd = command("kexec");
+    cmd.arg("--load").arg(kernel.as_ref());
+    if file_load {
+        cmd.arg("--kexec-file-syscall");
+    }
+    if let Some(initrd) = initrd {
+        cmd.arg(format!("--initrd={}", initrd.as_ref().to_string_lossy()));
+    }
+    cmd.arg(format!("--command-line={cmdline}"));
+
+    let status = status(&mut cmd)?;
+    if !status.success() {
+        return Err(anyhow!("kexec load failed: {status}"));
+    }
+    Ok(())
+}
+
+pub fn systemctl(args: &[&str]) -> anyhow::Result<()> {
+    let status = status(command("systemctl").args(args))?;
+    if !status.success() {
+        return Err(anyhow!("systemctl {} failed: {status}", args.join(" ")));
+    }
+    Ok(())
+}
+
+/// Posts `data` to `url` with curl
+pub fn curl_post(url: &str, data: &[u8]) -> anyhow::Result<()> {
+    let status = status_with_input(
+        command("curl")
+            .arg("--fail")
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--data-binary")
+            .arg("@-")
+            .arg(url),
+        Some(data),
+    )?;
+    if !status.success() {
+        return Err(anyhow!("curl {url} failed: {status}"));
+    }
+    Ok(())
+}
+
+/// Signs `file` and registers it in sbctl database so that sign-all keeps it signed
+pub fn sbctl_sign<P: AsRef<Path>>(file: P) -> anyhow::Result<()> {
+    let file = file.as_ref();
+    let status = status(command("sbctl").arg("sign").arg("-s").arg(file))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "sbctl sign {} failed: {status}",
+            file.to_string_lossy()
+        ));
+    }
+    Ok(())
+}
+
+/// Returns files sbctl knows about which are not signed
+pub fn sbctl_unsigned() -> anyhow::Result<Vec<String>> {
+    let output = command("sbctl").arg("verify").output()?;
+    if !output.status.success() {
+        return Err(anyhow!("sbctl verify failed: {}", output.status));
+    }
+
+    // ✗ /boot/vmlinuz-linux is not signed
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|l| l.strip_suffix(" is not signed"))
+        .filter_map(|l| l.find('/').map(|i| l[i..].to_string()))
+        .collect())
+}
+
+// gpg finds its keyring and agent through the environment
+fn gpg() -> std::process::Command {
+    let mut cmd = command("gpg");
+    for var in ["HOME", "GNUPGHOME"] {
+        if let Ok(v) = std::env::var(var) {
+            cmd.env(var, v);
+        }
+    }
+    cmd.arg("--batch").arg("--yes");
+    cmd
+}
+
+/// Writes a detached signature of `file` made with gpg `key` to `file`.sig,
+/// as verified by grub check_signatures
+pub fn gpg_detach_sign<P: AsRef<Path>>(key: &str, file: P) -> anyhow::Result<()> {
+    let file = file.as_ref();
+    let mut sig = file.as_os_str().to_owned();
+    sig.push(".sig");
+    let status = status(
+        gpg()
+            .arg("--local-user")
+            .arg(key)
+            .arg("--output")
+            .arg(sig)
+            .arg("--detach-sign")
+            .arg(file),
+    )?;
     if !status.success() {
-        return Err(anyhow!("failed to mount {}: {}", dev, status));
+        return Err(anyhow!(
+            "gpg failed to sign {}: {status}",
+            file.to_string_lossy()
+        ));
     }
     Ok(())
 }
 
-pub fn sbctl<S: AsRef<str>>(cmd: S) -> anyhow::Result<()> {
-    let status = command("sbctl").arg(cmd.as_ref()).status()?;
+/// Exports public gpg `key` to `out` in binary format, as grub --pubkey expects
+pub fn gpg_export<P: AsRef<Path>>(key: &str, out: P) -> anyhow::Result<()> {
+    let out = out.as_ref();
+    let status = status(gpg().arg("--output").arg(out).arg("--export").arg(key))?;
     if !status.success() {
-        return Err(anyhow!("sbctl {} failed: {status}", cmd.as_ref()));
+        return Err(anyhow!("gpg failed to export {key}: {status}"));
+    }
+    // gpg succeeds exporting nothing when the key is unknown
+    if !dry_run() && std::fs::metadata(out)?.len() == 0 {
+        return Err(anyhow!("gpg key {key} not found"));
     }
     Ok(())
 }
 
-pub fn umount(mountpoint: &PathBuf, args: &[&str]) -> anyhow::Result<()> {
-    let status = command("umount").args(args).arg(mountpoint).status()?;
+/// Signs an EFI binary in place, `key` is a PKCS#11 URI when `engine` is pkcs11
+pub fn sbsign<K: AsRef<OsStr>, P: AsRef<Path>, Q: AsRef<Path>>(
+    engine: Option<&str>,
+    key: K,
+    cert: P,
+    file: Q,
+) -> anyhow::Result<()> {
+    let file = file.as_ref();
+    let mut signed = file.as_os_str().to_owned();
+    signed.push(".signed");
+
+    let mut cmd = command("sbsign");
+    if let Some(engine) = engine {
+        cmd.arg("--engine").arg(engine);
+    }
+    let status = status(
+        cmd.arg("--key")
+            .arg(key.as_ref())
+            .arg("--cert")
+            .arg(cert.as_ref())
+            .arg("--output")
+            .arg(&signed)
+            .arg(file),
+    )?;
     if !status.success() {
-        return Err(anyhow!("failed to umount: {}", status));
+        return Err(anyhow!(
+            "sbsign failed on {}: {status}",
+            file.to_string_lossy()
+        ));
+    }
+
+    if dry_run() {
+        return Ok(());
+    }
+    Ok(std::fs::rename(signed, file)?)
+}
+
+/// Signs an EFI binary in place with the certificate `nickname` of NSS database `db`
+pub fn pesign<P: AsRef<Path>, Q: AsRef<Path>>(
+    db: P,
+    nickname: &str,
+    file: Q,
+) -> anyhow::Result<()> {
+    let file = file.as_ref();
+    let mut signed = file.as_os_str().to_owned();
+    signed.push(".signed");
+
+    let status = status(
+        command("pesign")
+            .arg("--certdir")
+            .arg(db.as_ref())
+            .arg("--certificate")
+            .arg(nickname)
+            .arg("--in")
+            .arg(file)
+            .arg("--out")
+            .arg(&signed)
+            .arg("--sign")
+            .arg("--force"),
+    )?;
+    if !status.success() {
+        return Err(anyhow!(
+            "pesign failed on {}: {status}",
+            file.to_string_lossy()
+        ));
+    }
+
+    if dry_run() {
+        return Ok(());
+    }
+    Ok(std::fs::rename(signed, file)?)
+}
+
+/// Returns true if `file` carries at least one signature
+pub fn pesign_signed<P: AsRef<Path>>(file: P) -> anyhow::Result<bool> {
+    let output = command("pesign")
+        .arg("--show-signature")
+        .arg("--in")
+        .arg(file.as_ref())
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!("pesign --show-signature failed: {}", output.status));
+    }
+    Ok(!String::from_utf8_lossy(&output.stdout).contains("No signatures found"))
+}
+
+/// Returns the hex encoded SHA-256 of `data`, computed with sha256sum
+pub fn sha256(data: &[u8]) -> anyhow::Result<String> {
+    let mut child = command("sha256sum")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(data)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("sha256sum failed: {}", output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Returns the hex encoded SHA-256 of `file`
+pub fn sha256sum<P: AsRef<Path>>(file: P) -> anyhow::Result<String> {
+    let file = file.as_ref();
+    let output = command("sha256sum").arg(file).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "sha256sum failed on {}: {}",
+            file.to_string_lossy(),
+            output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Returns true if `file` signature verifies against `cert`
+pub fn sbverify<P: AsRef<Path>, Q: AsRef<Path>>(cert: P, file: Q) -> anyhow::Result<bool> {
+    let output = command("sbverify")
+        .arg("--cert")
+        .arg(cert.as_ref())
+        .arg(file.as_ref())
+        .output()?;
+    Ok(output.status.success())
+}
+
+/// Detaches `mountpoint` right away even if busy, the filesystem is
+/// released once its last user is gone (umount -l)
+pub fn umount_detach(mountpoint: &Path) -> anyhow::Result<()> {
+    if dry_run() {
+        dry_run_note(format!("umount -l {}", mountpoint.to_string_lossy()));
+        return Ok(());
+    }
+    let target = cstring(mountpoint)?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(anyhow!(
+            "failed to detach {}: {}",
+            mountpoint.to_string_lossy(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Unmounts `mountpoint` with umount(2), along with the filesystems mounted
+/// under it when `recursive`. Not subject to the deadline as it cleans up.
+pub fn umount(mountpoint: &PathBuf, recursive: bool) -> anyhow::Result<()> {
+    if dry_run() {
+        let r = if recursive { " -R" } else { "" };
+        dry_run_note(format!("umount{r} {}", mountpoint.to_string_lossy()));
+        return Ok(());
+    }
+
+    // deepest mounts first
+    let mut targets = vec![mountpoint.clone()];
+    if recursive {
+        let mut nested: Vec<_> = mounts::mounts()?
+            .into_iter()
+            .map(|m| m.target)
+            .filter(|t| t != mountpoint && t.starts_with(mountpoint))
+            .collect();
+        nested.sort_by_key(|t| std::cmp::Reverse(t.components().count()));
+        nested.dedup();
+        targets.splice(0..0, nested);
+    }
+
+    for t in targets {
+        let target = cstring(&t)?;
+        if unsafe { libc::umount2(target.as_ptr(), 0) } != 0 {
+            return Err(anyhow!(
+                "failed to umount {}: {}",
+                t.to_string_lossy(),
+                std::io::Error::last_os_error()
+            ));
+        }
     }
     Ok(())
 }
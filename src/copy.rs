@@ -0,0 +1,229 @@
+use std::{
+    ffi::CString,
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{self, ffi::OsStrExt, fs::MetadataExt, fs::PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{command, events};
+
+// size of buffer used to copy data extents
+const CHUNK: usize = 1 << 20;
+
+fn cstring(p: &Path) -> anyhow::Result<CString> {
+    Ok(CString::new(p.as_os_str().as_bytes())?)
+}
+
+fn os_error(what: &str, p: &Path) -> anyhow::Error {
+    anyhow!(
+        "failed to {what} {}: {}",
+        p.to_string_lossy(),
+        std::io::Error::last_os_error()
+    )
+}
+
+// extended attributes (i.e. security.selinux labels) of a file, not following symlinks
+fn xattrs(p: &Path) -> anyhow::Result<Vec<(CString, Vec<u8>)>> {
+    let path = cstring(p)?;
+    let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        // filesystem without extended attributes support
+        return match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENOTSUP) => Ok(vec![]),
+            _ => Err(os_error("list extended attributes of", p)),
+        };
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+    if size < 0 {
+        return Err(os_error("list extended attributes of", p));
+    }
+    names.truncate(size as usize);
+
+    let mut attrs = vec![];
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let name = CString::new(name)?;
+        let size =
+            unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(os_error("read extended attributes of", p));
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if size < 0 {
+            return Err(os_error("read extended attributes of", p));
+        }
+        value.truncate(size as usize);
+        attrs.push((name, value));
+    }
+    Ok(attrs)
+}
+
+// copies ownership, extended attributes and permissions of `src` to `dst`
+fn copy_metadata(src: &Path, dst: &Path, meta: &fs::Metadata) -> anyhow::Result<()> {
+    let path = cstring(dst)?;
+    if unsafe { libc::lchown(path.as_ptr(), meta.uid(), meta.gid()) } != 0 {
+        return Err(os_error("change owner of", dst));
+    }
+
+    for (name, value) in xattrs(src)? {
+        let res = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        // destination filesystem cannot hold them (i.e. vfat)
+        if res != 0 && std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOTSUP) {
+            return Err(os_error("set extended attributes of", dst));
+        }
+    }
+
+    // symlinks have no permissions of their own
+    if !meta.is_symlink() {
+        fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+    }
+    Ok(())
+}
+
+// copies data extents of `src` only, so that holes of sparse files stay holes
+fn copy_sparse(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let mut from = fs::File::open(src)?;
+    let mut to = fs::File::create(dst)?;
+    let len = from.metadata()?.len();
+    let fd = from.as_raw_fd();
+    let mut buf = vec![0u8; CHUNK];
+
+    let mut pos = 0;
+    while pos < len {
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            match std::io::Error::last_os_error().raw_os_error() {
+                // no data after pos, the file ends with a hole
+                Some(libc::ENXIO) => break,
+                // filesystem cannot report holes, everything is data
+                Some(libc::EINVAL) => {
+                    from.seek(SeekFrom::Start(pos))?;
+                    std::io::copy(&mut from, &mut to)?;
+                    break;
+                }
+                _ => return Err(os_error("seek", src)),
+            }
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(os_error("seek", src));
+        }
+
+        let (mut off, end) = (data as u64, hole as u64);
+        from.seek(SeekFrom::Start(off))?;
+        to.seek(SeekFrom::Start(off))?;
+        while off < end {
+            let n = buf.len().min((end - off) as usize);
+            from.read_exact(&mut buf[..n])?;
+            to.write_all(&buf[..n])?;
+            off += n as u64;
+        }
+        pos = end;
+    }
+
+    // trailing hole
+    to.set_len(len)?;
+    Ok(to.sync_all()?)
+}
+
+// files and directories of `src` listed parents first
+fn walk(src: &Path, rel: &Path, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(src.join(rel))?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for name in entries {
+        let rel = rel.join(name);
+        let is_dir = fs::symlink_metadata(src.join(&rel))?.is_dir();
+        out.push(rel.clone());
+        if is_dir {
+            walk(src, &rel, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the content of directory `src` into existing directory `dst`,
+/// preserving ownership, permissions, extended attributes (thus SELinux
+/// labels), symlinks and holes of sparse files. Every copied file is checked
+/// against the hash of its source and progress is reported per file.
+pub fn copy_tree(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let mut entries = vec![];
+    walk(src, Path::new(""), &mut entries)?;
+    let total = entries.len() as u64;
+
+    // directories get their permissions last so that read-only ones can be filled
+    let mut dirs = vec![(PathBuf::new(), fs::symlink_metadata(src)?)];
+    for (i, rel) in entries.iter().enumerate() {
+        let (s, d) = (src.join(rel), dst.join(rel));
+        let meta = fs::symlink_metadata(&s)?;
+
+        if meta.is_symlink() {
+            unix::fs::symlink(fs::read_link(&s)?, &d)?;
+            copy_metadata(&s, &d, &meta)?;
+        } else if meta.is_dir() {
+            fs::create_dir(&d)?;
+            dirs.push((rel.clone(), meta));
+        } else if meta.is_file() {
+            copy_sparse(&s, &d)?;
+            if command::sha256sum(&s)? != command::sha256sum(&d)? {
+                return Err(anyhow!(
+                    "copy of {} to {} differs from source",
+                    s.to_string_lossy(),
+                    d.to_string_lossy()
+                ));
+            }
+            copy_metadata(&s, &d, &meta)?;
+        } else {
+            return Err(anyhow!("cannot copy special file {}", s.to_string_lossy()));
+        }
+        events::progress("copy", i as u64 + 1, total);
+    }
+
+    // deepest directories first
+    for (rel, meta) in dirs.iter().rev() {
+        copy_metadata(&src.join(rel), &dst.join(rel), meta)?;
+    }
+    Ok(())
+}
+
+/// Copies file `src` to `dst` like [copy_tree] does
+pub fn copy_file(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    if meta.is_symlink() {
+        unix::fs::symlink(fs::read_link(src)?, dst)?;
+    } else {
+        copy_sparse(src, dst)?;
+        if command::sha256sum(src)? != command::sha256sum(dst)? {
+            return Err(anyhow!(
+                "copy of {} to {} differs from source",
+                src.to_string_lossy(),
+                dst.to_string_lossy()
+            ));
+        }
+    }
+    copy_metadata(src, dst, &meta)
+}
@@ -0,0 +1,221 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{command, state};
+
+pub const GRUB_DEFAULTS: &str = "/etc/default/grub";
+
+enum Line {
+    // comments, blank lines and anything we don't understand are kept as is
+    Raw(String),
+    // original text is kept until value is changed
+    Var {
+        key: String,
+        value: String,
+        raw: Option<String>,
+    },
+}
+
+/// Editor of /etc/default/grub keeping comments and ordering
+pub struct GrubDefaults {
+    path: PathBuf,
+    lines: Vec<Line>,
+}
+
+fn unquote(v: &str) -> String {
+    let v = v.trim();
+    if let Some(s) = v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        return s.to_string();
+    }
+    if let Some(s) = v.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        let mut out = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some(n @ ('"' | '\\' | '$' | '`'))) => {
+                    out.push(n);
+                    chars.next();
+                }
+                _ => out.push(c),
+            }
+        }
+        return out;
+    }
+    v.to_string()
+}
+
+fn quote(v: &str) -> String {
+    let mut out = String::from('"');
+    for c in v.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+impl std::fmt::Display for GrubDefaults {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for l in self.lines.iter() {
+            match l {
+                Line::Raw(r) => writeln!(f, "{r}")?,
+                Line::Var { raw: Some(r), .. } => writeln!(f, "{r}")?,
+                Line::Var { key, value, .. } => writeln!(f, "{key}={}", quote(value))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GrubDefaults {
+    fn parse<P: AsRef<Path>>(path: P, content: &str) -> Self {
+        let lines = content
+            .lines()
+            .map(|l| match l.split_once('=') {
+                Some((k, v))
+                    if !k.is_empty()
+                        && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+                {
+                    Line::Var {
+                        key: k.into(),
+                        value: unquote(v),
+                        raw: Some(l.into()),
+                    }
+                }
+                _ => Line::Raw(l.into()),
+            })
+            .collect();
+
+        Self {
+            path: path.as_ref().into(),
+            lines,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let content = match fs::read_to_string(path.as_ref()) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self::parse(path, &content))
+    }
+
+    /// Sets `key`, only the last assignment is changed as the file is sourced by a shell
+    pub fn set(&mut self, key: &str, value: &str) {
+        let last = self.lines.iter_mut().rev().find_map(|l| match l {
+            Line::Var {
+                key: k, value, raw, ..
+            } if k == key => Some((value, raw)),
+            _ => None,
+        });
+
+        match last {
+            Some((v, _)) if v == value => {}
+            Some((v, raw)) => {
+                *v = value.into();
+                *raw = None;
+            }
+            None => self.lines.push(Line::Var {
+                key: key.into(),
+                value: value.into(),
+                raw: None,
+            }),
+        }
+    }
+
+    pub fn unset(&mut self, key: &str) {
+        self.lines
+            .retain(|l| !matches!(l, Line::Var { key: k, .. } if k == key));
+    }
+
+    /// Variables in file order
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines.iter().filter_map(|l| match l {
+            Line::Var { key, value, .. } => Some((key.as_str(), value.as_str())),
+            _ => None,
+        })
+    }
+
+    /// Writes changes, previous file is kept as .bak to be restored with [GrubDefaults::undo]
+    pub fn save(&self) -> anyhow::Result<()> {
+        state::write_atomic(&self.path, self.to_string(), true)
+    }
+
+    /// Restores the file as it was before the last save
+    pub fn undo<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let bak = state::with_suffix(path, ".bak");
+        if !bak.is_file() {
+            return Err(anyhow!("no backup to restore: {}", bak.to_string_lossy()));
+        }
+        if command::dry_run() {
+            command::dry_run_note(format!("restore {}", path.to_string_lossy()));
+            return Ok(());
+        }
+        Ok(fs::rename(bak, path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: &str = "# GRUB boot loader configuration
+
+GRUB_DEFAULT=0
+GRUB_TIMEOUT=5
+GRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet\"
+export GRUB_COLOR_NORMAL
+if [ -f /etc/default/grub.local ]; then . /etc/default/grub.local; fi
+GRUB_TIMEOUT='10'
+";
+
+    #[test]
+    fn round_trip_keeps_comments_and_unknown_lines() {
+        let d = GrubDefaults::parse(GRUB_DEFAULTS, DEFAULTS);
+        assert_eq!(d.to_string(), DEFAULTS);
+        let vars: Vec<_> = d.vars().collect();
+        assert_eq!(
+            vars,
+            [
+                ("GRUB_DEFAULT", "0"),
+                ("GRUB_TIMEOUT", "5"),
+                ("GRUB_CMDLINE_LINUX_DEFAULT", "loglevel=3 quiet"),
+                ("GRUB_TIMEOUT", "10"),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_changes_last_assignment_only() {
+        let mut d = GrubDefaults::parse(GRUB_DEFAULTS, DEFAULTS);
+        d.set("GRUB_TIMEOUT", "1");
+        d.set("GRUB_ENABLE_CRYPTODISK", "y");
+        assert_eq!(
+            d.to_string(),
+            DEFAULTS.replace("GRUB_TIMEOUT='10'", "GRUB_TIMEOUT=\"1\"")
+                + "GRUB_ENABLE_CRYPTODISK=\"y\"\n"
+        );
+
+        // unchanged values keep their original quoting
+        let mut d = GrubDefaults::parse(GRUB_DEFAULTS, DEFAULTS);
+        d.set("GRUB_TIMEOUT", "10");
+        assert_eq!(d.to_string(), DEFAULTS);
+    }
+
+    #[test]
+    fn quote_unquote() {
+        let value = "a \"b\" \\c $d `e`";
+        assert_eq!(quote(value), "\"a \\\"b\\\" \\\\c \\$d \\`e\\`\"");
+        assert_eq!(unquote(&quote(value)), value);
+        assert_eq!(unquote("'$x \\ `y`'"), "$x \\ `y`");
+        assert_eq!(unquote(" plain "), "plain");
+    }
+}
@@ -0,0 +1,135 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot,
+    bootloader::Bootloader,
+    command::{self, command},
+};
+
+// directory of EFI partition holding the files booted by firmware
+const ESP_DIR: &str = "cryptboot";
+const KERNEL: &str = "linux.efi";
+const INITRD: &str = "initrd.img";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    // label of the firmware boot entry
+    pub label: String,
+    // kernel and initramfs, relative to boot mountpoint
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    // unified kernel image, relative to boot mountpoint, it already embeds
+    // initramfs and command line so kernel, initrd and cmdline are ignored
+    pub uki: Option<PathBuf>,
+    pub cmdline: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            label: "Linux (cryptboot)".into(),
+            kernel: "vmlinuz-linux".into(),
+            initrd: Some("initramfs-linux.img".into()),
+            uki: None,
+            cmdline: String::new(),
+        }
+    }
+}
+
+/// Firmware boots the kernel directly through its EFI stub, kernel and
+/// initramfs are copied from encrypted boot to EFI partition as firmware
+/// cannot read encrypted boot.
+pub(crate) struct Efistub(Config);
+
+impl Efistub {
+    pub fn from_config(config: Config) -> Self {
+        Self(config)
+    }
+
+    fn esp_dir(cfg: &boot::Config) -> PathBuf {
+        cfg.efi.mountpoint.join("EFI").join(ESP_DIR)
+    }
+
+    // files to copy from boot to EFI partition
+    fn payload(&self) -> Vec<(PathBuf, &'static str)> {
+        match &self.0.uki {
+            Some(uki) => vec![(uki.clone(), KERNEL)],
+            None => {
+                let mut files = vec![(self.0.kernel.clone(), KERNEL)];
+                if let Some(initrd) = &self.0.initrd {
+                    files.push((initrd.clone(), INITRD));
+                }
+                files
+            }
+        }
+    }
+
+    // command line given to the kernel by firmware boot entry
+    fn cmdline(&self) -> Option<String> {
+        if self.0.uki.is_some() {
+            return None;
+        }
+
+        let mut cmdline = self.0.cmdline.clone();
+        if self.0.initrd.is_some() {
+            // EFI stub loads initrd from the partition it was loaded from
+            cmdline.push_str(&format!(" initrd=\\EFI\\{ESP_DIR}\\{INITRD}"));
+        }
+        Some(cmdline.trim().to_string())
+    }
+}
+
+impl Bootloader for Efistub {
+    fn mkconfig(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let dir = Self::esp_dir(cfg);
+        for (src, dst) in self.payload() {
+            let src = cfg.mountpoint.join(src);
+            if !src.is_file() {
+                return Err(anyhow!("file not found: {}", src.to_string_lossy()));
+            }
+
+            let dst = dir.join(dst);
+            if command::dry_run() {
+                command::dry_run_note(format!(
+                    "copy {} to {}",
+                    src.to_string_lossy(),
+                    dst.to_string_lossy()
+                ));
+                continue;
+            }
+            fs::create_dir_all(&dir)?;
+            fs::copy(&src, &dst)?;
+        }
+        Ok(())
+    }
+
+    fn install(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        // existing entries are replaced so that command line changes apply
+        command::efi_boot_entry(
+            &cfg.efi.device,
+            &self.0.label,
+            &format!("\\EFI\\{ESP_DIR}\\{KERNEL}"),
+            self.cmdline().as_deref(),
+        )
+    }
+
+    fn managed_files(&self, cfg: &boot::Config) -> Vec<PathBuf> {
+        vec![Self::esp_dir(cfg)]
+    }
+
+    fn signed_files(&self, cfg: &boot::Config) -> Vec<PathBuf> {
+        vec![Self::esp_dir(cfg).join(KERNEL)]
+    }
+
+    fn version(&self) -> anyhow::Result<String> {
+        let output = command("efibootmgr").arg("--version").output()?;
+        if !output.status.success() {
+            return Err(anyhow!("efibootmgr --version failed: {}", output.status));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
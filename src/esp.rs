@@ -0,0 +1,121 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{boot, command, grub, health::Report, pool, state};
+
+// kept in encrypted boot so that it cannot be altered along with the EFI partition
+const MANIFEST: &str = "cryptboot/esp.sha256";
+
+/// Hashes of every file of the EFI partition, by path relative to its mountpoint
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest(BTreeMap<PathBuf, String>);
+
+fn files(dir: &Path, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        if e.file_type()?.is_dir() {
+            files(&e.path(), out)?;
+        } else if e.file_name() == grub::BOOT_COUNTER_ENV {
+            // rewritten by grub at every boot
+            continue;
+        } else {
+            out.push(e.path());
+        }
+    }
+    Ok(())
+}
+
+impl Manifest {
+    fn path(cfg: &boot::Config) -> PathBuf {
+        cfg.mountpoint.join(MANIFEST)
+    }
+
+    /// Hashes the files of the EFI partition, which must be mounted
+    pub fn compute(cfg: &boot::Config) -> anyhow::Result<Self> {
+        let mut paths = vec![];
+        files(&cfg.efi.mountpoint, &mut paths)?;
+        let hashes = pool::map("hash", &paths, pool::jobs(), |p| command::sha256sum(p))?;
+
+        Ok(Self(
+            paths
+                .iter()
+                .zip(hashes)
+                .filter_map(|(p, h)| Some((p.strip_prefix(&cfg.efi.mountpoint).ok()?.into(), h)))
+                .collect(),
+        ))
+    }
+
+    /// Loads the manifest from boot, which must be mounted
+    pub fn load(cfg: &boot::Config) -> anyhow::Result<Self> {
+        let path = Self::path(cfg);
+        let content = fs::read_to_string(&path).map_err(|e| {
+            anyhow!(
+                "failed to read ESP manifest {}: {e}",
+                path.to_string_lossy()
+            )
+        })?;
+
+        // sha256sum format, so that it can be checked by hand
+        let mut m = Self::default();
+        for line in content.lines() {
+            let Some((hash, file)) = line.split_once("  ") else {
+                return Err(anyhow!("invalid ESP manifest line: {line}"));
+            };
+            m.0.insert(file.into(), hash.into());
+        }
+        Ok(m)
+    }
+
+    pub fn save(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let path = Self::path(cfg);
+        if let (Some(dir), false) = (path.parent(), command::dry_run()) {
+            fs::create_dir_all(dir)?;
+        }
+        let content: String = self
+            .0
+            .iter()
+            .map(|(f, h)| format!("{h}  {}\n", f.to_string_lossy()))
+            .collect();
+        state::write_atomic(path, content, false)
+    }
+
+    /// Records the current content of the EFI partition, boot and EFI must be mounted
+    pub fn update(cfg: &boot::Config) -> anyhow::Result<()> {
+        Self::compute(cfg)?.save(cfg)
+    }
+
+    /// Reports files of the EFI partition changed, added or removed since the
+    /// manifest was recorded
+    pub fn verify(cfg: &boot::Config, r: &mut Report) -> anyhow::Result<()> {
+        let (recorded, current) = (Self::load(cfg)?, Self::compute(cfg)?);
+
+        let mut changed = vec![];
+        let mut removed = vec![];
+        for (f, h) in recorded.0.iter() {
+            match current.0.get(f) {
+                Some(c) if c != h => changed.push(f.to_string_lossy().to_string()),
+                Some(_) => {}
+                None => removed.push(f.to_string_lossy().to_string()),
+            }
+        }
+        let added: Vec<_> = current
+            .0
+            .keys()
+            .filter(|f| !recorded.0.contains_key(*f))
+            .map(|f| f.to_string_lossy().to_string())
+            .collect();
+
+        for (name, files) in [("changed", changed), ("added", added), ("removed", removed)] {
+            match files.is_empty() {
+                true => r.ok(name, "none"),
+                false => r.fail(name, files.join(" ")),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    fs::File,
+    io::Write,
+    os::fd::{FromRawFd, RawFd},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use crate::output;
+
+// frontends read events from a file descriptor they passed to us
+static SINK: Mutex<Option<File>> = Mutex::new(None);
+
+/// Progress event, written as a JSON line
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    StepStarted {
+        step: &'a str,
+    },
+    StepFinished {
+        step: &'a str,
+        seconds: f64,
+    },
+    Progress {
+        step: &'a str,
+        done: u64,
+        total: u64,
+        percent: u64,
+    },
+    Warning {
+        message: &'a str,
+    },
+}
+
+/// Emits events on file descriptor `fd`, which must be open for writing
+pub fn set_fd(fd: RawFd) {
+    *SINK.lock().unwrap() = Some(unsafe { File::from_raw_fd(fd) });
+}
+
+pub fn emit(event: Event) {
+    let mut sink = SINK.lock().unwrap();
+    let Some(f) = sink.as_mut() else {
+        return;
+    };
+
+    // a frontend going away must not break the operation in progress
+    let res = output::to_json(&event).map(|line| writeln!(f, "{line}"));
+    if !matches!(res, Ok(Ok(()))) {
+        *sink = None;
+    }
+}
+
+/// Reports `done` out of `total` items of `step` processed
+pub fn progress(step: &str, done: u64, total: u64) {
+    emit(Event::Progress {
+        step,
+        done,
+        total,
+        percent: (done * 100).checked_div(total).unwrap_or(100),
+    });
+}
+
+/// Prints a warning and emits it as an event
+pub fn warn<S: AsRef<str>>(message: S) {
+    eprintln!("warning: {}", message.as_ref());
+    emit(Event::Warning {
+        message: message.as_ref(),
+    });
+}
@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::command::{self, command};
+
+// variables ssh needs to find keys, agent and known hosts
+const SSH_ENV: &[&str] = &["HOME", "USER", "SSH_AUTH_SOCK"];
+
+/// Operations shared by several hosts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    // cryptboot arguments run on hosts (i.e. ["grub-install"])
+    pub command: Vec<String>,
+    // configuration file on hosts, cryptboot default when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Host {
+    pub name: String,
+    // ssh destination, host name when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    pub profile: String,
+}
+
+/// Hosts managed together and what to run on them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fleet {
+    pub profiles: BTreeMap<String, Profile>,
+    pub hosts: Vec<Host>,
+}
+
+/// Outcome of the operation on one host
+#[derive(Debug, Clone, Serialize)]
+pub struct Outcome {
+    pub host: String,
+    pub success: bool,
+    pub output: String,
+}
+
+// arguments are joined by ssh into a remote shell command line
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl Fleet {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let fleet: Self = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("failed to parse fleet {}: {e}", path.to_string_lossy()))?;
+
+        for h in &fleet.hosts {
+            if !fleet.profiles.contains_key(&h.profile) {
+                return Err(anyhow!(
+                    "host {} uses unknown profile {}",
+                    h.name,
+                    h.profile
+                ));
+            }
+        }
+        Ok(fleet)
+    }
+
+    /// Runs cryptboot on every host over ssh, `plan` only computing what
+    /// would be done. Hosts are processed in order, failures do not stop others.
+    pub fn run(&self, ssh: &str, plan: bool) -> anyhow::Result<Vec<Outcome>> {
+        let mut outcomes = vec![];
+        for h in &self.hosts {
+            let profile = &self.profiles[&h.profile];
+
+            let mut remote = vec!["cryptboot".to_string()];
+            if let Some(config) = &profile.config {
+                remote.extend(["--config".into(), config.clone()]);
+            }
+            if plan {
+                remote.push("plan".into());
+            }
+            remote.extend(profile.command.iter().cloned());
+
+            let mut cmd = command(ssh);
+            for var in SSH_ENV {
+                if let Ok(v) = std::env::var(var) {
+                    cmd.env(var, v);
+                }
+            }
+            cmd.arg("-o")
+                .arg("BatchMode=yes")
+                .arg(h.destination.as_deref().unwrap_or(&h.name))
+                .arg("--")
+                .arg(
+                    remote
+                        .iter()
+                        .map(|a| shell_quote(a))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                );
+
+            if command::dry_run() {
+                command::status(&mut cmd)?;
+                continue;
+            }
+
+            let (success, output) = match cmd.output() {
+                Ok(o) => (
+                    o.status.success(),
+                    format!(
+                        "{}{}",
+                        String::from_utf8_lossy(&o.stdout),
+                        String::from_utf8_lossy(&o.stderr)
+                    ),
+                ),
+                Err(e) => (false, format!("failed to run {ssh}: {e}")),
+            };
+            outcomes.push(Outcome {
+                host: h.name.clone(),
+                success,
+                output,
+            });
+        }
+        Ok(outcomes)
+    }
+}
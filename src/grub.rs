@@ -1,6 +1,18 @@
-use std::fs;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
 
-use crate::{boot, command::command};
+use crate::{
+    boot,
+    bootloader::Bootloader,
+    command::{self, command},
+    events, kernel, state, timing, Device,
+};
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 
@@ -95,13 +107,260 @@ const MODULES: &[&str] = &[
     "raid6rec",
 ];
 
+// modules verifying gpg signatures of the files grub loads
+const SIGNATURE_MODULES: &[&str] = &["pgp", "gcry_sha256", "gcry_sha512", "gcry_rsa", "gcry_dsa"];
+
+// files of grub directory a signature is required for, everything but
+// signatures and grubenv which grub itself rewrites
+fn gpg_files(dir: &Path, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        let path = e.path();
+        if e.file_type()?.is_dir() {
+            gpg_files(&path, out)?;
+        } else if path.extension().is_none_or(|ext| ext != "sig")
+            && path.file_name().is_some_and(|n| n != "grubenv")
+        {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+// kernel parameters pointing initramfs at key files
+const KEY_PARAMS: &[&str] = &["cryptkey=", "rd.luks.key=", "luks.key="];
+// kernel parameters which should never hold a plaintext secret
+const SECRET_PARAMS: &[&str] = &["password=", "passwd=", "pass=", "passphrase="];
+
+/// Plaintext secrets and key file references found in a generated grub.cfg,
+/// as human readable findings
+fn secret_findings(content: &str) -> Vec<String> {
+    let mut findings = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let n = i + 1;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            // password_pbkdf2 is the hashed variant
+            Some("password") => findings.push(format!(
+                "line {n}: plaintext password for user {}",
+                words.next().unwrap_or_default()
+            )),
+            Some("linux" | "linuxefi" | "linux16") => {
+                for w in words {
+                    if KEY_PARAMS.iter().any(|p| w.starts_with(p)) {
+                        findings.push(format!(
+                            "line {n}: kernel command line references key file ({w})"
+                        ));
+                    } else if let Some(p) = SECRET_PARAMS.iter().find(|p| w.starts_with(*p)) {
+                        findings.push(format!(
+                            "line {n}: plaintext secret in kernel parameter {p}"
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    findings
+}
+
+// writes grub.cfg readable by root only, which is what grub-mkconfig does
+// only when it finds a password
+fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    // mode is not applied to an existing file
+    f.set_permissions(fs::Permissions::from_mode(0o600))?;
+    f.write_all(content.as_bytes())?;
+    f.sync_all()?;
+    Ok(fs::rename(tmp, path)?)
+}
+
+// Config embedded in core image unlocking boot before grub.cfg is loaded.
+// {uuid} is replaced by LUKS UUID of boot device (without dashes) and
+// {prefix} by the path of grub directory inside boot filesystem.
+pub const EARLY_CONFIG: &str = r#"cryptomount -u {uuid}
+set root=(cryptouuid/{uuid})
+set prefix=($root){prefix}
+"#;
+
+// environment block of the boot counter, in the EFI partition directory of grub
+pub const BOOT_COUNTER_ENV: &str = "cryptboot-counter.env";
+
+// Config of standalone images, modules stay on memdisk so that the image
+// does not depend on anything installed in boot
+pub const STANDALONE_CONFIG: &str = r#"cryptomount -u {uuid}
+search --no-floppy --fs-uuid --set=root {fs_uuid}
+configfile ($root)/grub/grub.cfg
+"#;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MenuLock {
+    // anybody can boot and edit entries
+    #[default]
+    None,
+    // anybody can boot entries but editing them or using the console
+    // requires authentication
+    EditOnly,
+    // authentication is required for everything
+    Full,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Terminal {
+    #[default]
+    Console,
+    // serial and local console (i.e. servers managed over IPMI)
+    Serial,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Serial {
+    pub unit: u8,
+    pub speed: u32,
+    pub word: u8,
+    // no, odd or even
+    pub parity: String,
+    pub stop: u8,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self {
+            unit: 0,
+            speed: 115200,
+            word: 8,
+            parity: "no".into(),
+            stop: 1,
+        }
+    }
+}
+
+impl Serial {
+    fn command(&self) -> String {
+        format!(
+            "serial --unit={} --speed={} --word={} --parity={} --stop={}",
+            self.unit, self.speed, self.word, self.parity, self.stop
+        )
+    }
+}
+
+/// Kernels of another distribution installed in a sub-directory of the
+/// encrypted boot partition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ForeignKernels {
+    // name displayed in menu entries
+    pub name: String,
+    // directory relative to boot mountpoint holding vmlinuz-* and initramfs
+    pub directory: PathBuf,
+    // kernel command line (root, crypt parameters ...)
+    pub cmdline: String,
+}
+
+impl ForeignKernels {
+    // initramfs naming conventions of the different distributions
+    const INITRD_NAMES: &'static [(&'static str, &'static str)] =
+        &[("initramfs-", ".img"), ("initrd.img-", ""), ("initrd-", "")];
+
+    /// Returns (version, kernel, initrd) paths relative to boot sorted from
+    /// the most recent version
+    fn kernels(
+        &self,
+        cfg: &boot::Config,
+    ) -> anyhow::Result<Vec<(String, PathBuf, Option<PathBuf>)>> {
+        let dir = cfg.mountpoint.join(&self.directory);
+        let rel = PathBuf::from("/").join(&self.directory);
+
+        let mut kernels = vec![];
+        for e in fs::read_dir(&dir)? {
+            let name = e?.file_name().to_string_lossy().to_string();
+            let Some(version) = name.strip_prefix("vmlinuz-") else {
+                continue;
+            };
+
+            let initrd = Self::INITRD_NAMES
+                .iter()
+                .map(|(prefix, suffix)| format!("{prefix}{version}{suffix}"))
+                .find(|n| dir.join(n).is_file())
+                .map(|n| rel.join(n));
+
+            kernels.push((version.to_string(), rel.join(&name), initrd));
+        }
+
+        kernels.sort_by(|a, b| b.0.cmp(&a.0));
+        Ok(kernels)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub(crate) struct Config {
     // Grub target
     pub target: String,
     pub bootloader_id: String,
     // additional modules
     pub add_modules: Vec<String>,
+    // number of boots allowed before falling back, boot counting is
+    // disabled when not set
+    pub boot_tries: Option<u8>,
+    // id or title of the menu entry booted once boot tries are exhausted
+    // (i.e. "gnulinux-advanced-<uuid>>gnulinux-<version>-advanced-<uuid>"),
+    // required by boot_tries
+    pub fallback_entry: Option<String>,
+    pub menu_lock: MenuLock,
+    // user allowed to edit entries when menu is locked
+    pub superuser: String,
+    // hash generated with grub-mkpasswd-pbkdf2 (may be a secret:<name> reference)
+    pub password_pbkdf2: Option<String>,
+    // template of the config embedded in core image, core image built by
+    // grub-install is kept when not set (see EARLY_CONFIG)
+    pub early_config: Option<String>,
+    // kernels of other distributions sharing the encrypted boot
+    pub foreign: Vec<ForeignKernels>,
+    // use distribution pre-signed shim and grub (i.e. grub-efi-amd64-signed)
+    // instead of building and signing our own grub
+    pub signed_shim: bool,
+    // machine owner key used to sign kernels when signed_shim is used
+    pub mok_key: Option<PathBuf>,
+    pub mok_cert: Option<PathBuf>,
+    // boot kernels with quiet splash and keep grub video mode, requires
+    // plymouth so that the initramfs passphrase prompt is visible
+    pub quiet_boot: bool,
+    // video mode used by grub menu (GRUB_GFXMODE)
+    pub gfxmode: Option<String>,
+    // keyboard layout (as understood by ckbcomp) used at the passphrase prompt
+    pub keymap: Option<String>,
+    // PF2 font (see grub-mkfont) used at the passphrase prompt
+    pub font: Option<PathBuf>,
+    pub terminal: Terminal,
+    // serial line settings used when terminal is serial
+    pub serial: Serial,
+    // beep when the passphrase prompt appears and on unlock success or failure
+    pub beep: bool,
+    // gpg key detach-signing kernels, initramfs and grub files of boot
+    pub gpg_key: Option<String>,
+    // embed gpg_key public key in core image and make grub refuse files
+    // without a valid signature (check_signatures=enforce)
+    pub check_signatures: bool,
+    // public key (binary gpg export) embedded in core image instead of the
+    // one of gpg_key, relative paths are resolved against boot mountpoint.
+    // Implies check_signatures.
+    pub pubkey: Option<PathBuf>,
+    // build a single self-contained image with grub-mkstandalone instead of
+    // running grub-install (see STANDALONE_CONFIG)
+    pub standalone: bool,
 }
 
 impl Default for Config {
@@ -110,6 +369,27 @@ impl Default for Config {
             target: "x86_64-efi".into(),
             bootloader_id: "GRUB".into(),
             add_modules: vec![],
+            boot_tries: None,
+            fallback_entry: None,
+            menu_lock: MenuLock::None,
+            superuser: "root".into(),
+            password_pbkdf2: None,
+            early_config: None,
+            foreign: vec![],
+            signed_shim: false,
+            mok_key: None,
+            mok_cert: None,
+            quiet_boot: false,
+            gfxmode: None,
+            keymap: None,
+            font: None,
+            terminal: Terminal::Console,
+            serial: Serial::default(),
+            beep: false,
+            gpg_key: None,
+            check_signatures: false,
+            pubkey: None,
+            standalone: false,
         }
     }
 }
@@ -127,7 +407,79 @@ impl Grub {
         config.into()
     }
 
-    pub fn modules_for_target(&self, target: &str) -> Vec<String> {
+    /// LUKS UUID of boot device without dashes as used by cryptomount
+    fn luks_uuid(cfg: &boot::Config) -> anyhow::Result<String> {
+        // probed live as it is written to grub.cfg and the early config
+        Ok(command::cryptsetup_uuid(&cfg.device)?.replace('-', ""))
+    }
+
+    /// Directory holding grub modules for target
+    pub fn modules_dir(&self) -> PathBuf {
+        PathBuf::from("/usr/lib/grub").join(&self.0.target)
+    }
+
+    /// Whether installed grub can unlock LUKS2 containers
+    pub fn supports_luks2(&self) -> bool {
+        self.modules_dir().join("luks2.mod").is_file()
+    }
+
+    /// Whether installed grub can derive keys with `pbkdf`, upstream grub only
+    /// supports pbkdf2 while some distributions patch in argon2 support
+    pub fn supports_pbkdf(&self, pbkdf: &str) -> bool {
+        match pbkdf {
+            "pbkdf2" => true,
+            "argon2i" | "argon2id" => self.modules_dir().join("argon2.mod").is_file(),
+            _ => false,
+        }
+    }
+
+    /// Describes cryptodisk support of installed grub, LUKS2 is only supported
+    /// upstream since 2.06 so anything older is patched by the distribution
+    pub fn cryptodisk_support(&self) -> String {
+        let version = self.version().unwrap_or_default();
+        let upstream_luks2 = version
+            .split_whitespace()
+            .last()
+            .and_then(|v| {
+                let mut nums = v.split(|c: char| !c.is_ascii_digit());
+                Some((
+                    nums.next()?.parse::<u32>().ok()?,
+                    nums.next()?.parse::<u32>().ok()?,
+                ))
+            })
+            .is_some_and(|v| v >= (2, 6));
+
+        let luks2 = match (self.supports_luks2(), upstream_luks2) {
+            (true, true) => "LUKS2 supported",
+            (true, false) => "LUKS2 supported (distribution patched)",
+            (false, _) => "LUKS2 not supported",
+        };
+        let argon2 = match self.supports_pbkdf("argon2id") {
+            true => "argon2 supported (distribution patched)",
+            false => "argon2 not supported",
+        };
+        format!("{version}: {luks2}, {argon2}")
+    }
+
+    /// Module dependencies of installed grub read from moddep.lst
+    fn module_deps(&self) -> anyhow::Result<HashMap<String, Vec<String>>> {
+        let path = self.modules_dir().join("moddep.lst");
+        let content = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("failed to read {}: {e}", path.to_string_lossy()))?;
+
+        Ok(content
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .map(|(m, deps)| {
+                (
+                    m.trim().to_string(),
+                    deps.split_whitespace().map(String::from).collect(),
+                )
+            })
+            .collect())
+    }
+
+    pub fn modules_for_target(&self, target: &str) -> anyhow::Result<Vec<String>> {
         let mut modules: Vec<String> = MODULES.iter().map(|&s| String::from(s)).collect();
 
         match target {
@@ -139,34 +491,820 @@ impl Grub {
             _ => {}
         }
 
-        for add_mod in self.0.add_modules.iter() {
-            if !modules.contains(add_mod) {
-                modules.push(add_mod.clone())
+        if self.0.add_modules.is_empty() {
+            return Ok(modules);
+        }
+
+        // unknown modules are kept to be reported by check_modules
+        let deps = self.module_deps()?;
+        let mut todo = self.0.add_modules.clone();
+        while let Some(m) = todo.pop() {
+            if modules.contains(&m) {
+                continue;
+            }
+            if let Some(d) = deps.get(&m) {
+                todo.extend(d.iter().cloned());
+            }
+            modules.push(m);
+        }
+
+        Ok(modules)
+    }
+
+    /// Makes sure all modules are available in installed grub so that we
+    /// don't end up with cryptic grub-install failures or a broken core image
+    fn check_modules(&self, modules: &[String]) -> anyhow::Result<()> {
+        let dir = self.modules_dir();
+        let missing: Vec<&str> = modules
+            .iter()
+            .filter(|m| !dir.join(format!("{m}.mod")).is_file())
+            .map(String::as_str)
+            .collect();
+
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "grub modules not available in {}: {}",
+                dir.to_string_lossy(),
+                missing.join(" ")
+            ));
+        }
+        Ok(())
+    }
+
+    fn config_file(cfg: &boot::Config) -> PathBuf {
+        cfg.mountpoint.join("grub").join("grub.cfg")
+    }
+
+    // environment block holding boot counter, on the EFI partition since
+    // grub cannot write to the encrypted boot
+    fn counter_env(&self) -> PathBuf {
+        Path::new("EFI")
+            .join(&self.0.bootloader_id)
+            .join(BOOT_COUNTER_ENV)
+    }
+
+    fn counter_env_file(&self, cfg: &boot::Config) -> PathBuf {
+        cfg.efi.mountpoint.join(self.counter_env())
+    }
+
+    // filesystem UUID grub searches the EFI partition by
+    fn efi_uuid(cfg: &boot::Config) -> anyhow::Result<String> {
+        match &cfg.efi.uuid {
+            Some(uuid) => Ok(uuid.clone()),
+            None => command::blkid_tag(&cfg.efi.device, "UUID")?
+                .ok_or(anyhow!("no filesystem UUID found on {}", cfg.efi.device)),
+        }
+    }
+
+    /// Grub script decrementing boot_counter at every boot until the last
+    /// install is marked as good, and booting the fallback entry when it
+    /// reaches zero. Grub scripting has no arithmetic so every step is unrolled.
+    /// The counter is kept on the EFI partition `efi_uuid`.
+    fn boot_counting_script(&self, efi_uuid: &str) -> anyhow::Result<Option<String>> {
+        let Some(tries) = self.0.boot_tries else {
+            return Ok(None);
+        };
+        // entry indexes shift with kernels and may designate a submenu
+        let fallback = match &self.0.fallback_entry {
+            None => return Err(anyhow!("boot_tries requires a fallback_entry")),
+            Some(e) if e.is_empty() || e.parse::<u32>().is_ok() => {
+                return Err(anyhow!(
+                    "fallback_entry must be a menu entry id or title, not an index: {e}"
+                ))
+            }
+            Some(e) if e.contains(['\'', '\n']) => {
+                return Err(anyhow!("invalid fallback_entry: {e}"))
+            }
+            Some(e) => e,
+        };
+        // environment block is not signed, it only selects among signed entries
+        let skip_sig = match self.checks_signatures() {
+            true => " --skip-sig",
+            false => "",
+        };
+        let env = format!("($cryptboot_esp)/{}", self.counter_env().to_string_lossy());
+
+        let mut script = String::from("### BEGIN cryptboot boot counting ###\n");
+        script.push_str(&format!(
+            "search --no-floppy --fs-uuid --set=cryptboot_esp {efi_uuid}\n"
+        ));
+        script.push_str(&format!(
+            "load_env{skip_sig} -f {env} boot_success boot_counter\n"
+        ));
+        script.push_str("if [ \"${boot_success}\" = \"0\" -a -n \"${boot_counter}\" ]; then\n");
+        script.push_str("  if [ \"${boot_counter}\" = \"0\" ]; then\n");
+        script.push_str(&format!("    set default='{fallback}'\n"));
+        for i in 1..=tries {
+            script.push_str(&format!(
+                "  elif [ \"${{boot_counter}}\" = \"{i}\" ]; then\n"
+            ));
+            script.push_str(&format!("    set boot_counter={}\n", i - 1));
+        }
+        script.push_str("  fi\n");
+        script.push_str(&format!("  save_env -f {env} boot_counter\n"));
+        script.push_str("fi\n");
+        script.push_str("### END cryptboot boot counting ###\n");
+        Ok(Some(script))
+    }
+
+    fn menu_lock_script(&self) -> anyhow::Result<Option<String>> {
+        if self.0.menu_lock == MenuLock::None {
+            return Ok(None);
+        }
+
+        let password = self.0.password_pbkdf2.as_ref().ok_or(anyhow!(
+            "grub menu lock requires a password_pbkdf2 in configuration"
+        ))?;
+
+        // both end up unquoted in grub.cfg, a broken line could lock everyone out
+        let user = &self.0.superuser;
+        if user.is_empty()
+            || !user
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(anyhow!("invalid grub superuser: {user:?}"));
+        }
+        if !password.starts_with("grub.pbkdf2.")
+            || password
+                .chars()
+                .any(|c| c.is_whitespace() || c == '"' || c == '\'')
+        {
+            return Err(anyhow!(
+                "password_pbkdf2 must be a hash generated with grub-mkpasswd-pbkdf2 (grub.pbkdf2.sha512...)"
+            ));
+        }
+
+        let mut script = String::from("### BEGIN cryptboot menu lock ###\n");
+        script.push_str(&format!("set superusers=\"{}\"\n", self.0.superuser));
+        script.push_str(&format!(
+            "password_pbkdf2 {} {}\n",
+            self.0.superuser, password
+        ));
+        script.push_str("### END cryptboot menu lock ###\n");
+        Ok(Some(script))
+    }
+
+    fn foreign_entries_script(&self, cfg: &boot::Config) -> anyhow::Result<Option<String>> {
+        if self.0.foreign.is_empty() {
+            return Ok(None);
+        }
+
+        let uuid = Self::luks_uuid(cfg)?;
+        let mut script = String::from("### BEGIN cryptboot foreign kernels ###\n");
+
+        for f in self.0.foreign.iter() {
+            for (version, kernel, initrd) in f.kernels(cfg)? {
+                script.push_str(&format!(
+                    "menuentry '{}, linux {version}' --class gnu-linux --class os {{\n",
+                    f.name
+                ));
+                script.push_str(&format!("\tcryptomount -u {uuid}\n"));
+                script.push_str(&format!("\tset root='cryptouuid/{uuid}'\n"));
+                script.push_str(&format!(
+                    "\tlinux {} {}\n",
+                    kernel.to_string_lossy(),
+                    f.cmdline
+                ));
+                if let Some(initrd) = initrd {
+                    script.push_str(&format!("\tinitrd {}\n", initrd.to_string_lossy()));
+                }
+                script.push_str("}\n");
+            }
+        }
+
+        script.push_str("### END cryptboot foreign kernels ###\n");
+        Ok(Some(script))
+    }
+
+    /// Applies cryptboot specific changes to the configuration generated by grub-mkconfig
+    fn postprocess(&self, cfg: &boot::Config, content: &str) -> anyhow::Result<String> {
+        let mut content = content.to_string();
+        if let Some(script) = self.foreign_entries_script(cfg)? {
+            content.push_str(&script);
+        }
+
+        let mut out = String::with_capacity(content.len());
+
+        for line in content.lines() {
+            let trimmed = line.trim_start();
+            let indent = &line[..line.len() - trimmed.len()];
+            match trimmed.split_once(' ') {
+                // entries can be booted but not edited without password
+                Some((kw @ ("menuentry" | "submenu"), rest))
+                    if self.0.menu_lock == MenuLock::EditOnly =>
+                {
+                    out.push_str(&format!("{indent}{kw} --unrestricted {rest}\n"))
+                }
+                // graphic mode must be kept for the splash screen to show up
+                Some(("linux" | "linuxefi", _)) if self.0.quiet_boot => {
+                    let mut line = line.to_string();
+                    for param in ["quiet", "splash"] {
+                        if !trimmed.split_whitespace().any(|p| p == param) {
+                            line.push(' ');
+                            line.push_str(param);
+                        }
+                    }
+                    out.push_str(&format!("{indent}set gfxpayload=keep\n{line}\n"));
+                }
+                _ => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+
+        // scripts need to be appended after the menu entries
+        if let Some(script) = self.menu_lock_script()? {
+            out.push_str(&script);
+        }
+
+        if self.0.boot_tries.is_some() {
+            if let Some(script) = self.boot_counting_script(&Self::efi_uuid(cfg)?)? {
+                out.push_str(&script);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Makes sure boot container is only unlocked by its LUKS UUID so that
+    /// configuration does not depend on device enumeration order
+    fn check_cryptomount(cfg: &boot::Config, content: &str) -> anyhow::Result<()> {
+        let uuid = Self::luks_uuid(cfg)?;
+        let mut found = false;
+
+        for line in content.lines() {
+            let mut args = line.split_whitespace();
+            if args.next() != Some("cryptomount") {
+                continue;
+            }
+
+            match (args.next(), args.next()) {
+                (Some("-u"), Some(u)) if u.replace('-', "").eq_ignore_ascii_case(&uuid) => {
+                    found = true
+                }
+                (Some("-u"), Some(u)) => {
+                    events::warn(format!("grub.cfg unlocks unknown container {u}"))
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "grub.cfg unlocks containers without UUID: {}",
+                        line.trim()
+                    ))
+                }
+            }
+        }
+
+        if !found {
+            events::warn(format!("grub.cfg does not unlock boot container {uuid}"));
+        }
+
+        Ok(())
+    }
+
+    /// Kernel images found at the root of boot partition
+    pub fn kernels(cfg: &boot::Config) -> anyhow::Result<Vec<PathBuf>> {
+        let mut kernels = vec![];
+        for e in fs::read_dir(&cfg.mountpoint)? {
+            let e = e?;
+            if e.file_name().to_string_lossy().starts_with("vmlinuz") && e.file_type()?.is_file() {
+                kernels.push(e.path());
             }
         }
+        Ok(kernels)
+    }
+
+    /// Signs kernels with machine owner key so that distribution shim accepts them
+    pub fn sign_kernels_mok(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let (Some(key), Some(cert)) = (&self.0.mok_key, &self.0.mok_cert) else {
+            return Err(anyhow!(
+                "signed_shim requires mok_key and mok_cert to sign kernels"
+            ));
+        };
+
+        for k in Self::kernels(cfg)? {
+            command::sbsign(None, key, cert, &k)?;
+            timing::count("files signed", 1);
+        }
+        Ok(())
+    }
 
-        modules
+    fn core_image(&self, cfg: &boot::Config) -> anyhow::Result<PathBuf> {
+        let name = match self.0.target.as_str() {
+            "x86_64-efi" => "grubx64.efi",
+            "i386-efi" => "grubia32.efi",
+            "arm64-efi" => "grubaa64.efi",
+            t => return Err(anyhow!("unsupported target for early config: {t}")),
+        };
+        Ok(cfg
+            .efi
+            .mountpoint
+            .join("EFI")
+            .join(&self.0.bootloader_id)
+            .join(name))
     }
 
-    pub fn mkconfig(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+    // keymap, font, serial terminal and beeps must be set in core image as they
+    // are needed before boot is unlocked
+    fn early_template(&self) -> Option<&str> {
+        match &self.0.early_config {
+            Some(t) => Some(t),
+            None if self.0.standalone => Some(STANDALONE_CONFIG),
+            None if self.0.keymap.is_some()
+                || self.0.font.is_some()
+                || self.0.terminal == Terminal::Serial
+                || self.0.beep
+                || self.checks_signatures() =>
+            {
+                Some(EARLY_CONFIG)
+            }
+            None => None,
+        }
+    }
+
+    fn render_early_config(&self, cfg: &boot::Config, template: &str) -> anyhow::Result<String> {
+        let uuid = Self::luks_uuid(cfg)?;
+
+        let mut out = String::new();
+        // everything loaded from boot (modules, grub.cfg, kernels) must be signed
+        if self.checks_signatures() {
+            out.push_str("set check_signatures=enforce\nexport check_signatures\n");
+        }
+        if self.0.font.is_some() {
+            out.push_str("loadfont (memdisk)/font.pf2\n");
+        }
+        if self.0.keymap.is_some() {
+            // grub keymaps only apply to at_keyboard input
+            out.push_str("insmod keylayouts\ninsmod at_keyboard\n");
+            out.push_str("terminal_input at_keyboard\nkeymap (memdisk)/keymap.gkb\n");
+        }
+        if self.0.terminal == Terminal::Serial {
+            out.push_str(&format!("{}\n", self.0.serial.command()));
+            out.push_str("terminal_input --append serial\nterminal_output --append serial\n");
+        }
+
+        let mut rendered = template
+            .replace("{uuid}", &uuid)
+            .replace("{prefix}", "/grub");
+        // filesystem of the decrypted boot, which is mounted
+        if rendered.contains("{fs_uuid}") {
+            let mapper = Device::Mapper(cfg.mapper_name().into());
+            let fs_uuid = command::blkid_tag(&mapper, "UUID")?
+                .ok_or(anyhow!("no filesystem UUID found on {mapper}"))?;
+            rendered = rendered.replace("{fs_uuid}", &fs_uuid);
+        }
+
+        for line in rendered.lines() {
+            match line.split_whitespace().next() {
+                // tempo 480: one short beep when prompting, a high pitch on
+                // success and two low pitched ones on failure
+                Some("cryptomount") if self.0.beep => {
+                    out.push_str("play 480 440 1\n");
+                    out.push_str(&format!(
+                        "if {}; then play 480 880 1; else play 480 220 1 0 1 220 1; fi\n",
+                        line.trim()
+                    ));
+                }
+                _ => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Builds the memdisk embedded in core image holding keymap and font,
+    /// returns None if there is nothing to embed
+    fn build_memdisk(&self, cfg: &boot::Config) -> anyhow::Result<Option<PathBuf>> {
+        if self.0.keymap.is_none() && self.0.font.is_none() {
+            return Ok(None);
+        }
+
+        let grub_dir = cfg.mountpoint.join("grub");
+        let dir = grub_dir.join("cryptboot-memdisk");
+        let tar = grub_dir.join("cryptboot-memdisk.tar");
+        if command::dry_run() {
+            return Ok(Some(tar));
+        }
+
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+
+        if let Some(font) = &self.0.font {
+            fs::copy(font, dir.join("font.pf2"))?;
+        }
+
+        if let Some(layout) = &self.0.keymap {
+            let status = command::status(
+                command("grub-kbdcomp")
+                    .arg("-o")
+                    .arg(dir.join("keymap.gkb"))
+                    .arg(layout),
+            )?;
+            if !status.success() {
+                return Err(anyhow!("grub-kbdcomp failed for {layout}: {status}"));
+            }
+        }
+
+        let status = command::status(
+            command("tar")
+                .arg("-cf")
+                .arg(&tar)
+                .arg("-C")
+                .arg(&dir)
+                .arg("."),
+        )?;
+        if !status.success() {
+            return Err(anyhow!("failed to build memdisk: {status}"));
+        }
+        Ok(Some(tar))
+    }
+
+    // renders template into the early config file, returns its path
+    fn write_early_config(&self, cfg: &boot::Config, template: &str) -> anyhow::Result<PathBuf> {
+        let early_cfg = cfg.mountpoint.join("grub").join("cryptboot-early.cfg");
+        let content = self.render_early_config(cfg, template)?;
+        match command::dry_run() {
+            true => {
+                command::dry_run_note(format!("write {}\n{content}", early_cfg.to_string_lossy()))
+            }
+            false => fs::write(&early_cfg, content)?,
+        }
+        Ok(early_cfg)
+    }
+
+    /// Replaces core image installed by grub-install with one embedding our early config
+    fn build_core_image(&self, cfg: &boot::Config, template: &str) -> anyhow::Result<()> {
+        let early_cfg = self.write_early_config(cfg, template)?;
+
+        let memdisk = self.build_memdisk(cfg)?;
+        let pubkey = self.pubkey(cfg)?;
+        self.mkimage(
+            &self.core_image(cfg)?,
+            Some(&early_cfg),
+            memdisk.as_deref(),
+            pubkey.as_deref(),
+        )?;
+        Ok(())
+    }
+
+    // paths of the early config, memdisk and public key of the installed core image
+    fn early_files(
+        &self,
+        cfg: &boot::Config,
+    ) -> (Option<PathBuf>, Option<PathBuf>, Option<PathBuf>) {
+        let grub_dir = cfg.mountpoint.join("grub");
+        if self.early_template().is_none() {
+            return (None, None, None);
+        }
+        let memdisk = Some(grub_dir.join("cryptboot-memdisk.tar")).filter(|m| m.is_file());
+        let pubkey = Some(self.pubkey_file(cfg)).filter(|_| self.checks_signatures());
+        (Some(grub_dir.join("cryptboot-early.cfg")), memdisk, pubkey)
+    }
+
+    // an embedded public key makes grub enforce signatures anyway
+    fn checks_signatures(&self) -> bool {
+        self.0.check_signatures || self.0.pubkey.is_some()
+    }
+
+    // public key embedded in core image when checking signatures
+    fn pubkey_file(&self, cfg: &boot::Config) -> PathBuf {
+        match &self.0.pubkey {
+            Some(p) => cfg.mountpoint.join(p),
+            None => cfg.mountpoint.join("grub").join("cryptboot-pubkey.gpg"),
+        }
+    }
+
+    /// Public key to embed in core image, exported from gpg_key unless
+    /// pubkey is set, None when signatures are not checked
+    fn pubkey(&self, cfg: &boot::Config) -> anyhow::Result<Option<PathBuf>> {
+        if !self.checks_signatures() {
+            return Ok(None);
+        }
+        let pubkey = self.pubkey_file(cfg);
+        match (&self.0.pubkey, &self.0.gpg_key) {
+            (Some(_), _) if !pubkey.is_file() && !command::dry_run() => Err(anyhow!(
+                "public key not found: {}",
+                pubkey.to_string_lossy()
+            )),
+            (Some(_), _) => Ok(Some(pubkey)),
+            (None, Some(key)) => {
+                command::gpg_export(key, &pubkey)?;
+                Ok(Some(pubkey))
+            }
+            (None, None) => Err(anyhow!("check_signatures requires gpg_key")),
+        }
+    }
+
+    /// Detach-signs with gpg_key the kernels, initramfs and grub files of
+    /// boot, which must be mounted
+    pub fn gpg_sign(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let Some(key) = &self.0.gpg_key else {
+            return Ok(());
+        };
+
+        let mut files = vec![];
+        for k in kernel::images(cfg)? {
+            files.extend(kernel::initrd(&k));
+            files.push(k);
+        }
+        for f in &self.0.foreign {
+            for (_, k, initrd) in f.kernels(cfg)? {
+                files.push(cfg.mountpoint.join(k.strip_prefix("/")?));
+                if let Some(i) = initrd {
+                    files.push(cfg.mountpoint.join(i.strip_prefix("/")?));
+                }
+            }
+        }
+        gpg_files(&cfg.mountpoint.join("grub"), &mut files)?;
+        // kernels on efi partition are not loaded through grub
+        files.retain(|f| f.starts_with(&cfg.mountpoint));
+        files.sort();
+        files.dedup();
+
+        for (i, f) in files.iter().enumerate() {
+            command::gpg_detach_sign(key, f)?;
+            timing::count("files gpg signed", 1);
+            events::progress("gpg-sign", i as u64 + 1, files.len() as u64);
+        }
+        Ok(())
+    }
+
+    /// Builds a single image with grub-mkstandalone, embedding every module
+    /// in its memdisk along with `files` (memdisk path, source)
+    fn mkstandalone(
+        &self,
+        out: &Path,
+        files: &[(String, PathBuf)],
+        pubkey: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        let mut cmd = command("grub-mkstandalone");
+        // preloaded modules are not read from memdisk, thus not checked
+        // against pubkey
+        // grub-mkstandalone builds the memdisk by itself
+        let modules = self.core_modules(false, pubkey.is_some())?;
+        self.check_modules(&modules)?;
+        if let Some(pubkey) = pubkey {
+            cmd.arg("--pubkey").arg(pubkey);
+        }
+        cmd.arg("-O")
+            .arg(&self.0.target)
+            .arg("-o")
+            .arg(out)
+            .arg(format!("--modules={}", modules.join(" ")));
+        for (dst, src) in files {
+            let mut graft = OsString::from(format!("{dst}="));
+            graft.push(src);
+            cmd.arg(graft);
+        }
+
+        let status = command::status(&mut cmd)?;
+        if !status.success() {
+            return Err(anyhow!("grub-mkstandalone failed: {}", status));
+        }
+        Ok(())
+    }
+
+    /// Installs a standalone image (see [Self::mkstandalone]) in place of
+    /// the one of grub-install and creates its firmware boot entry
+    fn install_standalone(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        if self.0.signed_shim {
+            return Err(anyhow!(
+                "standalone image cannot replace distribution signed grub (signed_shim)"
+            ));
+        }
+
+        let template = self.early_template().unwrap_or(STANDALONE_CONFIG);
+        let mut files = vec![(
+            "boot/grub/grub.cfg".to_string(),
+            self.write_early_config(cfg, template)?,
+        )];
+        if self.build_memdisk(cfg)?.is_some() {
+            let dir = cfg.mountpoint.join("grub").join("cryptboot-memdisk");
+            for (name, set) in [
+                ("font.pf2", self.0.font.is_some()),
+                ("keymap.gkb", self.0.keymap.is_some()),
+            ] {
+                if set {
+                    files.push((name.to_string(), dir.join(name)));
+                }
+            }
+        }
+
+        // memdisk files are checked against pubkey like any other file
+        let pubkey = self.pubkey(cfg)?;
+        if let (Some(_), Some(key)) = (&pubkey, &self.0.gpg_key) {
+            for (dst, src) in files.clone() {
+                command::gpg_detach_sign(key, &src)?;
+                let mut sig = src.into_os_string();
+                sig.push(".sig");
+                files.push((format!("{dst}.sig"), sig.into()));
+            }
+        }
+
+        let out = self.core_image(cfg)?;
+        if let (Some(dir), false) = (out.parent(), command::dry_run()) {
+            fs::create_dir_all(dir)?;
+        }
+        self.mkstandalone(&out, &files, pubkey.as_deref())?;
+
+        let loader = out
+            .strip_prefix(&cfg.efi.mountpoint)?
+            .to_string_lossy()
+            .replace('/', "\\");
+        command::efi_boot_entry(
+            &cfg.efi.device,
+            &self.0.bootloader_id,
+            &format!("\\{loader}"),
+            None,
+        )?;
+
+        // standalone images embed a timestamped memdisk, they cannot be reproduced
+        state::State::update(|s| s.grub_build = None)
+    }
+
+    // modules embedded in core image, unknown ones are reported by check_modules
+    fn core_modules(&self, memdisk: bool, pubkey: bool) -> anyhow::Result<Vec<String>> {
+        let mut modules = self.modules_for_target(&self.0.target)?;
+        if memdisk {
+            modules.push("tar".into());
+        }
+        if pubkey {
+            modules.extend(SIGNATURE_MODULES.iter().map(|m| m.to_string()));
+        }
+        if self.0.keymap.is_some() {
+            if !matches!(self.0.target.as_str(), "x86_64-efi" | "i386-efi") {
+                return Err(anyhow!("keymap is not supported on {}", self.0.target));
+            }
+            modules.extend(["keylayouts", "at_keyboard"].map(String::from));
+        }
+        if self.0.terminal == Terminal::Serial {
+            modules.extend(["serial", "terminal"].map(String::from));
+        }
+        if self.0.beep && !matches!(self.0.target.as_str(), "x86_64-efi" | "i386-efi") {
+            return Err(anyhow!("beep is not supported on {}", self.0.target));
+        }
+        Ok(modules)
+    }
+
+    /// Builds a core image with grub-mkimage, returns the modules it embeds
+    fn mkimage(
+        &self,
+        out: &Path,
+        config: Option<&Path>,
+        memdisk: Option<&Path>,
+        pubkey: Option<&Path>,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut cmd = command("grub-mkimage");
+        let modules = self.core_modules(memdisk.is_some(), pubkey.is_some())?;
+        if let Some(memdisk) = memdisk {
+            cmd.arg("-m").arg(memdisk);
+        }
+        if let Some(pubkey) = pubkey {
+            cmd.arg("--pubkey").arg(pubkey);
+        }
+
+        self.check_modules(&modules)?;
+
+        if let Some(config) = config {
+            cmd.arg("-c").arg(config);
+        }
+        let status = command::status(
+            cmd.arg("-O")
+                .arg(&self.0.target)
+                .arg("-o")
+                .arg(out)
+                .arg("-p")
+                .arg("/grub")
+                .args(&modules),
+        )?;
+
+        if !status.success() {
+            return Err(anyhow!("grub-mkimage failed: {}", status));
+        }
+        Ok(modules)
+    }
+
+    /// Records the inputs of the installed core image along with the hash of
+    /// a reference image built from them, boot must be mounted
+    pub fn build_inputs(&self, cfg: &boot::Config) -> anyhow::Result<state::GrubBuild> {
+        let (config, memdisk, pubkey) = self.early_files(cfg);
+        let hash = |p: &Option<PathBuf>| -> anyhow::Result<Option<String>> {
+            match p {
+                Some(p) if p.is_file() => Ok(Some(command::sha256sum(p)?)),
+                _ => Ok(None),
+            }
+        };
+
+        // images built twice from the same inputs must be identical
+        let tmp = PathBuf::from(format!("/run/cryptboot-grub-{}.efi", std::process::id()));
+        let res = self.mkimage(
+            &tmp,
+            config.as_deref(),
+            memdisk.as_deref(),
+            pubkey.as_deref(),
+        );
+        let image = match (&res, command::dry_run()) {
+            (Ok(_), false) => command::sha256sum(&tmp),
+            _ => Ok(String::new()),
+        };
+        let _ = fs::remove_file(&tmp);
+
+        Ok(state::GrubBuild {
+            version: self.version()?,
+            target: self.0.target.clone(),
+            modules: res?,
+            early_config: hash(&config)?,
+            memdisk: hash(&memdisk)?,
+            image: image?,
+        })
+    }
+}
+
+impl Bootloader for Grub {
+    fn mkconfig(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        // we fail before overwriting grub.cfg if menu lock is misconfigured
+        self.menu_lock_script()?;
+        // grub.cfg is signed right away, grub would refuse it until next signature
+        if self.checks_signatures() && self.0.gpg_key.is_none() {
+            return Err(anyhow!(
+                "checking signatures requires gpg_key to sign grub.cfg"
+            ));
+        }
+        // without plymouth, splash hides the passphrase prompt of the initramfs
+        if self.0.quiet_boot
+            && !["/usr/bin/plymouth", "/bin/plymouth"]
+                .iter()
+                .any(|p| Path::new(p).exists())
+        {
+            return Err(anyhow!(
+                "quiet_boot requires plymouth to display passphrase prompt"
+            ));
+        }
+
         let grub_dir = cfg.mountpoint.join("grub");
         // create grub directory if it does not exists
-        if !grub_dir.exists() {
+        if !grub_dir.exists() && !command::dry_run() {
             fs::create_dir(&grub_dir)?;
         }
-        let status = command("grub-mkconfig")
-            .arg("-o")
-            .arg(grub_dir.join("grub.cfg"))
-            .status()?;
+        let grub_cfg = Self::config_file(cfg);
+        // generated next to grub.cfg so that it only lands once checked
+        let generated = grub_cfg.with_extension("cfg.new");
+        let mut mkconfig = command("grub-mkconfig");
+        mkconfig.env("GRUB_ENABLE_CRYPTODISK", "y");
+        if let Some(mode) = &self.0.gfxmode {
+            mkconfig.env("GRUB_GFXMODE", mode);
+        }
+        if self.0.terminal == Terminal::Serial {
+            mkconfig
+                .env("GRUB_TERMINAL", "serial console")
+                .env("GRUB_SERIAL_COMMAND", self.0.serial.command());
+        }
+        let status = command::status(mkconfig.arg("-o").arg(&generated))?;
 
         if !status.success() {
+            let _ = fs::remove_file(&generated);
             return Err(anyhow!("grub-mkconfig failed: {}", status));
         }
 
+        // nothing was generated to post-process
+        if command::dry_run() {
+            command::dry_run_note(format!(
+                "check and move {} to {}",
+                generated.to_string_lossy(),
+                grub_cfg.to_string_lossy()
+            ));
+            if let (true, Some(key)) = (self.checks_signatures(), &self.0.gpg_key) {
+                command::gpg_detach_sign(key, &grub_cfg)?;
+            }
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(&generated);
+        let _ = fs::remove_file(&generated);
+        let content = self.postprocess(cfg, &content?)?;
+        // only LUKS containers have an UUID grub can unlock
+        if cfg.container_type == boot::ContainerType::Luks {
+            Self::check_cryptomount(cfg, &content)?;
+        }
+        // i.e. GRUB_CMDLINE_LINUX misconfigured in /etc/default/grub
+        for f in secret_findings(&content) {
+            events::warn(format!("{}: {f}", grub_cfg.to_string_lossy()));
+        }
+        write_private(&grub_cfg, &content)?;
+
+        if let (true, Some(key)) = (self.checks_signatures(), &self.0.gpg_key) {
+            command::gpg_detach_sign(key, &grub_cfg)?;
+        }
+
         Ok(())
     }
 
-    pub fn install(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+    fn install(&self, cfg: &boot::Config) -> anyhow::Result<()> {
         let esp = &cfg.efi.mountpoint;
 
         if !esp.is_dir() {
@@ -176,20 +1314,223 @@ impl Grub {
             ));
         }
 
-        let status = command("grub-install")
+        if self.0.standalone {
+            return self.install_standalone(cfg);
+        }
+
+        if self.0.signed_shim && self.early_template().is_some() {
+            return Err(anyhow!(
+                "early config cannot be embedded in distribution signed grub"
+            ));
+        }
+
+        let mut cmd = command("grub-install");
+        cmd.env("GRUB_ENABLE_CRYPTODISK", "y")
             .arg(format!("--target={}", self.0.target))
             .arg(format!("--efi-directory={}", esp.to_string_lossy()))
-            .arg(format!("--bootloader-id={}", self.0.bootloader_id))
-            .arg(format!(
-                "--modules={}",
-                self.modules_for_target(&self.0.target).join(" ")
-            ))
-            .arg("--disable-shim-lock")
-            .status()?;
+            .arg(format!("--bootloader-id={}", self.0.bootloader_id));
+
+        if self.0.signed_shim {
+            // signed images are prebuilt so modules cannot be added
+            cmd.arg("--uefi-secure-boot");
+        } else {
+            let mut modules = self.modules_for_target(&self.0.target)?;
+            // core image of grub-install verifies signatures too
+            if let Some(pubkey) = self.pubkey(cfg)? {
+                cmd.arg("--pubkey").arg(pubkey);
+                modules.extend(SIGNATURE_MODULES.iter().map(|m| m.to_string()));
+            }
+            self.check_modules(&modules)?;
+            cmd.arg(format!("--modules={}", modules.join(" ")))
+                .arg("--disable-shim-lock");
+        }
+
+        let status = command::status(&mut cmd)?;
 
         if !status.success() {
             return Err(anyhow!("grub-install failed: {}", status));
         }
+
+        if let Some(template) = self.early_template() {
+            self.build_core_image(cfg, template)?;
+        }
+
+        // distribution signed images are not built here
+        if !self.0.signed_shim {
+            let inputs = self.build_inputs(cfg)?;
+            state::State::update(|s| s.grub_build = Some(inputs))?;
+        }
+
         Ok(())
     }
+
+    /// Arms boot counting for the freshly installed boot chain
+    fn arm_boot_counter(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        if let Some(tries) = self.0.boot_tries {
+            command::grub_editenv(
+                self.counter_env_file(cfg),
+                &["set", "boot_success=0", &format!("boot_counter={tries}")],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Flags the current boot as successful and clears any pending boot counter
+    fn mark_good(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        // nothing to clear when boot counting was never armed
+        let env = self.counter_env_file(cfg);
+        if !env.exists() && !command::dry_run() {
+            return Ok(());
+        }
+        command::grub_editenv(&env, &["set", "boot_success=1"])?;
+        command::grub_editenv(&env, &["unset", "boot_counter"])
+    }
+
+    /// Files and directories written by mkconfig and install
+    fn managed_files(&self, cfg: &boot::Config) -> Vec<PathBuf> {
+        vec![
+            Self::config_file(cfg),
+            cfg.efi.mountpoint.join("EFI").join(&self.0.bootloader_id),
+        ]
+    }
+
+    fn version(&self) -> anyhow::Result<String> {
+        let output = command("grub-install").arg("--version").output()?;
+        if !output.status.success() {
+            return Err(anyhow!("grub-install --version failed: {}", output.status));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grub(config: Config) -> Grub {
+        Grub::from_config(config)
+    }
+
+    const HASH: &str = "grub.pbkdf2.sha512.10000.0011AA.22BB33";
+
+    #[test]
+    fn menu_lock_script() {
+        assert!(grub(Config::default())
+            .menu_lock_script()
+            .unwrap()
+            .is_none());
+
+        let g = grub(Config {
+            menu_lock: MenuLock::Full,
+            superuser: "admin_1".into(),
+            password_pbkdf2: Some(HASH.into()),
+            ..Default::default()
+        });
+        let script = g.menu_lock_script().unwrap().unwrap();
+        assert!(script.contains("set superusers=\"admin_1\"\n"));
+        assert!(script.contains(&format!("password_pbkdf2 admin_1 {HASH}\n")));
+    }
+
+    #[test]
+    fn menu_lock_rejects_invalid_values() {
+        let lock = |user: &str, password: Option<&str>| {
+            grub(Config {
+                menu_lock: MenuLock::EditOnly,
+                superuser: user.into(),
+                password_pbkdf2: password.map(String::from),
+                ..Default::default()
+            })
+            .menu_lock_script()
+        };
+
+        assert!(lock("root", None).is_err());
+        for user in ["", "my user", "root\"", "root;reboot", "root\n"] {
+            assert!(lock(user, Some(HASH)).is_err(), "{user:?}");
+        }
+        for password in [
+            "secret",
+            "grub.pbkdf2.sha512 x",
+            "grub.pbkdf2.x\"",
+            "grub.pbkdf2.x\nreboot",
+        ] {
+            assert!(lock("root", Some(password)).is_err(), "{password:?}");
+        }
+    }
+
+    #[test]
+    fn boot_counting_script() {
+        let g = grub(Config {
+            boot_tries: Some(2),
+            fallback_entry: Some("gnulinux-advanced-1234>gnulinux-6.1-advanced-1234".into()),
+            ..Default::default()
+        });
+        let script = g.boot_counting_script("ABCD-EF01").unwrap().unwrap();
+        let env = "($cryptboot_esp)/EFI/GRUB/cryptboot-counter.env";
+
+        assert!(script.contains("search --no-floppy --fs-uuid --set=cryptboot_esp ABCD-EF01\n"));
+        assert!(script.contains(&format!("load_env -f {env} boot_success boot_counter\n")));
+        assert!(script.contains(&format!("  save_env -f {env} boot_counter\n")));
+        assert!(
+            script.contains("set default='gnulinux-advanced-1234>gnulinux-6.1-advanced-1234'\n")
+        );
+        assert!(script
+            .contains("  elif [ \"${boot_counter}\" = \"2\" ]; then\n    set boot_counter=1\n"));
+        assert!(script
+            .contains("  elif [ \"${boot_counter}\" = \"1\" ]; then\n    set boot_counter=0\n"));
+        // grubenv of the encrypted boot cannot be written by grub
+        assert!(!script.contains("save_env boot_counter"));
+    }
+
+    #[test]
+    fn boot_counting_skips_signature_of_counter() {
+        let g = grub(Config {
+            boot_tries: Some(1),
+            fallback_entry: Some("fallback".into()),
+            check_signatures: true,
+            ..Default::default()
+        });
+        let script = g.boot_counting_script("ABCD-EF01").unwrap().unwrap();
+        assert!(script.contains("load_env --skip-sig -f "));
+    }
+
+    #[test]
+    fn boot_counting_requires_fallback_entry() {
+        assert!(grub(Config::default())
+            .boot_counting_script("ABCD-EF01")
+            .unwrap()
+            .is_none());
+
+        for fallback in [None, Some("1"), Some(""), Some("it's"), Some("a\nb")] {
+            let g = grub(Config {
+                boot_tries: Some(3),
+                fallback_entry: fallback.map(String::from),
+                ..Default::default()
+            });
+            assert!(g.boot_counting_script("ABCD-EF01").is_err(), "{fallback:?}");
+        }
+    }
+
+    #[test]
+    fn secret_findings_plaintext_password() {
+        let findings = secret_findings("set superusers=\"root\"\npassword root hunter2\n");
+        assert_eq!(findings, ["line 2: plaintext password for user root"]);
+    }
+
+    #[test]
+    fn secret_findings_ignores_hashed_password() {
+        assert!(secret_findings(&format!("password_pbkdf2 root {HASH}\n")).is_empty());
+    }
+
+    #[test]
+    fn secret_findings_kernel_parameters() {
+        let cfg = "menuentry 'Linux' {\n\tlinux /vmlinuz root=/dev/mapper/root cryptkey=rootfs:/key.bin\n    linuxefi /vmlinuz passphrase=secret quiet\n}\n";
+        assert_eq!(
+            secret_findings(cfg),
+            [
+                "line 2: kernel command line references key file (cryptkey=rootfs:/key.bin)",
+                "line 3: plaintext secret in kernel parameter passphrase=",
+            ]
+        );
+        assert!(secret_findings("\tlinux /vmlinuz root=/dev/mapper/root quiet\n").is_empty());
+    }
 }
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+use crate::output;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Warn => write!(f, "warn"),
+            Self::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // maximum age in days of the LUKS header backup
+    pub max_header_backup_age: u64,
+    // number of kernels expected to be kept in boot partition
+    pub retained_kernels: u64,
+    // space in MiB needed by one kernel and its initramfs
+    pub size_per_kernel: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_header_backup_age: 180,
+            retained_kernels: 2,
+            size_per_kernel: 128,
+        }
+    }
+}
+
+/// Result of checks meant to be consumed by monitoring tools
+#[derive(Debug, Default)]
+pub struct Report {
+    checks: Vec<Check>,
+    // whether the score is printed along with the status
+    scored: bool,
+}
+
+impl Report {
+    /// Report printing a score, for reviews comparing machines
+    pub fn scored() -> Self {
+        Self {
+            scored: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn push<S: Into<String>>(&mut self, name: &'static str, status: Status, message: S) {
+        self.checks.push(Check {
+            name,
+            status,
+            message: message.into(),
+        })
+    }
+
+    pub fn ok<S: Into<String>>(&mut self, name: &'static str, message: S) {
+        self.push(name, Status::Ok, message)
+    }
+
+    pub fn warn<S: Into<String>>(&mut self, name: &'static str, message: S) {
+        self.push(name, Status::Warn, message)
+    }
+
+    pub fn fail<S: Into<String>>(&mut self, name: &'static str, message: S) {
+        self.push(name, Status::Fail, message)
+    }
+
+    /// Moves the checks of `other` at the end of this report
+    pub fn append(&mut self, mut other: Report) {
+        self.checks.append(&mut other.checks)
+    }
+
+    /// Worst status among checks
+    pub fn status(&self) -> Status {
+        self.checks
+            .iter()
+            .map(|c| c.status)
+            .fold(Status::Ok, |acc, s| if s > acc { s } else { acc })
+    }
+
+    /// Score out of 100, a warning counting half a passed check
+    pub fn score(&self) -> u64 {
+        if self.checks.is_empty() {
+            return 100;
+        }
+        let points: u64 = self
+            .checks
+            .iter()
+            .map(|c| match c.status {
+                Status::Ok => 2,
+                Status::Warn => 1,
+                Status::Fail => 0,
+            })
+            .sum();
+        points * 50 / self.checks.len() as u64
+    }
+
+    /// Prints one `check=<name> status=<status> message="<message>"` line per
+    /// check or a JSON object in JSON output mode
+    pub fn print(&self) {
+        if output::json() {
+            #[derive(Serialize)]
+            struct Json<'a> {
+                status: Status,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                score: Option<u64>,
+                checks: &'a [Check],
+            }
+            let json = Json {
+                status: self.status(),
+                score: self.scored.then(|| self.score()),
+                checks: &self.checks,
+            };
+            match output::to_json(&json) {
+                Ok(j) => println!("{j}"),
+                Err(e) => eprintln!("failed to serialize report: {e}"),
+            }
+            return;
+        }
+
+        for c in self.checks.iter() {
+            println!(
+                "check={} status={} message={:?}",
+                c.name, c.status, c.message
+            );
+        }
+        println!("status={}", self.status());
+        if self.scored {
+            println!("score={}", self.score());
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{health::Report, mounts};
+
+const CHECK: &str = "hibernate";
+
+struct Swap {
+    path: PathBuf,
+    is_file: bool,
+}
+
+fn swaps() -> anyhow::Result<Vec<Swap>> {
+    Ok(fs::read_to_string("/proc/swaps")?
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            Some(Swap {
+                path: PathBuf::from(mounts::unescape(fields.next()?)),
+                is_file: fields.next()? == "file",
+            })
+        })
+        .collect())
+}
+
+fn cmdline_param<'a>(cmdline: &'a str, name: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+}
+
+// resolves UUID=, PARTUUID= and LABEL= specifications to a device path
+fn resolve_spec(spec: &str) -> PathBuf {
+    let dev = match spec.split_once('=') {
+        Some(("UUID", u)) => PathBuf::from("/dev/disk/by-uuid").join(u),
+        Some(("PARTUUID", u)) => PathBuf::from("/dev/disk/by-partuuid").join(u),
+        Some(("LABEL", l)) => PathBuf::from("/dev/disk/by-label").join(l),
+        _ => PathBuf::from(spec),
+    };
+    fs::canonicalize(&dev).unwrap_or(dev)
+}
+
+// device holding a swap file
+fn file_device(path: &Path) -> Option<PathBuf> {
+    let mount = mounts::mounts()
+        .ok()?
+        .into_iter()
+        .filter(|m| path.starts_with(&m.target))
+        .max_by_key(|m| m.target.as_os_str().len())?;
+    Some(resolve_spec(&mount.source))
+}
+
+// swap devices opened with a random key in crypttab cannot be resumed from
+fn random_key_swaps() -> Vec<PathBuf> {
+    let Ok(crypttab) = fs::read_to_string("/etc/crypttab") else {
+        return vec![];
+    };
+
+    crypttab
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            let name = fields.next()?;
+            let _device = fields.next()?;
+            let key = fields.next()?;
+            matches!(key, "/dev/urandom" | "/dev/random")
+                .then(|| resolve_spec(&format!("/dev/mapper/{name}")))
+        })
+        .collect()
+}
+
+/// Flags configurations where hibernating would not resume
+pub fn check(r: &mut Report) {
+    let cmdline = match fs::read_to_string("/proc/cmdline") {
+        Ok(c) => c,
+        Err(e) => return r.fail(CHECK, format!("/proc/cmdline: {e}")),
+    };
+
+    let swaps = match swaps() {
+        Ok(s) => s,
+        Err(e) => return r.fail(CHECK, format!("/proc/swaps: {e}")),
+    };
+
+    let Some(resume) = cmdline_param(&cmdline, "resume") else {
+        return r.ok(CHECK, "hibernation is not configured");
+    };
+    let resume = resolve_spec(resume);
+
+    let Some(swap) = swaps.iter().find(|s| {
+        if s.is_file {
+            file_device(&s.path).as_ref() == Some(&resume)
+        } else {
+            fs::canonicalize(&s.path).ok().as_ref() == Some(&resume)
+        }
+    }) else {
+        return r.warn(
+            CHECK,
+            format!(
+                "resume device {} is not an active swap",
+                resume.to_string_lossy()
+            ),
+        );
+    };
+
+    if swap.is_file && cmdline_param(&cmdline, "resume_offset").is_none() {
+        return r.warn(
+            CHECK,
+            format!(
+                "swap file {} requires resume_offset= on kernel command line",
+                swap.path.to_string_lossy()
+            ),
+        );
+    }
+
+    if random_key_swaps().contains(&resume) {
+        return r.warn(
+            CHECK,
+            format!(
+                "swap {} is encrypted with a random key and cannot be resumed from",
+                resume.to_string_lossy()
+            ),
+        );
+    }
+
+    r.ok(CHECK, format!("resuming from {}", resume.to_string_lossy()))
+}
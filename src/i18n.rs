@@ -0,0 +1,95 @@
+use std::{collections::HashMap, env, fs, path::PathBuf, sync::OnceLock};
+
+// translations are TOML files mapping message ids to translated messages
+const LOCALE_DIR: &str = "/usr/share/cryptboot/locale";
+
+// english catalog, also used as fallback for missing translations
+const EN: &[(&str, &str)] = &[
+    ("need-root", "this program needs to run as root"),
+    ("confirm-choices", "[y|n]"),
+    ("confirm-yes", "y|yes"),
+    ("proceed", "Proceed ?"),
+    ("ask-passphrase", "Passphrase for {device}:"),
+    ("ask-pin", "PIN of signing key {uri}:"),
+    (
+        "efi-read-only",
+        "efi is mounted read-only, tools updating it (bootctl, kernel-install ...) must go through cryptboot run",
+    ),
+    (
+        "efi-skipped",
+        "efi is not mounted, tools updating it (bootctl, kernel-install ...) must go through cryptboot run",
+    ),
+    (
+        "different-disks",
+        "warning: boot and efi devices are on different disks",
+    ),
+    (
+        "luks-compatible",
+        "boot device is already compatible with grub",
+    ),
+    (
+        "keyslot-convert",
+        "keyslot {id} uses {pbkdf} and will be converted to pbkdf2",
+    ),
+    (
+        "luks1-convert",
+        "installed grub does not support LUKS2, device will be converted to LUKS1",
+    ),
+    ("header-saved", "LUKS header saved to {path}"),
+    (
+        "change-keyslot",
+        "Change keyslot to PBKDF2-{hash} with {iterations} iterations ?",
+    ),
+];
+
+static CATALOG: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+// candidate languages from the environment, i.e. fr_FR.UTF-8 gives fr_FR then fr
+fn languages() -> Vec<String> {
+    let Some(lang) = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|v| env::var(v).ok())
+        .find(|v| !v.is_empty())
+    else {
+        return vec![];
+    };
+
+    let lang = lang
+        .split(['.', '@'])
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut out = vec![lang.clone()];
+    if let Some((short, _)) = lang.split_once('_') {
+        out.push(short.to_string());
+    }
+    out
+}
+
+fn catalog() -> &'static HashMap<String, String> {
+    CATALOG.get_or_init(|| {
+        for lang in languages() {
+            let path = PathBuf::from(LOCALE_DIR).join(format!("{lang}.toml"));
+            if let Ok(c) = fs::read_to_string(path) {
+                if let Ok(m) = toml::from_str(&c) {
+                    return m;
+                }
+            }
+        }
+        HashMap::new()
+    })
+}
+
+/// Returns the translation of message `id` with `{name}` placeholders
+/// replaced by their values in `args`
+pub fn tr(id: &str, args: &[(&str, &str)]) -> String {
+    let msg = catalog()
+        .get(id)
+        .map(String::as_str)
+        .or_else(|| EN.iter().find(|(k, _)| *k == id).map(|(_, v)| *v))
+        .unwrap_or(id);
+
+    args.iter().fold(msg.to_string(), |m, (k, v)| {
+        m.replace(&format!("{{{k}}}"), v)
+    })
+}
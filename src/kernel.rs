@@ -0,0 +1,151 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::boot;
+
+const CMDLINE: &str = "/proc/cmdline";
+const OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+const VERSION: &str = "/proc/sys/kernel/version";
+
+// initramfs naming conventions of the different distributions
+const INITRD_NAMES: &[(&str, &str)] =
+    &[("initramfs-", ".img"), ("initrd.img-", ""), ("initrd-", "")];
+
+// offsets in x86 boot protocol header (Documentation/arch/x86/boot.rst)
+const SETUP_HEADER_MAGIC: usize = 0x202;
+const KERNEL_VERSION_PTR: usize = 0x20e;
+// index of certificate table in PE data directories
+const SECURITY_DIRECTORY: usize = 4;
+
+/// Version string of the running kernel as found in its image, release
+/// followed by build version (i.e. 6.6.1-arch1-1 (linux@archlinux) #1 SMP ...)
+pub fn running() -> anyhow::Result<(String, String)> {
+    Ok((
+        fs::read_to_string(OSRELEASE)?.trim().to_string(),
+        fs::read_to_string(VERSION)?.trim().to_string(),
+    ))
+}
+
+/// Whether kernel version string `image` identifies the running kernel
+pub fn is_running(image: &str, running: &(String, String)) -> bool {
+    let (release, version) = running;
+    image.split(' ').next() == Some(release.as_str()) && image.contains(version.as_str())
+}
+
+fn u16_at(data: &[u8], off: usize) -> Option<usize> {
+    Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?) as usize)
+}
+
+fn u32_at(data: &[u8], off: usize) -> Option<usize> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?) as usize)
+}
+
+/// Version string embedded in a bzImage, None for other formats
+pub fn image_version(data: &[u8]) -> Option<String> {
+    if data.get(SETUP_HEADER_MAGIC..SETUP_HEADER_MAGIC + 4) != Some(b"HdrS") {
+        return None;
+    }
+    let start = u16_at(data, KERNEL_VERSION_PTR)? + 0x200;
+    let s = data.get(start..)?;
+    let end = s.iter().position(|&b| b == 0)?;
+    Some(String::from_utf8_lossy(&s[..end]).to_string())
+}
+
+/// Whether a PE image carries an Authenticode signature
+pub fn is_signed(data: &[u8]) -> bool {
+    let signed = || -> Option<bool> {
+        let pe = u32_at(data, 0x3c)?;
+        if data.get(pe..pe + 4) != Some(b"PE\0\0") {
+            return Some(false);
+        }
+        // optional header follows the 20 bytes COFF header
+        let opt = pe + 24;
+        let (count, dirs) = match u16_at(data, opt)? {
+            0x10b => (u32_at(data, opt + 92)?, opt + 96),
+            0x20b => (u32_at(data, opt + 108)?, opt + 112),
+            _ => return Some(false),
+        };
+        if count <= SECURITY_DIRECTORY {
+            return Some(false);
+        }
+        Some(u32_at(data, dirs + SECURITY_DIRECTORY * 8 + 4)? > 0)
+    };
+    signed().unwrap_or(false)
+}
+
+/// Command line of the running kernel without the parameters set by the
+/// bootloader for the image it loaded
+pub fn running_cmdline() -> anyhow::Result<String> {
+    Ok(fs::read_to_string(CMDLINE)?
+        .split_whitespace()
+        .filter(|p| !p.starts_with("BOOT_IMAGE=") && !p.starts_with("initrd="))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Initramfs matching kernel image `kernel` (i.e. vmlinuz-linux -> initramfs-linux.img)
+pub fn initrd(kernel: &Path) -> Option<PathBuf> {
+    let name = kernel.file_name()?.to_string_lossy();
+    let version = name.strip_prefix("vmlinuz-")?;
+    let dir = kernel.parent()?;
+    INITRD_NAMES
+        .iter()
+        .map(|(prefix, suffix)| dir.join(format!("{prefix}{version}{suffix}")))
+        .find(|p| p.is_file())
+}
+
+/// Most recently installed kernel image at the root of boot and efi
+/// partitions, which must be mounted
+pub fn latest(cfg: &boot::Config) -> anyhow::Result<Option<PathBuf>> {
+    let mut latest = None;
+    for i in images(cfg)? {
+        let modified = fs::metadata(&i)?.modified()?;
+        if latest.as_ref().is_none_or(|(m, _)| modified > *m) {
+            latest = Some((modified, i));
+        }
+    }
+    Ok(latest.map(|(_, i)| i))
+}
+
+fn images_in(dir: &Path, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        if e.file_name().to_string_lossy().starts_with("vmlinuz") && e.file_type()?.is_file() {
+            out.push(e.path());
+        }
+    }
+    Ok(())
+}
+
+/// Kernel images at the root of boot and efi partitions
+pub fn images(cfg: &boot::Config) -> anyhow::Result<Vec<PathBuf>> {
+    let mut images = vec![];
+    images_in(&cfg.mountpoint, &mut images)?;
+    if cfg.efi.mountpoint.is_dir() {
+        images_in(&cfg.efi.mountpoint, &mut images)?;
+    }
+    Ok(images)
+}
+
+/// Version strings of the signed kernel images found at the root of boot and
+/// efi partitions, which must be mounted
+pub fn signed_versions(cfg: &boot::Config) -> anyhow::Result<Vec<String>> {
+    let mut versions = vec![];
+    for i in images(cfg)? {
+        let data =
+            fs::read(&i).map_err(|e| anyhow!("failed to read {}: {e}", i.to_string_lossy()))?;
+        if !is_signed(&data) {
+            continue;
+        }
+        if let Some(v) = image_version(&data) {
+            versions.push(v);
+        }
+    }
+    versions.sort();
+    versions.dedup();
+    Ok(versions)
+}
@@ -0,0 +1,39 @@
+use std::{
+    fs::{self, OpenOptions},
+    os::fd::AsRawFd,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+
+const LOCK_PATH: &str = "/run/cryptboot.lock";
+
+/// Takes the lock serializing cryptboot invocations, waiting up to `wait`
+/// seconds for the running one to finish. The lock is released when the
+/// returned file is closed, at the latest when the process exits.
+pub fn acquire(wait: Option<u64>) -> anyhow::Result<fs::File> {
+    let f = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(LOCK_PATH)
+        .map_err(|e| anyhow!("failed to open lock file {LOCK_PATH}: {e}"))?;
+
+    let deadline = Instant::now() + Duration::from_secs(wait.unwrap_or_default());
+    loop {
+        if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(f);
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(anyhow!("failed to lock {LOCK_PATH}: {err}"));
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "another cryptboot is running (lock {LOCK_PATH}), use --wait to wait for it"
+            ));
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
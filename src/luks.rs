@@ -0,0 +1,409 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::{
+    command::{self, command},
+    Device,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct Keyslot {
+    pub id: u32,
+    pub pbkdf: String,
+    pub iterations: Option<u64>,
+}
+
+/// Information parsed from cryptsetup luksDump
+#[derive(Debug, Default, Clone)]
+pub struct Header {
+    pub version: u8,
+    pub keyslots: Vec<Keyslot>,
+    // LUKS2 token types (i.e. systemd-tpm2)
+    pub tokens: Vec<String>,
+}
+
+// top level sections of luksDump output, LUKS1 only has keyslots
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Header,
+    Keyslots,
+    Tokens,
+    // i.e. Data segments, Digests, whose iterations are not keyslot ones
+    Other,
+}
+
+impl Header {
+    fn parse(dump: &str) -> Self {
+        let mut h = Self::default();
+        let mut section = Section::Header;
+
+        for line in dump.lines() {
+            let trimmed = line.trim();
+
+            // LUKS2 section titles are the only unindented lines ending with ':'
+            if !line.starts_with(char::is_whitespace) && trimmed.ends_with(':') {
+                section = match trimmed {
+                    "Keyslots:" => Section::Keyslots,
+                    "Tokens:" => Section::Tokens,
+                    _ => Section::Other,
+                };
+                continue;
+            }
+
+            if let Some(v) = trimmed.strip_prefix("Version:") {
+                h.version = v.trim().parse().unwrap_or_default();
+                continue;
+            }
+
+            // LUKS1 keyslot header
+            if let Some(rest) = trimmed.strip_prefix("Key Slot ") {
+                section = Section::Other;
+                if let Some((id, "ENABLED")) = rest.split_once(": ") {
+                    section = Section::Keyslots;
+                    h.keyslots.push(Keyslot {
+                        id: id.parse().unwrap_or_default(),
+                        pbkdf: "pbkdf2".into(),
+                        iterations: None,
+                    });
+                }
+                continue;
+            }
+
+            let entry = trimmed
+                .split_once(": ")
+                .filter(|(id, _)| id.parse::<u32>().is_ok());
+            match (section, entry) {
+                // LUKS2 token
+                (Section::Tokens, Some((_, t))) => {
+                    if t.starts_with("systemd-") {
+                        h.tokens.push(t.into());
+                    }
+                    continue;
+                }
+                // LUKS2 keyslot header
+                (Section::Keyslots, Some((id, _))) => {
+                    h.keyslots.push(Keyslot {
+                        id: id.parse().unwrap_or_default(),
+                        ..Default::default()
+                    });
+                    continue;
+                }
+                (Section::Keyslots, None) => {}
+                _ => continue,
+            }
+
+            let Some(slot) = h.keyslots.last_mut() else {
+                continue;
+            };
+
+            if let Some(v) = trimmed.strip_prefix("PBKDF:") {
+                slot.pbkdf = v.trim().into();
+            } else if let Some(v) = trimmed.strip_prefix("Iterations:") {
+                slot.iterations = v.trim().parse().ok();
+            }
+        }
+
+        h
+    }
+}
+
+pub fn dump(dev: &Device) -> anyhow::Result<Header> {
+    let output = command("cryptsetup")
+        .arg("luksDump")
+        .arg(dev.full_path())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "cryptsetup luksDump failed on {dev}: {}",
+            output.status
+        ));
+    }
+
+    Ok(Header::parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Returns the number of PBKDF2 iterations per second cryptsetup measures for `hash`
+pub fn benchmark_pbkdf2(hash: &str) -> anyhow::Result<u64> {
+    let output = command("cryptsetup")
+        .arg("benchmark")
+        .arg("--pbkdf")
+        .arg("pbkdf2")
+        .arg("--hash")
+        .arg(hash)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!("cryptsetup benchmark failed: {}", output.status));
+    }
+
+    // PBKDF2-sha256    1234567 iterations per second for 256-bit key
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|l| {
+            let mut fields = l.split_whitespace();
+            fields
+                .next()
+                .filter(|f| f.starts_with("PBKDF2-"))
+                .and_then(|_| fields.next())
+                .and_then(|n| n.parse().ok())
+        })
+        .ok_or(anyhow!("failed to parse cryptsetup benchmark output"))
+}
+
+/// Sets PBKDF2 with a fixed number of iterations on `slot` or on the keyslot
+/// unlocked by the passphrase cryptsetup prompts for
+pub fn set_pbkdf2_iterations(
+    dev: &Device,
+    header: &Header,
+    slot: Option<u32>,
+    hash: &str,
+    iterations: u64,
+) -> anyhow::Result<()> {
+    let mut cmd = command("cryptsetup");
+    match header.version {
+        // only luksChangeKey can re-encrypt a LUKS1 keyslot
+        1 => cmd.arg("luksChangeKey"),
+        _ => cmd.arg("luksConvertKey").arg("--pbkdf").arg("pbkdf2"),
+    };
+
+    if let Some(slot) = slot {
+        cmd.arg("--key-slot").arg(slot.to_string());
+    }
+
+    let status = command::status(
+        cmd.arg("--hash")
+            .arg(hash)
+            .arg("--pbkdf-force-iterations")
+            .arg(iterations.to_string())
+            .arg(dev.full_path()),
+    )?;
+
+    if !status.success() {
+        return Err(anyhow!("failed to change keyslot pbkdf: {status}"));
+    }
+    Ok(())
+}
+
+pub fn header_backup<P: AsRef<Path>>(dev: &Device, file: P) -> anyhow::Result<()> {
+    let status = command::status(
+        command("cryptsetup")
+            .arg("luksHeaderBackup")
+            .arg(dev.full_path())
+            .arg("--header-backup-file")
+            .arg(file.as_ref()),
+    )?;
+
+    if !status.success() {
+        return Err(anyhow!("cryptsetup luksHeaderBackup failed: {status}"));
+    }
+    Ok(())
+}
+
+/// Enrolls a keyslot unlocked by TPM2 when `pcrs` (i.e. 7+14) are unchanged,
+/// systemd-cryptenroll asks for an existing passphrase
+pub fn enroll_tpm2(dev: &Device, pcrs: &str) -> anyhow::Result<()> {
+    let status = command::status(
+        command("systemd-cryptenroll")
+            .arg("--tpm2-device=auto")
+            .arg(format!("--tpm2-pcrs={pcrs}"))
+            .arg(dev.full_path()),
+    )?;
+
+    if !status.success() {
+        return Err(anyhow!("systemd-cryptenroll failed: {status}"));
+    }
+    Ok(())
+}
+
+/// Enrolls a keyslot unlocked by a FIDO2 security key, systemd-cryptenroll
+/// asks for an existing passphrase and the key PIN
+pub fn enroll_fido2(dev: &Device) -> anyhow::Result<()> {
+    let status = command::status(
+        command("systemd-cryptenroll")
+            .arg("--fido2-device=auto")
+            .arg(dev.full_path()),
+    )?;
+
+    if !status.success() {
+        return Err(anyhow!("systemd-cryptenroll failed: {status}"));
+    }
+    Ok(())
+}
+
+/// Adds a keyslot unlocked by the content of `keyfile`, cryptsetup asks for
+/// an existing passphrase
+pub fn add_key<P: AsRef<Path>>(dev: &Device, keyfile: P) -> anyhow::Result<()> {
+    let status = command::status(
+        command("cryptsetup")
+            .arg("luksAddKey")
+            .arg(dev.full_path())
+            .arg(keyfile.as_ref()),
+    )?;
+
+    if !status.success() {
+        return Err(anyhow!("cryptsetup luksAddKey failed: {status}"));
+    }
+    Ok(())
+}
+
+/// Converts a LUKS2 device to LUKS1, device must be closed and all keyslots use PBKDF2
+pub fn convert_luks1(dev: &Device) -> anyhow::Result<()> {
+    let status = command::status(
+        command("cryptsetup")
+            .arg("convert")
+            .arg("-q")
+            .arg("--type")
+            .arg("luks1")
+            .arg(dev.full_path()),
+    )?;
+
+    if !status.success() {
+        return Err(anyhow!("cryptsetup convert failed: {status}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LUKS1_DUMP: &str = "LUKS header information for /dev/sda2
+
+Version:       \t1
+Cipher name:   \taes
+Cipher mode:   \txts-plain64
+Hash spec:     \tsha256
+Payload offset:\t4096
+MK bits:       \t512
+MK digest:     \t4c 9d 0e 6a 1f 2b 73 88 a1 55 02 d7 3e 91 c4 0b 27 6f 3a 10 
+MK salt:       \t81 2e 6c 55 09 af 3d 7e 44 c1 92 0f 5b 6d 18 e3 
+               \t7a 30 cc 91 46 2d 8b 0e f5 13 67 a9 2c 58 d4 01 
+MK iterations: \t124593
+UUID:          \t0f2c8b9e-4d61-4a3f-9b7e-2c5d8e1a6f30
+
+Key Slot 0: ENABLED
+\tIterations:         \t2000000
+\tSalt:               \t5d 92 1c 0a 7f 33 e8 46 b1 20 9c 4e 6a d5 71 08 
+\t                      \t2f 84 c3 19 5e a6 0d 7b 93 e1 48 2a 6c f0 35 d9 
+\tKey material offset:\t8
+\tAF stripes:            \t4000
+Key Slot 1: ENABLED
+\tIterations:         \t1000
+\tSalt:               \t1e 70 a4 3b 92 c8 05 6d f1 2a 87 4c e3 19 b6 50 
+\t                      \t0c 5f 98 e2 37 a1 6b 4d 82 f0 29 c7 5e 13 d8 6a 
+\tKey material offset:\t512
+\tAF stripes:            \t4000
+Key Slot 2: DISABLED
+Key Slot 3: DISABLED
+Key Slot 4: DISABLED
+Key Slot 5: DISABLED
+Key Slot 6: DISABLED
+Key Slot 7: DISABLED
+";
+
+    const LUKS2_DUMP: &str = "LUKS header information
+Version:       \t2
+Epoch:         \t5
+Metadata area: \t16384 [bytes]
+Keyslots area: \t16744448 [bytes]
+UUID:          \t7b3e1c52-9a04-4f6d-8e21-5c0b9d7a3f18
+Label:         \t(no label)
+Subsystem:     \t(no subsystem)
+Flags:       \t(no flags)
+
+Data segments:
+  0: crypt
+\toffset: 16777216 [bytes]
+\tlength: (whole device)
+\tcipher: aes-xts-plain64
+\tsector: 512 [bytes]
+
+Keyslots:
+  0: luks2
+\tKey:        512 bits
+\tPriority:   normal
+\tCipher:     aes-xts-plain64
+\tCipher key: 512 bits
+\tPBKDF:      argon2id
+\tTime cost:  4
+\tMemory:     1048576
+\tThreads:    4
+\tSalt:       3a 8f 21 c6 0d 5b e7 94 12 af 6c 38 d1 70 4e 9b 
+\t            85 2c f3 0a 67 1d b9 e4 58 03 ca 7f 26 91 d0 4b 
+\tAF stripes: 4000
+\tAF hash:    sha256
+\tArea offset:32768 [bytes]
+\tArea length:258048 [bytes]
+\tDigest ID:  0
+  1: luks2
+\tKey:        512 bits
+\tPriority:   normal
+\tCipher:     aes-xts-plain64
+\tCipher key: 512 bits
+\tPBKDF:      pbkdf2
+\tHash:       sha256
+\tIterations: 1000
+\tSalt:       e4 19 7b 0c a2 56 3d f8 91 2e 6a c0 47 b5 18 d3 
+\t            09 7c e1 34 5a 8f 26 b0 d7 43 9e 12 6f a8 c5 01 
+\tAF stripes: 4000
+\tAF hash:    sha256
+\tArea offset:290816 [bytes]
+\tArea length:258048 [bytes]
+\tDigest ID:  0
+Tokens:
+  0: systemd-tpm2
+\ttpm2-hash-pcrs:   7
+\ttpm2-pcr-bank:    sha256
+\ttpm2-pubkey:
+\t            (null)
+\ttpm2-pubkey-pcrs: n/a
+\ttpm2-primary-alg: ecc
+\ttpm2-blob:        00 9e 00 20 5d 3a 8c 71 e2 04 b6 19 f0 4d 2a 87
+\ttpm2-policy-hash:
+\t                  c1 5e 08 7a 93 2f d4 66 1b e0 37 a9 5c 82 4f 0d
+\ttpm2-pin:         false
+\ttpm2-pcrlock:     false
+\ttpm2-salt:        false
+\ttpm2-srk:         true
+\tKeyslot:    2
+Digests:
+  0: pbkdf2
+\tHash:       sha256
+\tIterations: 117028
+\tSalt:       6b 20 d9 4e 13 a7 5c f1 88 2d 0e 97 c3 46 b5 7a 
+\t            f2 09 61 3c de 84 17 a0 5b e6 29 c8 70 1f 93 4d 
+\tDigest:     91 7d 3e a0 54 c2 18 6f e9 0b 47 d5 26 8a f3 1c 
+\t            b0 65 2e 9f 03 d8 71 4a c6 1e 85 37 a2 5f 0c e9 
+";
+
+    #[test]
+    fn parse_luks1() {
+        let h = Header::parse(LUKS1_DUMP);
+        assert_eq!(h.version, 1);
+        let slots: Vec<_> = h
+            .keyslots
+            .iter()
+            .map(|s| (s.id, s.pbkdf.as_str(), s.iterations))
+            .collect();
+        assert_eq!(
+            slots,
+            [(0, "pbkdf2", Some(2000000)), (1, "pbkdf2", Some(1000))]
+        );
+        assert!(h.tokens.is_empty());
+    }
+
+    #[test]
+    fn parse_luks2_ignores_digest_iterations() {
+        let h = Header::parse(LUKS2_DUMP);
+        assert_eq!(h.version, 2);
+        let slots: Vec<_> = h
+            .keyslots
+            .iter()
+            .map(|s| (s.id, s.pbkdf.as_str(), s.iterations))
+            .collect();
+        assert_eq!(slots, [(0, "argon2id", None), (1, "pbkdf2", Some(1000))]);
+        assert_eq!(h.tokens, ["systemd-tpm2"]);
+    }
+}
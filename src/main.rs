@@ -1,136 +1,1445 @@
 use std::{
-    fs,
-    os::unix::{self, fs::FileTypeExt},
-    path::PathBuf,
+    fs, io,
+    os::{fd::FromRawFd, unix::fs::FileTypeExt},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
-use boot::EncryptedBoot;
+use boot::{EfiMode, EncryptedBoot};
+use bootloader::Bootloader;
 use clap::{builder::styling, CommandFactory, FromArgMatches, Parser};
-use fs_extra::dir::CopyOptions;
+use defaults::GrubDefaults;
+use efistub::Efistub;
 use grub::Grub;
+use relocate::Health;
+use sdboot::SystemdBoot;
+use secrets::Secrets;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use signer::Signer;
+use state::State;
+use uki::Uki;
 
+mod adopt;
+mod audit;
 mod boot;
+mod bootloader;
 mod command;
+mod copy;
+mod defaults;
+mod efistub;
+mod esp;
+mod events;
+mod fleet;
 mod grub;
+mod health;
+mod hibernate;
+mod i18n;
+mod kernel;
+mod lock;
+mod luks;
+mod mounts;
+mod notify;
+mod offline;
+mod output;
+mod plan;
+mod platform;
+mod pool;
+mod posture;
+mod prompt;
+mod relocate;
+mod sdboot;
+mod secrets;
+mod signer;
+mod state;
+mod sysroot;
+mod systemd;
+mod timing;
+mod topology;
+mod translog;
+mod uki;
 
-#[allow(dead_code)]
+const SBCTL_DIR: &str = "/usr/share/secureboot";
+// environment variable holding boot passphrase
+const PASSPHRASE_ENV: &str = "CRYPTBOOT_PASSPHRASE";
+// defaults used to compute grub friendly PBKDF2 iterations
+const GRUB_PBKDF2_SLOWDOWN: u64 = 10;
+const GRUB_UNLOCK_TARGET: u64 = 10;
+
+/// Block device, configuration accepts paths as well as UUID=, PARTUUID=
+/// and LABEL= specifications resolved through /dev/disk/by-*
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 enum Device {
     Path(PathBuf),
-    PartUuid(Uuid),
+    Uuid(String),
+    PartUuid(String),
+    Label(String),
     Mapper(String),
 }
 
+impl Default for Device {
+    fn default() -> Self {
+        Self::Path(PathBuf::new())
+    }
+}
+
 impl std::fmt::Display for Device {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.full_path().to_string_lossy())
     }
 }
 
+impl std::str::FromStr for Device {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let dev = match s.split_once('=') {
+            Some(("UUID", v)) => Self::Uuid(v.into()),
+            Some(("PARTUUID", v)) => Self::PartUuid(v.to_lowercase()),
+            Some(("LABEL", v)) => Self::Label(v.into()),
+            Some((t, _)) if t.chars().all(|c| c.is_ascii_uppercase()) => {
+                return Err(anyhow!("unsupported device specification: {s}"))
+            }
+            _ => Self::Path(s.into()),
+        };
+
+        match &dev {
+            Self::Uuid(v) | Self::PartUuid(v) | Self::Label(v) if v.is_empty() => {
+                Err(anyhow!("empty device specification: {s}"))
+            }
+            _ => Ok(dev),
+        }
+    }
+}
+
+impl TryFrom<String> for Device {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Device> for String {
+    fn from(value: Device) -> Self {
+        match value {
+            Device::Uuid(v) => format!("UUID={v}"),
+            Device::PartUuid(v) => format!("PARTUUID={v}"),
+            Device::Label(v) => format!("LABEL={v}"),
+            d => d.full_path().to_string_lossy().to_string(),
+        }
+    }
+}
+
+// udev escapes characters of /dev/disk/by-label links as \xNN
+fn udev_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"#+-.:=@_".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("\\x{b:02x}"));
+        }
+    }
+    out
+}
+
 impl Device {
     fn full_path(&self) -> PathBuf {
         match self {
             Self::Path(p) => p.clone(),
-            Self::PartUuid(u) => {
-                PathBuf::from("/dev/disk/by-partuuid").join(u.hyphenated().to_string())
+            Self::Uuid(u) => PathBuf::from("/dev/disk/by-uuid").join(u),
+            Self::PartUuid(u) => PathBuf::from("/dev/disk/by-partuuid").join(u),
+            Self::Label(l) => PathBuf::from("/dev/disk/by-label").join(udev_escape(l)),
+            Self::Mapper(s) => PathBuf::from("/dev/mapper").join(s),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match fs::metadata(self.full_path()) {
+            Ok(m) => m.file_type().is_block_device(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Config {
+    boot: boot::Config,
+    #[serde(default)]
+    bootloader: bootloader::Kind,
+    #[serde(default)]
+    grub: grub::Config,
+    #[serde(default)]
+    sdboot: sdboot::Config,
+    #[serde(default)]
+    efistub: efistub::Config,
+    #[serde(default)]
+    uki: uki::Config,
+    #[serde(default, alias = "signing", alias = "sign")]
+    signer: signer::Config,
+    #[serde(default)]
+    health: health::Config,
+    #[serde(default)]
+    notify: notify::Config,
+    #[serde(default)]
+    transparency: translog::Config,
+    #[serde(default)]
+    check: boot::Checks,
+}
+
+impl Config {
+    /// Replaces `secret:<name>` references of every secret capable setting
+    /// with secrets stored in boot, which must be mounted
+    fn resolve_secrets(&mut self) -> anyhow::Result<()> {
+        let secrets = Secrets::load(&self.boot)?;
+        secrets.resolve_opt(&mut self.grub.password_pbkdf2)?;
+        secrets.resolve_opt(&mut self.grub.gpg_key)?;
+        secrets.resolve_opt(&mut self.signer.pkcs11_uri)?;
+        secrets.resolve_path(&mut self.signer.key)?;
+        secrets.resolve_path(&mut self.signer.cert)?;
+        Ok(())
+    }
+
+    /// Rejects secret references in settings used before boot is unlocked,
+    /// they could never be resolved
+    fn check_secrets(&self) -> anyhow::Result<()> {
+        if let Some(keyfile) = self
+            .boot
+            .keyfile
+            .as_ref()
+            .filter(|k| Secrets::is_reference(k))
+        {
+            return Err(anyhow!(
+                "boot keyfile unlocks boot, it cannot reference a secret stored in boot: {}",
+                keyfile.to_string_lossy()
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct Cryptboot {
+    config: Config,
+    // where configuration was read from
+    config_path: PathBuf,
+    // bypass safety checks
+    force: bool,
+}
+
+impl Cryptboot {
+    fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            config_path: PathBuf::new(),
+            force: false,
+        }
+    }
+
+    fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    fn config_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    fn mount(&self) -> anyhow::Result<EncryptedBoot> {
+        self.mount_with(EfiMode::ReadWrite)
+    }
+
+    /// Opens boot mapper only, leaving mounts to someone else (i.e. systemd)
+    fn unlock(&self) -> anyhow::Result<()> {
+        let m = EncryptedBoot::from_config(self.config.boot.clone()).force(self.force);
+        if m.mapper().is_valid() {
+            return Ok(());
+        }
+        m.unlock()
+    }
+
+    fn mount_with(&self, efi_mode: EfiMode) -> anyhow::Result<EncryptedBoot> {
+        let txn = State::load()?.transaction.is_some();
+        let mut m = EncryptedBoot::from_config(self.config.boot.clone())
+            .efi_mode(efi_mode)
+            .force(self.force)
+            .fsck(self.config.check.fsck)
+            .keep_mounted(txn);
+
+        // commands run within a transaction share its mount
+        if txn && m.is_mounted()? {
+            return Ok(m);
+        }
+
+        // boot mounted by someone else (i.e. during a package upgrade) is
+        // reused and left mounted rather than pulled out from under them
+        if m.is_mounted_from_config()? {
+            return Ok(m.keep_mounted(true));
+        }
+
+        m.reset();
+        m.mount()?;
+        self.check_relocations();
+        self.check_signing_keys();
+
+        if let Some(secs) = self.config.boot.max_exposure {
+            command::set_deadline(Instant::now() + Duration::from_secs(secs));
+        }
+        Ok(m)
+    }
+
+    /// Relocations recorded in state, only those are checked and repaired
+    fn relocations(&self) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+        let mut relocations: Vec<_> = State::load()?
+            .relocations
+            .into_iter()
+            .map(|r| (sysroot::path(r.src), sysroot::path(r.dst)))
+            .collect();
+
+        // sbctl hardened before relocations were recorded, it is only taken
+        // over when it is still a symlink to boot
+        let (sbctl, dst) = (sysroot::path(SBCTL_DIR), self.sbctl_dst());
+        if relocate::is_relocated(&sbctl, &dst)
+            && !relocations.contains(&(sbctl.clone(), dst.clone()))
+        {
+            State::update(|s| s.add_relocation(&sysroot::strip(&sbctl), &sysroot::strip(&dst)))?;
+            relocations.push((sbctl, dst));
+        }
+        Ok(relocations)
+    }
+
+    /// Warns about broken relocations, boot must be mounted. Directories
+    /// recreated by package updates are merged back automatically.
+    fn check_relocations(&self) {
+        let relocations = match self.relocations() {
+            Ok(r) => r,
+            Err(e) => {
+                events::warn(format!("failed to check relocations: {e}"));
+                return;
+            }
+        };
+
+        for (src, dst) in relocations {
+            let health = relocate::health(&src, &dst);
+            if health == Health::Replaced {
+                eprintln!(
+                    "{} was recreated, merging it into {}",
+                    src.to_string_lossy(),
+                    dst.to_string_lossy()
+                );
+                if command::dry_run() {
+                    continue;
+                }
+                if let Err(e) = relocate::merge(&src, &dst) {
+                    events::warn(format!("failed to merge {}: {e}", src.to_string_lossy()));
+                }
+            } else if !matches!(health, Health::Ok | Health::NotRelocated) {
+                events::warn(format!(
+                    "{} relocation is broken ({health}), run mount --repair to fix it",
+                    src.to_string_lossy()
+                ));
+            }
+        }
+    }
+
+    fn repair_relocations(&self) -> anyhow::Result<()> {
+        for (src, dst) in self.relocations()? {
+            if command::dry_run() {
+                command::dry_run_note(format!("repair {}", src.to_string_lossy()));
+                continue;
+            }
+            relocate::repair(&src, &dst)?;
+        }
+        Ok(())
+    }
+
+    fn sbctl_dst(&self) -> PathBuf {
+        self.config.boot.mountpoint.join("secureboot")
+    }
+
+    /// Path where a protected directory is relocated on encrypted boot
+    fn protected_dst(&self, dir: &Path) -> anyhow::Result<PathBuf> {
+        let rel = dir
+            .strip_prefix("/")
+            .map_err(|_| anyhow!("not an absolute path: {}", dir.to_string_lossy()))?;
+        Ok(self
+            .config
+            .boot
+            .mountpoint
+            .join("cryptboot")
+            .join("protected")
+            .join(rel))
+    }
+
+    fn relocate(&self, dirs: &[(PathBuf, PathBuf)], undo: bool) -> anyhow::Result<()> {
+        let m = self.mount()?.umount_on_drop();
+
+        for (src, dst) in dirs {
+            if command::dry_run() {
+                let (from, to) = if undo { (dst, src) } else { (src, dst) };
+                command::dry_run_note(format!(
+                    "move {} to {}",
+                    from.to_string_lossy(),
+                    to.to_string_lossy()
+                ));
+                continue;
+            }
+            if undo {
+                relocate::restore(src, dst)?;
+                State::update(|s| s.remove_relocation(&sysroot::strip(src), &sysroot::strip(dst)))?;
+            } else {
+                relocate::relocate(src, dst)?;
+                State::update(|s| s.add_relocation(&sysroot::strip(src), &sysroot::strip(dst)))?;
+            }
+        }
+
+        drop(m);
+        Ok(())
+    }
+
+    fn harden_sbctl(&self, o: HardenSbctlOptions) -> anyhow::Result<()> {
+        self.relocate(&[(sysroot::path(SBCTL_DIR), self.sbctl_dst())], o.undo)
+    }
+
+    fn protect(&self, o: ProtectOptions) -> anyhow::Result<()> {
+        let dirs = o
+            .dirs
+            .iter()
+            .map(|d| Ok((sysroot::path(d), self.protected_dst(d)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.relocate(&dirs, o.undo)
+    }
+
+    fn umount(&self) -> anyhow::Result<()> {
+        let m = EncryptedBoot::from_config(self.config.boot.clone());
+        m.umount()?;
+
+        // unmounting aborts the transaction in progress
+        if let Some(txn) = State::load()?.transaction {
+            if txn.sign_pending {
+                events::warn("transaction aborted, files were not signed");
+            }
+            State::update(|s| s.transaction = None)?;
+        }
+        Ok(())
+    }
+
+    /// Configuration with secret references resolved, boot must be mounted
+    fn resolved_config(&self) -> anyhow::Result<Config> {
+        let mut config = self.config.clone();
+        config.resolve_secrets()?;
+        Ok(config)
+    }
+
+    /// Grub with secrets resolved, boot must be mounted
+    fn grub(&self) -> anyhow::Result<Grub> {
+        Ok(Grub::from_config(self.resolved_config()?.grub))
+    }
+
+    /// Configured bootloader, boot must be mounted as secrets may be needed
+    fn bootloader(&self) -> anyhow::Result<Box<dyn Bootloader>> {
+        match self.config.bootloader {
+            bootloader::Kind::Grub => Ok(Box::new(self.grub()?)),
+            bootloader::Kind::SystemdBoot => Ok(Box::new(SystemdBoot::from_config(
+                self.config.sdboot.clone(),
+            ))),
+            bootloader::Kind::Efistub => {
+                Ok(Box::new(Efistub::from_config(self.config.efistub.clone())))
+            }
+        }
+    }
+
+    fn systemd_units(&self, o: SystemdUnitsOptions) -> anyhow::Result<()> {
+        // units see mountpoints from within sysroot
+        let mut boot = self.config.boot.clone();
+        boot.mountpoint = sysroot::strip(&boot.mountpoint);
+        boot.efi.mountpoint = sysroot::strip(&boot.efi.mountpoint);
+
+        let cryptboot = std::env::current_exe()?;
+        let units = systemd::automount_units(&boot, &cryptboot, o.idle);
+        let names = systemd::install(&sysroot::path(&o.dir), &units)?;
+        command::systemctl(&["daemon-reload"])?;
+        if !output::json() {
+            println!("installed {}", names.join(" "));
+            println!(
+                "enable with: systemctl enable --now {} {}",
+                systemd::unit_name(&boot.mountpoint, "automount"),
+                systemd::unit_name(&boot.efi.mountpoint, "automount")
+            );
+        }
+        Ok(())
+    }
+
+    fn grub_install(&self, o: GrubInstallOptions) -> anyhow::Result<()> {
+        let m = self.mount()?.umount_on_drop();
+
+        let bootloader = self.bootloader()?;
+        // update bootloader configuration
+        timing::time("mkconfig", || bootloader.mkconfig(&self.config.boot))?;
+        // install bootloader
+        timing::time("install", || bootloader.install(&self.config.boot))?;
+        // the new boot chain needs to be marked as good after reboot
+        bootloader.arm_boot_counter(&self.config.boot)?;
+
+        let version = bootloader.version()?;
+        State::update(|s| {
+            for f in bootloader.managed_files(&self.config.boot) {
+                s.add_managed_file(f);
+            }
+            s.bootloader = Some(state::Bootloader {
+                version,
+                installed_at: state::now(),
+            });
+            s.boot_chain = state::BootChain::Pending;
+        })?;
+
+        // we sign all files, which records the EFI partition content
+        if !o.no_sign {
+            timing::time("sign", || self.sign_or_defer())?;
+        } else {
+            esp::Manifest::update(&self.config.boot)?;
+        }
+
+        drop(m);
+        if !output::json() {
+            print!("{}", timing::summary());
+        }
+        Ok(())
+    }
+
+    // files cryptboot installs on EFI partition which must be signed besides
+    // those known to the signer
+    fn signed_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut files = self.bootloader()?.signed_files(&self.config.boot);
+        let uki = Uki::from_config(self.config.uki.clone()).output(&self.config.boot);
+        if uki.is_file() {
+            files.push(uki);
+        }
+        Ok(files)
+    }
+
+    fn sign_all(&self) -> anyhow::Result<()> {
+        if self.config.bootloader == bootloader::Kind::Grub && self.config.grub.signed_shim {
+            // grub and shim are signed by the distribution
+            self.grub()?.sign_kernels_mok(&self.config.boot)?;
+        } else {
+            self.signer()?
+                .sign_all(&self.config.boot, &self.signed_files()?)?;
+        }
+        // files grub loads from boot, complementing secure boot
+        if self.config.bootloader == bootloader::Kind::Grub {
+            let grub = self.grub()?;
+            timing::time("gpg-sign", || grub.gpg_sign(&self.config.boot))?;
+        }
+        let keys = self.signer()?.fingerprint().unwrap_or_default();
+        let kernels = kernel::signed_versions(&self.config.boot)?;
+        self.config.transparency.record(&self.config.boot)?;
+        esp::Manifest::update(&self.config.boot)?;
+        State::update(|s| {
+            s.last_sign = Some(state::now());
+            s.signing_keys = keys;
+            s.stale_signatures = false;
+            s.signed_kernels = kernels;
+            s.invalidate_probes("sign:");
+        })
+    }
+
+    /// Whether signing keys changed since the last signature, boot must be
+    /// mounted for keys to be readable
+    fn signing_keys_changed(&self) -> bool {
+        let last = match State::load() {
+            Ok(s) => s.signing_keys,
+            Err(e) => {
+                events::warn(format!("failed to load state: {e}"));
+                return false;
+            }
+        };
+        let Some(last) = last else {
+            return false;
+        };
+        match self.signer().and_then(|s| s.fingerprint()) {
+            Ok(Some(current)) => current != last,
+            Ok(None) => false,
+            Err(e) => {
+                events::warn(format!("failed to identify signing keys: {e}"));
+                false
+            }
+        }
+    }
+
+    /// Reports signing keys changed since the last signature, files are only
+    /// signed again by operations which sign with EFI mounted read-write
+    fn check_signing_keys(&self) {
+        if !self.signing_keys_changed() {
+            return;
+        }
+        events::warn(
+            "signing keys changed since last signature, run grub-install or run --sign-all to sign files again",
+        );
+        if let Err(e) = State::update(|s| s.stale_signatures = true) {
+            events::warn(format!("failed to record stale signatures: {e}"));
+        }
+    }
+
+    /// Configured signing backend, boot must be mounted as keys may live there
+    fn signer(&self) -> anyhow::Result<Box<dyn Signer>> {
+        self.resolved_config()?.signer.signer(&self.config.boot)
+    }
+
+    /// Signs now or at the end of the transaction in progress
+    fn sign_or_defer(&self) -> anyhow::Result<()> {
+        let mut deferred = false;
+        State::update(|s| {
+            if let Some(t) = s.transaction.as_mut() {
+                t.sign_pending = true;
+                deferred = true;
+            }
+        })?;
+
+        if !deferred {
+            self.sign_all()?;
+        }
+        Ok(())
+    }
+
+    fn txn_begin(&self) -> anyhow::Result<()> {
+        State::update(|s| {
+            s.transaction.get_or_insert(state::Transaction {
+                started_at: state::now(),
+                sign_pending: false,
+            });
+        })?;
+
+        if let Err(e) = self.mount() {
+            State::update(|s| s.transaction = None)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn txn_end(&self) -> anyhow::Result<()> {
+        let Some(txn) = State::load()?.transaction else {
+            return Err(anyhow!("no transaction in progress"));
+        };
+
+        // boot is unmounted once transaction is over
+        let m = self.mount()?.keep_mounted(false).umount_on_drop();
+        // transaction is closed first so that a signing failure does not
+        // leave boot mounted by later commands
+        State::update(|s| s.transaction = None)?;
+        if txn.sign_pending {
+            timing::time("sign", || self.sign_all())?;
+        }
+
+        drop(m);
+        Ok(())
+    }
+
+    fn uki_build(&self, o: UkiBuildOptions) -> anyhow::Result<()> {
+        let m = self.mount()?.umount_on_drop();
+
+        let uki = timing::time("build", || {
+            Uki::from_config(self.config.uki.clone()).build(&self.config.boot)
+        })?;
+        State::update(|s| s.add_managed_file(&uki))?;
+        if !output::json() {
+            println!("unified kernel image written to {}", uki.to_string_lossy());
+        }
+
+        // we sign all files, which records the EFI partition content
+        if !o.no_sign {
+            timing::time("sign", || self.sign_or_defer())?;
+        } else {
+            esp::Manifest::update(&self.config.boot)?;
+        }
+
+        drop(m);
+        Ok(())
+    }
+
+    /// Loads the latest kernel with kexec so that the new boot payload is tested
+    /// without going through firmware
+    fn kexec_test(&self, o: KexecTestOptions) -> anyhow::Result<()> {
+        if posture::kexec_disabled() {
+            return Err(anyhow!(
+                "kexec is disabled until reboot (kernel.kexec_load_disabled)"
+            ));
+        }
+        // lockdown only lets kexec_file_load boot signed kernels
+        let lockdown = posture::lockdown().unwrap_or("none".into());
+        let locked = lockdown != "none";
+
+        let m = self.mount_with(EfiMode::ReadOnly)?.umount_on_drop();
+
+        let kernel = match o.kernel {
+            Some(k) => self.config.boot.mountpoint.join(k),
+            None => kernel::latest(&self.config.boot)?
+                .ok_or(anyhow!("no kernel image found on boot"))?,
+        };
+        let initrd = kernel::initrd(&kernel);
+        let cmdline = match o.cmdline {
+            Some(c) => c,
+            None => kernel::running_cmdline()?,
+        };
+
+        let signed = kernel::is_signed(&fs::read(&kernel)?);
+        if locked && !signed {
+            return Err(anyhow!(
+                "lockdown is {lockdown} and {} is not signed, kexec would refuse it",
+                kernel.to_string_lossy()
+            ));
+        }
+
+        timing::time("kexec", || {
+            command::kexec_load(&kernel, initrd.as_ref(), &cmdline, locked)
+        })?;
+        // kexec holds the payload in memory, boot is not needed anymore
+        drop(m);
+
+        if !output::json() {
+            println!(
+                "loaded {} (lockdown: {lockdown}, signed: {signed})",
+                kernel.to_string_lossy()
+            );
+        }
+
+        match o.exec {
+            true => command::systemctl(&["kexec"]),
+            false => {
+                if !output::json() {
+                    println!("run systemctl kexec to boot it");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn enroll_tpm2(&self, o: EnrollTpm2Options) -> anyhow::Result<()> {
+        if self.config.boot.container_type != boot::ContainerType::Luks {
+            return Err(anyhow!("TPM2 enrollment requires a LUKS boot device"));
+        }
+        let pcrs = o
+            .pcrs
+            .or(self.config.boot.tpm2_pcrs.clone())
+            .unwrap_or(boot::DEFAULT_TPM2_PCRS.into());
+
+        luks::enroll_tpm2(&self.config.boot.device, &pcrs)?;
+        State::update(|s| s.invalidate_probes("cryptsetup:tokens:"))
+    }
+
+    /// Generates a key file unlocking boot, stored as boot.keyfile_seal says
+    fn enroll_keyfile(&self) -> anyhow::Result<()> {
+        if self.config.boot.container_type != boot::ContainerType::Luks {
+            return Err(anyhow!("key file enrollment requires a LUKS boot device"));
+        }
+        let Some(keyfile) = &self.config.boot.keyfile else {
+            return Err(anyhow!("boot.keyfile must be set to enroll a key file"));
+        };
+        let keyfile = sysroot::path(keyfile);
+        if keyfile.exists() {
+            return Err(anyhow!(
+                "key file {} already exists",
+                keyfile.to_string_lossy()
+            ));
+        }
+
+        let key = boot::generate_key()?;
+        // cryptsetup reads new keys from files, /run is memory backed
+        let tmp = PathBuf::from(format!("/run/cryptboot-key-{}", std::process::id()));
+        boot::write_keyfile(&tmp, &key)?;
+        let res = luks::add_key(&self.config.boot.device, &tmp);
+        if !command::dry_run() {
+            fs::remove_file(&tmp)?;
+        }
+        res?;
+
+        boot::store_keyfile(&keyfile, &key, self.config.boot.keyfile_seal)
+    }
+
+    fn enroll_fido2(&self) -> anyhow::Result<()> {
+        if self.config.boot.container_type != boot::ContainerType::Luks {
+            return Err(anyhow!("FIDO2 enrollment requires a LUKS boot device"));
+        }
+        luks::enroll_fido2(&self.config.boot.device)?;
+        State::update(|s| s.invalidate_probes("cryptsetup:tokens:"))
+    }
+
+    /// Secure boot keys are managed by sbctl whichever signer is configured
+    fn keys_dir(&self) -> PathBuf {
+        sysroot::path(SBCTL_DIR).join("keys")
+    }
+
+    fn keys(&self, c: KeysCommand) -> anyhow::Result<()> {
+        // keys live on encrypted boot once sbctl is hardened
+        let m = self.mount()?.umount_on_drop();
+
+        match c {
+            KeysCommand::Create => {
+                if self.keys_dir().exists() {
+                    return Err(anyhow!(
+                        "keys already exist in {}",
+                        self.keys_dir().to_string_lossy()
+                    ));
+                }
+                command::sbctl(&["create-keys"])?;
+            }
+            KeysCommand::Enroll(o) => {
+                let mut args = vec!["enroll-keys"];
+                if o.microsoft {
+                    args.push("--microsoft");
+                }
+                command::sbctl(&args)?;
+            }
+            KeysCommand::Export(o) => {
+                // only public certificates leave encrypted boot
+                for k in ["PK", "KEK", "db"] {
+                    let cert = self.keys_dir().join(k).join(format!("{k}.pem"));
+                    let dst = o.dir.join(format!("{k}.pem"));
+                    if command::dry_run() {
+                        command::dry_run_note(format!(
+                            "copy {} to {}",
+                            cert.to_string_lossy(),
+                            dst.to_string_lossy()
+                        ));
+                        continue;
+                    }
+                    fs::create_dir_all(&o.dir)?;
+                    fs::copy(&cert, &dst)
+                        .map_err(|e| anyhow!("failed to export {}: {e}", cert.to_string_lossy()))?;
+                }
+            }
+        }
+
+        drop(m);
+        Ok(())
+    }
+
+    /// Signing on an offline machine holding secure boot keys
+    fn sign(&self, c: SignCommand) -> anyhow::Result<()> {
+        let m = self.mount()?.umount_on_drop();
+
+        match c {
+            SignCommand::ExportRequests(o) => {
+                let manifest = offline::export(&self.config.boot, &o.out)?;
+                if !output::json() {
+                    println!(
+                        "{} files to sign exported to {}",
+                        manifest.requests.len(),
+                        o.out.to_string_lossy()
+                    );
+                }
+            }
+            SignCommand::Import(o) => {
+                // signed files are only installed once checked against it
+                let Some(cert) = self.resolved_config()?.signer.cert else {
+                    return Err(anyhow!("sign import requires signer cert"));
+                };
+                let cert = self.config.boot.mountpoint.join(cert);
+                let n = offline::import(&self.config.boot, &o.bundle, &cert)?;
+                self.config.transparency.record(&self.config.boot)?;
+                esp::Manifest::update(&self.config.boot)?;
+                State::update(|s| {
+                    s.last_sign = Some(state::now());
+                    s.invalidate_probes("sign:");
+                })?;
+                if !output::json() {
+                    println!("{n} signed files installed");
+                }
+            }
+        }
+
+        drop(m);
+        Ok(())
+    }
+
+    /// Rebuilds grub image from recorded inputs, failing when it differs
+    fn reproduce_check(&self) -> anyhow::Result<health::Report> {
+        let mut r = health::Report::default();
+        let Some(recorded) = State::load()?.grub_build else {
+            return Err(anyhow!("no grub build recorded, run grub-install first"));
+        };
+
+        let m = self.mount_with(EfiMode::Skip)?.umount_on_drop();
+        let current = self.grub()?.build_inputs(&self.config.boot)?;
+        drop(m);
+
+        if current.version != recorded.version {
+            r.warn(
+                "version",
+                format!(
+                    "grub changed from {} to {} since install",
+                    recorded.version, current.version
+                ),
+            );
+        } else {
+            r.ok("version", current.version.clone());
+        }
+
+        for (name, recorded, current) in [
+            (
+                "early-config",
+                &recorded.early_config,
+                &current.early_config,
+            ),
+            ("memdisk", &recorded.memdisk, &current.memdisk),
+        ] {
+            match recorded == current {
+                true => r.ok(name, "unchanged"),
+                false => r.warn(name, "changed since install"),
+            }
+        }
+
+        match (recorded.modules == current.modules, recorded.image == current.image) {
+            (_, true) => r.ok("image", format!("reproduced {}", current.image)),
+            (false, false) => r.warn("image", "modules changed since install"),
+            // same inputs must give the same image
+            (true, false) if recorded.version == current.version => r.fail(
+                "image",
+                format!(
+                    "rebuilt image {} differs from recorded {}, toolchain is not reproducible or was tampered with",
+                    current.image, recorded.image
+                ),
+            ),
+            (true, false) => r.warn("image", "image differs, grub was updated since install"),
+        }
+        Ok(r)
+    }
+
+    fn mark_good(&self) -> anyhow::Result<()> {
+        let m = self.mount()?.umount_on_drop();
+
+        self.bootloader()?.mark_good(&self.config.boot)?;
+        State::update(|s| s.boot_chain = state::BootChain::Good)?;
+
+        drop(m);
+        Ok(())
+    }
+
+    fn run(&self, o: RunOptions) -> anyhow::Result<()> {
+        let m = self.mount()?.umount_on_drop();
+
+        if !o.command_line.is_empty() {
+            let program = &o.command_line[0];
+            let mut cmd = command::command(program);
+            if o.command_line.len() > 1 {
+                cmd.args(&o.command_line[1..]);
+            }
+            let status = timing::time("command", || command::status(&mut cmd))?;
+            // the command may have changed files to sign
+            State::update(|s| s.invalidate_probes("sign:"))?;
+            if !status.success() {
+                return Err(anyhow!("failed to run {program}: {status}"));
+            }
+        }
+
+        // files signed with former keys would not boot anymore
+        if o.sign_all || self.signing_keys_changed() {
+            timing::time("sign", || self.sign_or_defer())?;
+        }
+
+        drop(m);
+        if !output::json() {
+            print!("{}", timing::summary());
+        }
+        Ok(())
+    }
+
+    fn bench_unlock(&self, o: BenchUnlockOptions) -> anyhow::Result<()> {
+        let dev = self.config.boot.device.clone();
+        let header = luks::dump(&dev)?;
+        let rate = luks::benchmark_pbkdf2(&o.hash)?;
+        let grub = Grub::from_config(self.config.grub.clone());
+
+        // grub has no optimized crypto so unlocking is much slower than with cryptsetup
+        let grub_rate = (rate / o.grub_slowdown).max(1);
+        // cryptsetup refuses less than 1000 iterations
+        let suggested = (grub_rate * o.target).max(1000);
+
+        println!("cryptsetup PBKDF2-{}: {rate} iterations per second", o.hash);
+        for slot in header.keyslots.iter() {
+            match slot.iterations {
+                Some(i) if slot.pbkdf == "pbkdf2" => println!(
+                    "keyslot {}: {i} iterations, ~{}s to unlock in grub",
+                    slot.id,
+                    i / grub_rate
+                ),
+                _ if grub.supports_pbkdf(&slot.pbkdf) => println!(
+                    "keyslot {}: {} unlock time in grub cannot be estimated",
+                    slot.id, slot.pbkdf
+                ),
+                _ => println!(
+                    "keyslot {}: {} is not supported by grub",
+                    slot.id, slot.pbkdf
+                ),
+            }
+        }
+        println!(
+            "suggested: {suggested} iterations for ~{}s unlock in grub",
+            o.target
+        );
+
+        if o.apply
+            && prompt::confirm(&i18n::tr(
+                "change-keyslot",
+                &[("hash", &o.hash), ("iterations", &suggested.to_string())],
+            ))?
+        {
+            luks::set_pbkdf2_iterations(&dev, &header, None, &o.hash, suggested)?;
+        }
+
+        Ok(())
+    }
+
+    fn fix_luks_for_grub(&self, o: FixLuksForGrubOptions) -> anyhow::Result<()> {
+        let dev = self.config.boot.device.clone();
+        let header = luks::dump(&dev)?;
+        let grub = Grub::from_config(self.config.grub.clone());
+
+        let slots: Vec<&luks::Keyslot> = header
+            .keyslots
+            .iter()
+            .filter(|s| !grub.supports_pbkdf(&s.pbkdf))
+            .collect();
+        let convert = header.version == 2 && !grub.supports_luks2();
+
+        println!("{}", grub.cryptodisk_support());
+
+        if slots.is_empty() && !convert {
+            println!("{}", i18n::tr("luks-compatible", &[]));
+            return Ok(());
+        }
+
+        for s in slots.iter() {
+            println!(
+                "{}",
+                i18n::tr(
+                    "keyslot-convert",
+                    &[("id", &s.id.to_string()), ("pbkdf", &s.pbkdf)]
+                )
+            );
+        }
+        if convert {
+            println!("{}", i18n::tr("luks1-convert", &[]));
+        }
+
+        if !prompt::confirm(&i18n::tr("proceed", &[]))? {
+            return Ok(());
+        }
+
+        // header backup is mandatory before touching keyslots
+        let backup = o.header_backup.unwrap_or(PathBuf::from(format!(
+            "/root/cryptboot-header-{}.img",
+            state::now()
+        )));
+        luks::header_backup(&dev, &backup)?;
+        println!(
+            "{}",
+            i18n::tr("header-saved", &[("path", &backup.to_string_lossy())])
+        );
+
+        let iterations =
+            (luks::benchmark_pbkdf2("sha256")? / GRUB_PBKDF2_SLOWDOWN).max(1) * GRUB_UNLOCK_TARGET;
+        for s in slots {
+            luks::set_pbkdf2_iterations(&dev, &header, Some(s.id), "sha256", iterations)?;
+        }
+
+        if convert {
+            // conversion requires the device to be closed
+            EncryptedBoot::from_config(self.config.boot.clone()).reset();
+            luks::convert_luks1(&dev)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs checks which do not need to decrypt boot
+    fn healthcheck(&self, remind: bool) -> health::Report {
+        let mut r = health::Report::default();
+
+        let state = match State::load() {
+            Ok(s) => {
+                r.ok("state", "state file is valid");
+                s
+            }
+            Err(e) => {
+                r.fail("state", e.to_string());
+                return r;
+            }
+        };
+
+        match (&state.bootloader, state.last_sign) {
+            (Some(b), Some(sign)) if sign < b.installed_at => {
+                r.fail("signatures", "bootloader installed after last signature")
+            }
+            (Some(_), None) => r.fail("signatures", "bootloader installed but never signed"),
+            _ => r.ok("signatures", "signatures are up to date"),
+        }
+
+        // someone may have booted a kernel cryptboot never signed
+        if !state.signed_kernels.is_empty() {
+            let signed = |running| {
+                state
+                    .signed_kernels
+                    .iter()
+                    .any(|k| kernel::is_running(k, running))
+            };
+            match kernel::running() {
+                Ok(running) if signed(&running) => {
+                    r.ok("running-kernel", format!("{} is signed", running.0))
+                }
+                Ok(running) => r.fail(
+                    "running-kernel",
+                    format!("{} does not match any signed kernel image", running.0),
+                ),
+                Err(e) => r.fail("running-kernel", e.to_string()),
+            }
+        }
+
+        if state.stale_signatures {
+            r.warn(
+                "signing-keys",
+                "signing keys changed since last signature, files are signed with former keys",
+            );
+        }
+
+        match state.boot_chain {
+            state::BootChain::Pending => r.warn("boot-chain", "last install not marked as good"),
+            _ => r.ok(
+                "boot-chain",
+                format!("{:?}", state.boot_chain).to_lowercase(),
+            ),
+        }
+
+        match (
+            state.mounted_at,
+            EncryptedBoot::from_config(self.config.boot.clone()).is_partially_mounted(),
+        ) {
+            (_, Err(e)) => r.fail("exposure", e.to_string()),
+            (Some(at), Ok(true)) => {
+                let secs = state::now().saturating_sub(at);
+                // healthcheck runs periodically so users are reminded until they unmount
+                if remind
+                    && self.config.notify.desktop
+                    && secs >= self.config.notify.exposure_threshold
+                {
+                    notify::desktop(
+                        "Boot partition left decrypted",
+                        &format!(
+                            "boot has been decrypted for {secs}s, run cryptboot umount once done"
+                        ),
+                    );
+                }
+                r.warn("exposure", format!("boot decrypted for {secs}s"))
+            }
+            (None, Ok(true)) => r.warn("exposure", "boot decrypted"),
+            (_, Ok(false)) => r.ok("exposure", "boot is not decrypted"),
+        }
+
+        match &state.last_umount {
+            Some(c) if !c.leftovers.is_empty() => r.fail(
+                "umount",
+                format!(
+                    "boot left exposed by last unmount: {}",
+                    c.leftovers.join(", ")
+                ),
+            ),
+            Some(_) => r.ok("umount", "last unmount verified"),
+            None => {}
+        }
+
+        let min_size = self.config.health.retained_kernels * self.config.health.size_per_kernel;
+        match command::blockdev_size(&self.config.boot.device.clone()) {
+            Ok(size) if size < min_size << 20 => r.warn(
+                "boot-size",
+                format!(
+                    "boot partition is {}MiB, {min_size}MiB needed for {} kernels",
+                    size >> 20,
+                    self.config.health.retained_kernels
+                ),
+            ),
+            Ok(size) => r.ok("boot-size", format!("boot partition is {}MiB", size >> 20)),
+            Err(e) => r.fail("boot-size", e.to_string()),
+        }
+
+        match topology::share_disk(
+            self.config.boot.device.full_path(),
+            self.config.boot.efi.device.full_path(),
+        ) {
+            Ok(true) => r.ok("topology", "boot and efi are on the same disk"),
+            Ok(false) => r.warn(
+                "topology",
+                "boot and efi are on different disks, both are needed to boot",
+            ),
+            Err(e) => r.fail("topology", e.to_string()),
+        }
+
+        hibernate::check(&mut r);
+
+        // filesystem can only be inspected when boot is unlocked
+        let mapper = EncryptedBoot::from_config(self.config.boot.clone()).mapper();
+        if mapper.is_valid() {
+            let fstype = command::blkid_tag(&mapper, "TYPE").unwrap_or_default();
+            if matches!(fstype.as_deref(), Some("ext2" | "ext3" | "ext4")) {
+                match command::ext_reserved_blocks(&mapper) {
+                    Ok(0) => r.ok("reserved-blocks", "no reserved blocks"),
+                    Ok(n) => r.warn(
+                        "reserved-blocks",
+                        format!("{n} blocks reserved, disable them with tune2fs -m 0 {mapper}"),
+                    ),
+                    Err(e) => r.fail("reserved-blocks", e.to_string()),
+                }
             }
-            Self::Mapper(s) => PathBuf::from("/dev/mapper").join(s),
         }
-    }
 
-    fn is_valid(&self) -> bool {
-        match fs::metadata(self.full_path()) {
-            Ok(m) => m.file_type().is_block_device(),
-            _ => false,
+        if let Some(backup) = &self.config.boot.header_backup {
+            let max_age = Duration::from_secs(self.config.health.max_header_backup_age * 86400);
+            match fs::metadata(backup).and_then(|m| m.modified()) {
+                Ok(t) if t.elapsed().unwrap_or_default() > max_age => r.warn(
+                    "header-backup",
+                    format!(
+                        "LUKS header backup older than {} days",
+                        self.config.health.max_header_backup_age
+                    ),
+                ),
+                Ok(_) => r.ok("header-backup", "LUKS header backup is recent"),
+                Err(e) => r.fail(
+                    "header-backup",
+                    format!("{}: {e}", backup.to_string_lossy()),
+                ),
+            }
         }
-    }
-}
 
-#[derive(Default, Serialize, Deserialize)]
-struct Config {
-    boot: boot::Config,
-    grub: grub::Config,
-}
+        if let Some(log) = &self.config.transparency.path {
+            match translog::verify(sysroot::path(log)) {
+                Ok(n) => r.ok("transparency-log", format!("{n} entries, chain is valid")),
+                Err(e) => r.fail("transparency-log", e.to_string()),
+            }
+        }
 
-struct Cryptboot(Config);
+        if let Some(keyfile) = &self.config.boot.keyfile {
+            match boot::check_keyfile(sysroot::path(keyfile)) {
+                Ok(()) if self.config.boot.keyfile_seal == boot::Seal::None => {
+                    r.warn("keyfile", self.config.boot.keyfile_seal.threat_model())
+                }
+                Ok(()) => r.ok("keyfile", self.config.boot.keyfile_seal.threat_model()),
+                Err(e) => r.fail("keyfile", e.to_string()),
+            }
+        }
 
-impl Cryptboot {
-    fn from_config(config: Config) -> Self {
-        Self(config)
+        r
     }
 
-    fn mount(&self) -> anyhow::Result<EncryptedBoot> {
-        let mut m = EncryptedBoot::from_config(self.0.boot.clone());
-        m.reset();
-        m.mount()?;
-        Ok(m)
-    }
+    /// Reports mapper and mounts state, failing when they are inconsistent,
+    /// `cache` saving probes in state
+    fn status(&self, cache: bool) -> health::Report {
+        let mut r = health::Report::default();
+        let boot = &self.config.boot;
+        let mapper = EncryptedBoot::from_config(boot.clone()).mapper();
+        let mapper_open = mapper.is_valid();
 
-    fn harden_sbctl(&self) -> anyhow::Result<()> {
-        let sbctl_dir = PathBuf::from("/usr/share/secureboot").canonicalize()?;
-        let dst = self.0.boot.mountpoint.join("secureboot");
+        match mapper_open {
+            true => r.ok("mapper", format!("{mapper} is open")),
+            false => r.ok("mapper", format!("{mapper} is closed")),
+        }
 
-        let m = self.mount()?.umount_on_drop();
+        let (boot_mount, efi_mount) = match (
+            mounts::find_by_target(&boot.mountpoint),
+            mounts::find_by_target(&boot.efi.mountpoint),
+        ) {
+            (Ok(b), Ok(e)) => (b, e),
+            (Err(e), _) | (_, Err(e)) => {
+                r.fail("mounts", e.to_string());
+                return r;
+            }
+        };
 
-        // nothing to do
-        if sbctl_dir == dst {
-            return Ok(());
+        let mapper_path = mapper.full_path();
+        match &boot_mount {
+            Some(m) if fs::canonicalize(&m.source).ok() != fs::canonicalize(&mapper_path).ok() => r
+                .fail(
+                    "boot",
+                    format!(
+                        "{} is mounted from {} instead of {mapper}",
+                        boot.mountpoint.to_string_lossy(),
+                        m.source
+                    ),
+                ),
+            Some(m) => r.ok(
+                "boot",
+                format!(
+                    "{} is mounted from {}",
+                    boot.mountpoint.to_string_lossy(),
+                    m.source
+                ),
+            ),
+            None if mapper_open => r.fail(
+                "boot",
+                format!(
+                    "{mapper} is open but {} is not mounted",
+                    boot.mountpoint.to_string_lossy()
+                ),
+            ),
+            None => r.ok(
+                "boot",
+                format!("{} is not mounted", boot.mountpoint.to_string_lossy()),
+            ),
+        }
+
+        match &efi_mount {
+            Some(m)
+                if fs::canonicalize(&m.source).ok()
+                    != fs::canonicalize(boot.efi.device.full_path()).ok() =>
+            {
+                r.fail(
+                    "efi",
+                    format!(
+                        "{} is mounted from {} instead of {}",
+                        boot.efi.mountpoint.to_string_lossy(),
+                        m.source,
+                        boot.efi.device
+                    ),
+                )
+            }
+            Some(m) => r.ok(
+                "efi",
+                format!(
+                    "{} is mounted from {}",
+                    boot.efi.mountpoint.to_string_lossy(),
+                    m.source
+                ),
+            ),
+            None => r.ok(
+                "efi",
+                format!("{} is not mounted", boot.efi.mountpoint.to_string_lossy()),
+            ),
+        }
+
+        // sbctl database and keys may live on encrypted boot and files are spread on both
+        if boot_mount.is_some() && efi_mount.is_some() {
+            let unsigned = self.signer().and_then(|s| match cache {
+                true => state::cached_probe("sign:unsigned", || s.unsigned(&self.config.boot)),
+                false => s.unsigned(&self.config.boot),
+            });
+            match unsigned {
+                Ok(files) if files.is_empty() => r.ok("signatures", "all files are signed"),
+                Ok(files) => r.warn("signatures", format!("not signed: {}", files.join(" "))),
+                Err(e) => r.warn("signatures", e.to_string()),
+            }
         }
 
-        fs_extra::dir::move_dir(&sbctl_dir, &self.0.boot.mountpoint, &CopyOptions::new())?;
-        // we create a symlink
-        unix::fs::symlink(dst, sbctl_dir)?;
+        posture::check(&mut r);
 
-        drop(m);
-        Ok(())
+        r
     }
 
-    fn umount(&self) -> anyhow::Result<()> {
-        let m = EncryptedBoot::from_config(self.0.boot.clone());
-        m.umount()
+    /// Compares EFI partition with the manifest recorded in boot
+    fn verify(&self) -> anyhow::Result<health::Report> {
+        let mut r = health::Report::default();
+        let _m = self.mount_with(EfiMode::ReadOnly)?.umount_on_drop();
+        esp::Manifest::verify(&self.config.boot, &mut r)?;
+        Ok(r)
     }
 
-    fn grub_install(&self, o: GrubInstallOptions) -> anyhow::Result<()> {
-        let m = self.mount()?.umount_on_drop();
+    /// Runs every known check without writing anything, boot is only
+    /// inspected if already mounted
+    fn audit(&self) -> health::Report {
+        let mut r = health::Report::scored();
+        r.append(self.healthcheck(false));
+        r.append(self.status(false));
 
-        let grub = Grub::from_config(self.0.grub.clone());
-        // update grub configuration
-        grub.mkconfig(&self.0.boot)?;
-        // install grub
-        grub.install(&self.0.boot)?;
+        let grub = (self.config.bootloader == bootloader::Kind::Grub)
+            .then(|| Grub::from_config(self.config.grub.clone()));
+        match luks::dump(&self.config.boot.device) {
+            Ok(header) => audit::luks(&mut r, &header, grub.as_ref()),
+            Err(e) => r.fail("luks-version", e.to_string()),
+        }
 
-        // we sign all files
-        if !o.no_sign {
-            command::sbctl("sign-all")?;
+        audit::efi_ids(&mut r, &self.config.boot.efi);
+
+        // secret files must not be readable by others
+        let mut files = vec![
+            (self.config_path.clone(), false),
+            (State::path(), false),
+            (sysroot::path(SBCTL_DIR).join("keys"), true),
+        ];
+        if let Some(backup) = &self.config.boot.header_backup {
+            files.push((backup.clone(), true));
+        }
+        if let Some(log) = &self.config.transparency.path {
+            files.push((sysroot::path(log), false));
         }
+        if let Some(key) = &self.config.signer.key {
+            files.push((self.config.boot.mountpoint.join(key), true));
+        }
+        let files: Vec<_> = files.iter().map(|(p, s)| (p.as_path(), *s)).collect();
+        audit::permissions(&mut r, &files);
 
-        drop(m);
-        Ok(())
+        r
     }
 
-    fn run(&self, o: RunOptions) -> anyhow::Result<()> {
-        let m = self.mount()?.umount_on_drop();
-
-        if !o.command_line.is_empty() {
-            let program = &o.command_line[0];
-            let mut cmd = command::command(program);
-            if o.command_line.len() > 1 {
-                cmd.args(&o.command_line[1..]);
+    /// Returns true if running `command` would change the state of the system
+    fn check(&self, command: &Command) -> anyhow::Result<bool> {
+        let m = EncryptedBoot::from_config(self.config.boot.clone());
+        match command {
+            Command::Configure(_)
+            | Command::Audit
+            | Command::Healthcheck
+            | Command::State
+            | Command::Status
+            | Command::ReproduceCheck
+            | Command::Verify
+            | Command::Fleet(FleetCommand::Plan(_))
+            | Command::Plan(_) => Ok(false),
+            Command::Apply(_) => Ok(true),
+            Command::Adopt(o) => Ok(o.record),
+            Command::BenchUnlock(o) => Ok(o.apply),
+            // assuming changes are needed as finding out requires reading LUKS header
+            Command::FixLuksForGrub(_) => Ok(true),
+            Command::GrubDefaults(o) => {
+                if o.undo {
+                    return Ok(
+                        state::with_suffix(&sysroot::path(defaults::GRUB_DEFAULTS), ".bak")
+                            .is_file(),
+                    );
+                }
+                let cur = GrubDefaults::load(sysroot::path(defaults::GRUB_DEFAULTS))?;
+                let mut new = GrubDefaults::load(sysroot::path(defaults::GRUB_DEFAULTS))?;
+                o.apply(&mut new)?;
+                Ok(cur.to_string() != new.to_string())
             }
-            let status = cmd.status()?;
-            if !status.success() {
-                return Err(anyhow!("failed to run {program}: {status}"));
+            Command::Mount(o) if o.unlock_only => Ok(!m.mapper().is_valid()),
+            Command::SystemdUnits(_) => Ok(true),
+            Command::Mount(_) => Ok(!m.efi_mode(self.config.boot.efi.mount_mode).is_mounted()?),
+            Command::Umount => m.is_partially_mounted(),
+            Command::HardenSbctl(o) => {
+                Ok(relocate::is_relocated(&sysroot::path(SBCTL_DIR), &self.sbctl_dst()) == o.undo)
             }
+            Command::Protect(o) => {
+                for d in o.dirs.iter() {
+                    if relocate::is_relocated(&sysroot::path(d), &self.protected_dst(d)?) == o.undo
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            // we have no way to know what grub-install or an arbitrary
+            // command would change so we always consider changes are pending
+            Command::GrubInstall(_)
+            | Command::MarkGood
+            | Command::Run(_)
+            | Command::Uki(UkiCommand::Build(_))
+            | Command::KexecTest(_)
+            | Command::Enroll(_)
+            | Command::Keys(KeysCommand::Enroll(_) | KeysCommand::Export(_))
+            | Command::Sign(_)
+            | Command::Fleet(FleetCommand::Apply(_)) => Ok(true),
+            Command::Keys(KeysCommand::Create) => Ok(!self.keys_dir().exists()),
+            Command::Txn(TxnCommand::Begin) => Ok(State::load()?.transaction.is_none()),
+            Command::Txn(TxnCommand::End) => Ok(State::load()?.transaction.is_some()),
         }
-
-        if o.sign_all {
-            command::sbctl("sign-all")?;
-        }
-
-        drop(m);
-        Ok(())
     }
 }
 
@@ -139,6 +1448,36 @@ pub struct Args {
     /// Path of configuration file
     #[clap(short, long, default_value_t = String::from("/etc/cryptboot/config.toml"))]
     config: String,
+    /// Abort after this number of seconds, cleaning up mounts before exiting
+    #[clap(long)]
+    deadline: Option<u64>,
+    /// Wait up to this number of seconds for another running cryptboot to finish
+    #[clap(long)]
+    wait: Option<u64>,
+    /// Bypass safety checks (i.e. unlock cool-down, unsupported platform)
+    #[clap(long)]
+    force: bool,
+    /// Only report whether the command would change anything (exit code 2 when changes are pending)
+    #[clap(long)]
+    check: bool,
+    /// Print commands and changes instead of running them
+    #[clap(long)]
+    dry_run: bool,
+    /// Root filesystem tree under which configuration, state and system files are looked up
+    #[clap(long)]
+    sysroot: Option<PathBuf>,
+    /// Output format of command results
+    #[clap(long, value_enum, default_value_t = output::Format::Text)]
+    output: output::Format,
+    /// Write progress events (steps, progress, warnings) as JSON lines to this file descriptor
+    #[clap(long, value_name = "FD")]
+    events_fd: Option<i32>,
+    /// Read boot passphrase from standard input instead of prompting
+    #[clap(long, conflicts_with = "passphrase_fd")]
+    passphrase_stdin: bool,
+    /// Read boot passphrase from this file descriptor instead of prompting
+    #[clap(long, value_name = "FD")]
+    passphrase_fd: Option<i32>,
     #[clap(subcommand)]
     command: Option<Command>,
 }
@@ -147,41 +1486,318 @@ pub struct Args {
 enum Command {
     /// Create a configuration from command line
     Configure(ConfigureOption),
+    /// Create a configuration from a system set up by bash cryptboot or by hand, keeping its mapper name
+    Adopt(AdoptOptions),
     /// Mount encrypted boot partition
-    Mount,
+    Mount(MountOptions),
+    /// Install systemd units mounting boot when first accessed and unmounting it when idle
+    SystemdUnits(SystemdUnitsOptions),
     /// Unmount encrypted boot partition
     Umount,
-    /// Install Grub in EFI mountpoint
+    /// Install configured bootloader (grub, systemd-boot or efistub) in EFI mountpoint
+    #[clap(visible_alias = "install")]
     GrubInstall(GrubInstallOptions),
     /// Move sbctl /usr/share/secureboot directory to encrypted boot partition and creates a symlink to it.
     /// After that command sbctl will work only through cryptboot
-    HardenSbctl,
+    HardenSbctl(HardenSbctlOptions),
+    /// Move directories to encrypted boot partition and replace them with symlinks
+    Protect(ProtectOptions),
+    /// Mark the last installation as good, to be run (i.e. from a systemd unit) once the system booted successfully
+    MarkGood,
     /// Mount encrypted boot partition, run command then unmount
     Run(RunOptions),
+    /// Show what cryptboot did to the system
+    State,
+    /// Share a single mount window between commands run during a package manager transaction,
+    /// signing is deferred until the transaction ends
+    #[clap(subcommand)]
+    Txn(TxnCommand),
+    /// Enroll additional unlock methods on boot device
+    #[clap(subcommand)]
+    Enroll(EnrollCommand),
+    /// Create, enroll and export secure boot keys (through sbctl)
+    #[clap(subcommand)]
+    Keys(KeysCommand),
+    /// Sign on an offline machine: export files to sign and import their signed versions
+    #[clap(subcommand)]
+    Sign(SignCommand),
+    /// Build a signed unified kernel image (kernel, initramfs, command line) in EFI mountpoint
+    #[clap(subcommand)]
+    Uki(UkiCommand),
+    /// Load the latest kernel and initramfs with kexec to test them without a firmware reboot
+    KexecTest(KexecTestOptions),
+    /// Print the ordered actions a command would perform, optionally saving them to be applied later
+    Plan(PlanOptions),
+    /// Run the command of a saved plan if it still performs the same actions
+    Apply(ApplyOptions),
+    /// Run cryptboot on the hosts of a fleet manifest over SSH
+    #[clap(subcommand)]
+    Fleet(FleetCommand),
+    /// Compare hashes of EFI partition files with the manifest kept in encrypted boot (exit code 1 on tampering)
+    Verify,
+    /// Rebuild grub image from the inputs recorded at install time and compare hashes (exit code 1 when not reproducible)
+    ReproduceCheck,
+    /// Show whether boot mapper is open, which devices are mounted and kernel security posture (exit code 1 when inconsistent)
+    Status,
+    /// Run checks not requiring to decrypt boot, suitable for monitoring (exit code 1 on failure)
+    Healthcheck,
+    /// Run every check strictly read-only and print a scored report for security reviews (exit code 1 on failure)
+    Audit,
+    /// Benchmark PBKDF2 and suggest keyslot iterations keeping grub unlock time reasonable
+    BenchUnlock(BenchUnlockOptions),
+    /// Convert boot device keyslots (and LUKS version if needed) to settings supported by grub
+    FixLuksForGrub(FixLuksForGrubOptions),
+    /// Show or edit /etc/default/grub, keeping a backup to undo the last change
+    GrubDefaults(GrubDefaultsOptions),
 }
 
 #[derive(Debug, Parser)]
 
 struct GrubInstallOptions {
-    /// Do not sign grub after installation
+    /// Do not sign bootloader after installation
+    #[clap(long)]
+    no_sign: bool,
+}
+
+#[derive(Debug, Parser)]
+struct MountOptions {
+    /// Repair broken symlinks of directories moved to encrypted boot
+    #[clap(long)]
+    repair: bool,
+    /// Only open boot mapper without mounting anything (used by automount units)
+    #[clap(long, conflicts_with = "repair")]
+    unlock_only: bool,
+}
+
+#[derive(Debug, Parser)]
+struct SystemdUnitsOptions {
+    /// Directory units are written to
+    #[clap(long, default_value = "/etc/systemd/system")]
+    dir: PathBuf,
+    /// Seconds without access after which boot is unmounted
+    #[clap(long, default_value_t = 600)]
+    idle: u64,
+}
+
+#[derive(Debug, Parser)]
+struct AdoptOptions {
+    /// Record directories already moved into boot as relocations, once the printed configuration is saved
+    #[clap(long)]
+    record: bool,
+}
+
+#[derive(Debug, Parser)]
+struct HardenSbctlOptions {
+    /// Move secureboot directory back to /usr/share and remove the symlink
+    #[clap(long)]
+    undo: bool,
+}
+
+#[derive(Debug, Parser)]
+struct ProtectOptions {
+    /// Move directories back to their original location and remove the symlinks
+    #[clap(long)]
+    undo: bool,
+    /// Absolute paths of directories to protect
+    #[clap(required = true)]
+    dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+struct BenchUnlockOptions {
+    /// Target unlock time in grub (seconds)
+    #[clap(long, default_value_t = GRUB_UNLOCK_TARGET)]
+    target: u64,
+    /// PBKDF2 hash algorithm
+    #[clap(long, default_value_t = String::from("sha256"))]
+    hash: String,
+    /// How many times grub PBKDF2 is slower than cryptsetup one
+    #[clap(long, default_value_t = GRUB_PBKDF2_SLOWDOWN, value_parser = clap::value_parser!(u64).range(1..))]
+    grub_slowdown: u64,
+    /// Apply suggested iterations to the keyslot (after confirmation)
+    #[clap(long)]
+    apply: bool,
+}
+
+#[derive(Debug, Parser)]
+struct FixLuksForGrubOptions {
+    /// Where to save LUKS header backup before modifying it
+    #[clap(long)]
+    header_backup: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+enum TxnCommand {
+    /// Mount encrypted boot until the transaction ends
+    Begin,
+    /// Sign files if requested during the transaction and unmount encrypted boot
+    End,
+}
+
+#[derive(Debug, Parser)]
+struct PlanOptions {
+    /// Save the plan to this file
+    #[clap(short, long)]
+    out: Option<PathBuf>,
+    /// Command to plan (i.e. grub-install)
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+struct ApplyOptions {
+    /// Plan saved with plan --out
+    plan: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+enum EnrollCommand {
+    /// Enroll a TPM2 bound keyslot, tried before asking the passphrase when unlocking
+    Tpm2(EnrollTpm2Options),
+    /// Generate a key file (sealed as boot.keyfile_seal says) and enroll it, tried before asking the passphrase when unlocking
+    Keyfile,
+    /// Enroll a FIDO2 security key keyslot, tried before asking the passphrase when unlocking
+    Fido2,
+}
+
+#[derive(Debug, Parser)]
+struct EnrollTpm2Options {
+    /// PCRs the keyslot is bound to, overrides boot.tpm2_pcrs (default 7)
+    #[clap(long)]
+    pcrs: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+enum FleetCommand {
+    /// Print what the command of each host profile would do
+    Plan(FleetOptions),
+    /// Run the command of each host profile
+    Apply(FleetOptions),
+}
+
+#[derive(Debug, Parser)]
+struct FleetOptions {
+    /// Fleet manifest listing hosts and profiles
+    manifest: PathBuf,
+    /// SSH command used to reach hosts
+    #[clap(long, default_value = "ssh")]
+    ssh: String,
+}
+
+#[derive(Debug, Parser)]
+enum SignCommand {
+    /// Copy unsigned EFI binaries and kernels to a bundle directory, to be signed in place offline
+    ExportRequests(SignExportOptions),
+    /// Install the signed files of a bundle, skipping files changed since the export
+    Import(SignImportOptions),
+}
+
+#[derive(Debug, Parser)]
+struct SignExportOptions {
+    /// Bundle directory
+    out: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+struct SignImportOptions {
+    /// Bundle directory holding signed files
+    bundle: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+enum KeysCommand {
+    /// Create PK, KEK and db keys
+    Create,
+    /// Enroll keys in firmware, which must be in setup mode
+    Enroll(KeysEnrollOptions),
+    /// Export public certificates to enroll them on other machines
+    Export(KeysExportOptions),
+}
+
+#[derive(Debug, Parser)]
+struct KeysEnrollOptions {
+    /// Also enroll Microsoft certificates, needed by firmware drivers of some devices
+    #[clap(long)]
+    microsoft: bool,
+}
+
+#[derive(Debug, Parser)]
+struct KeysExportOptions {
+    /// Directory receiving PK.pem, KEK.pem and db.pem
+    dir: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+enum UkiCommand {
+    /// Build the image from [uki] configuration and sign it
+    Build(UkiBuildOptions),
+}
+
+#[derive(Debug, Parser)]
+struct UkiBuildOptions {
+    /// Do not sign all files after building
     #[clap(long)]
     no_sign: bool,
 }
 
+#[derive(Debug, Parser)]
+struct KexecTestOptions {
+    /// Kernel image relative to boot mountpoint, the most recent one by default
+    #[clap(long)]
+    kernel: Option<PathBuf>,
+    /// Kernel command line, the one of the running kernel by default
+    #[clap(long)]
+    cmdline: Option<String>,
+    /// Boot the loaded kernel right away (systemctl kexec)
+    #[clap(long)]
+    exec: bool,
+}
+
+#[derive(Debug, Parser)]
+struct GrubDefaultsOptions {
+    /// Set a variable (i.e. GRUB_TIMEOUT=5)
+    #[clap(long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+    /// Remove a variable
+    #[clap(long, value_name = "KEY")]
+    unset: Vec<String>,
+    /// Restore the file as it was before the last change
+    #[clap(long, conflicts_with_all = ["set", "unset"])]
+    undo: bool,
+}
+
+impl GrubDefaultsOptions {
+    fn apply(&self, d: &mut GrubDefaults) -> anyhow::Result<()> {
+        for s in self.set.iter() {
+            let (k, v) = s
+                .split_once('=')
+                .ok_or(anyhow!("expecting KEY=VALUE: {s}"))?;
+            d.set(k, v);
+        }
+        for k in self.unset.iter() {
+            d.unset(k);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Parser)]
 struct ConfigureOption {
-    /// Path to a LUKS formated device used to store boot files
+    /// LUKS formated device used to store boot files (path, UUID=, PARTUUID= or LABEL=)
     #[clap(long)]
-    boot_device: PathBuf,
+    boot_device: Device,
     /// Path where boot partition will be mounted
     #[clap(long, default_value_t = String::from("/boot"))]
     boot_mountpoint: String,
-    /// Path to the device holding your efi partition (accessible by UEFI)
+    /// Device holding your efi partition accessible by UEFI (path, UUID=, PARTUUID= or LABEL=)
     #[clap(long)]
-    efi_device: PathBuf,
+    efi_device: Device,
     /// Path where efi partition will be mounted
     #[clap(long, default_value_t= String::from("/boot/efi"))]
     efi_mountpoint: String,
+    /// Include default grub early config template in configuration, to customize what runs before grub.cfg is loaded
+    #[clap(long)]
+    early_config: bool,
 }
 
 impl From<ConfigureOption> for Config {
@@ -194,23 +1810,214 @@ impl From<ConfigureOption> for Config {
 
         c.boot.efi.device = value.efi_device;
         c.boot.efi.mountpoint = value.efi_mountpoint.into();
+
+        if value.early_config {
+            c.grub.early_config = Some(grub::EARLY_CONFIG.into());
+        }
         c
     }
 }
 
 #[derive(Debug, Parser)]
 struct RunOptions {
-    /// Run sbctl sign-all before unmounting (useful when running a system update)
+    /// Sign all files before unmounting (useful when running a system update)
     #[clap(short = 's', long)]
     sign_all: bool,
     /// Command line to run
     command_line: Vec<String>,
 }
 
+// configuration of new devices, recording identifiers of efi device
+fn new_config(o: ConfigureOption) -> Config {
+    let mut c: Config = o.into();
+    // identifiers are used to detect efi device changes
+    if let Err(e) = c.boot.efi.probe_ids() {
+        eprintln!("failed to probe efi device identifiers: {e}");
+    }
+    match topology::share_disk(c.boot.device.full_path(), c.boot.efi.device.full_path()) {
+        Ok(false) => eprintln!("{}", i18n::tr("different-disks", &[])),
+        Ok(true) => {}
+        Err(e) => eprintln!("failed to inspect device topology: {e}"),
+    }
+    c
+}
+
 fn get_current_uid() -> libc::uid_t {
     unsafe { libc::getuid() }
 }
 
+/// Fleet commands run on an admin machine, without root nor configuration
+fn fleet(c: FleetCommand) -> anyhow::Result<()> {
+    let (o, plan) = match c {
+        FleetCommand::Plan(o) => (o, true),
+        FleetCommand::Apply(o) => (o, false),
+    };
+    let outcomes = fleet::Fleet::load(&o.manifest)?.run(&o.ssh, plan)?;
+    if output::json() {
+        println!("{}", output::to_json(&outcomes)?);
+    } else {
+        for r in &outcomes {
+            let status = if r.success { "ok" } else { "failed" };
+            println!("== {} ({status})\n{}", r.host, r.output.trim_end());
+        }
+    }
+    let failed = outcomes.iter().filter(|r| !r.success).count();
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} hosts failed", outcomes.len()));
+    }
+    Ok(())
+}
+
+fn parse_command(args: &[String]) -> anyhow::Result<Command> {
+    let command = Command::try_parse_from(
+        std::iter::once("cryptboot").chain(args.iter().map(|a| a.as_str())),
+    )?;
+    if matches!(
+        command,
+        Command::Plan(_) | Command::Apply(_) | Command::Fleet(_)
+    ) {
+        return Err(anyhow!("plan, apply and fleet cannot be planned"));
+    }
+    Ok(command)
+}
+
+/// Runs the command in dry-run mode, recording what it would do
+fn plan(cryptboot: &Cryptboot, args: &[String]) -> anyhow::Result<plan::Plan> {
+    let command = parse_command(args)?;
+
+    let dry_run = command::dry_run();
+    command::set_dry_run(true);
+    command::record_notes();
+    let res = run_command(cryptboot, command);
+    let actions = command::take_notes();
+    command::set_dry_run(dry_run);
+
+    res?;
+    plan::Plan::new(args.to_vec(), actions)
+}
+
+fn run_command(cryptboot: &Cryptboot, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Configure(_) => {}
+        Command::Adopt(_) => {
+            adopt::record_relocations(&sysroot::strip(&cryptboot.config.boot.mountpoint))?
+        }
+        Command::Plan(o) => {
+            let p = plan(cryptboot, &o.command)?;
+            match output::json() {
+                true => println!("{}", output::to_json(&p)?),
+                false => print!("{p}"),
+            }
+            if let Some(out) = o.out {
+                p.save(out)?;
+            }
+        }
+        Command::Apply(o) => {
+            let saved = plan::Plan::load(&o.plan)?;
+            // the system may have changed since the plan was made, it is
+            // computed again under the lock
+            if !plan(cryptboot, &saved.args)?.matches(&saved) {
+                return Err(anyhow!(
+                    "plan {} is stale, run plan again",
+                    o.plan.to_string_lossy()
+                ));
+            }
+            run_command(cryptboot, parse_command(&saved.args)?)?;
+        }
+        Command::Mount(o) if o.unlock_only => cryptboot.unlock()?,
+        Command::Mount(o) => {
+            let efi_mode = cryptboot.config.boot.efi.mount_mode;
+            cryptboot.mount_with(efi_mode)?;
+            match efi_mode {
+                EfiMode::ReadWrite => {}
+                EfiMode::ReadOnly => eprintln!("{}", i18n::tr("efi-read-only", &[])),
+                EfiMode::Skip => eprintln!("{}", i18n::tr("efi-skipped", &[])),
+            }
+            if o.repair {
+                cryptboot.repair_relocations()?;
+            }
+        }
+        Command::Umount => cryptboot.umount()?,
+        Command::SystemdUnits(o) => cryptboot.systemd_units(o)?,
+        Command::Txn(TxnCommand::Begin) => cryptboot.txn_begin()?,
+        Command::Txn(TxnCommand::End) => cryptboot.txn_end()?,
+        Command::Uki(UkiCommand::Build(o)) => cryptboot.uki_build(o)?,
+        Command::KexecTest(o) => cryptboot.kexec_test(o)?,
+        Command::Keys(c) => cryptboot.keys(c)?,
+        Command::Sign(c) => cryptboot.sign(c)?,
+        Command::Enroll(EnrollCommand::Tpm2(o)) => cryptboot.enroll_tpm2(o)?,
+        Command::Enroll(EnrollCommand::Fido2) => cryptboot.enroll_fido2()?,
+        Command::Enroll(EnrollCommand::Keyfile) => cryptboot.enroll_keyfile()?,
+        Command::GrubDefaults(o) => {
+            if o.undo {
+                return GrubDefaults::undo(sysroot::path(defaults::GRUB_DEFAULTS));
+            }
+            let mut d = GrubDefaults::load(sysroot::path(defaults::GRUB_DEFAULTS))?;
+            if o.set.is_empty() && o.unset.is_empty() {
+                for (k, v) in d.vars() {
+                    println!("{k}={v:?}");
+                }
+                return Ok(());
+            }
+            o.apply(&mut d)?;
+            d.save()?;
+        }
+        Command::GrubInstall(o) => cryptboot.grub_install(o)?,
+        Command::HardenSbctl(o) => cryptboot.harden_sbctl(o)?,
+        Command::Protect(o) => cryptboot.protect(o)?,
+        Command::MarkGood => cryptboot.mark_good()?,
+        Command::Run(o) => cryptboot.run(o)?,
+        Command::State if output::json() => println!("{}", output::to_json(&State::load()?)?),
+        Command::State => print!("{}", toml::to_string(&State::load()?)?),
+        Command::Fleet(_) => unreachable!("fleet runs without configuration"),
+        Command::Verify => {
+            let report = cryptboot.verify()?;
+            report.print();
+            if report.status() == health::Status::Fail {
+                std::process::exit(1);
+            }
+        }
+        Command::ReproduceCheck => {
+            let report = cryptboot.reproduce_check()?;
+            report.print();
+            if report.status() == health::Status::Fail {
+                std::process::exit(1);
+            }
+        }
+        Command::Status => {
+            let report = cryptboot.status(true);
+            report.print();
+            if report.status() == health::Status::Fail {
+                std::process::exit(1);
+            }
+        }
+        Command::Audit => {
+            let report = cryptboot.audit();
+            report.print();
+            if report.status() == health::Status::Fail {
+                std::process::exit(1);
+            }
+        }
+        Command::BenchUnlock(o) => cryptboot.bench_unlock(o)?,
+        Command::FixLuksForGrub(o) => cryptboot.fix_luks_for_grub(o)?,
+        Command::Healthcheck => {
+            let report = cryptboot.healthcheck(true);
+            report.print();
+            if report.status() != health::Status::Ok {
+                notify::notify(
+                    &cryptboot.config.notify,
+                    "healthcheck",
+                    &format!("healthcheck status: {}", report.status()),
+                );
+            }
+            if report.status() == health::Status::Fail {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), anyhow::Error> {
     let styles = styling::Styles::styled()
         .header(styling::AnsiColor::Green.on_default() | styling::Effects::BOLD)
@@ -220,34 +2027,197 @@ fn main() -> Result<(), anyhow::Error> {
 
     let a = Args::command().styles(styles).get_matches();
     let args = Args::from_arg_matches(&a)?;
+    command::set_dry_run(args.dry_run);
+    if let Some(root) = &args.sysroot {
+        sysroot::set(root);
+    }
+    output::set_format(args.output);
+    if let Some(fd) = args.events_fd {
+        events::set_fd(fd);
+    }
+
+    // CRYPTBOOT_PASSPHRASE is the last resort of pipelines unable to pass a fd
+    let passphrase = match (args.passphrase_stdin, args.passphrase_fd) {
+        (true, _) => Some(io::read_to_string(io::stdin())?),
+        (false, Some(fd)) => Some(io::read_to_string(unsafe { fs::File::from_raw_fd(fd) })?),
+        (false, None) => std::env::var(PASSPHRASE_ENV).ok(),
+    };
+    if let Some(p) = passphrase {
+        prompt::set_passphrase(p);
+    }
+
+    if let Some(secs) = args.deadline {
+        command::set_deadline(Instant::now() + Duration::from_secs(secs));
+    }
 
     if let Some(Command::Configure(o)) = args.command {
-        let c: Config = o.into();
-        print!("{}", toml::to_string(&c)?);
+        print!("{}", toml::to_string(&new_config(o))?);
         return Ok(());
     }
 
+    if let Some(Command::Fleet(c)) = args.command {
+        return fleet(c);
+    }
+
+    platform::check_host(args.sysroot.is_some(), args.force)?;
+
     if get_current_uid() != 0 && !matches!(args.command, Some(Command::Configure(_))) {
-        return Err(anyhow!("this program needs to run as root"));
+        return Err(anyhow!(i18n::tr("need-root", &[])));
     }
 
-    let config: Config = toml::from_str(
-        &fs::read_to_string(&args.config)
-            .map_err(|e| anyhow!("failed to read configuration file {}: {e}", &args.config))?,
-    )?;
+    // recording relocations needs the saved configuration and the lock
+    if let Some(Command::Adopt(AdoptOptions { record: false })) = args.command {
+        let layout = adopt::detect()?;
+        let mut c = new_config(ConfigureOption {
+            boot_device: layout.boot_device.clone(),
+            boot_mountpoint: layout.boot_mountpoint.to_string_lossy().into(),
+            efi_device: layout.efi_device.clone(),
+            efi_mountpoint: layout.efi_mountpoint.to_string_lossy().into(),
+            early_config: false,
+        });
+        // keys made by cryptboot-efikeys are used as is when sbctl is absent
+        if let (Some(keys), false) = (&layout.keys_dir, sysroot::path(SBCTL_DIR).exists()) {
+            c.signer.backend = signer::Kind::Sbsign;
+            c.signer.key = Some(keys.join("db.key"));
+            c.signer.cert = Some(keys.join("db.crt"));
+        }
+        // crypttab, fstab and hooks keep referencing the existing mapper
+        if layout.mapper != boot::BOOT_MAPPER_NAME {
+            c.boot.mapper_name = Some(layout.mapper.clone());
+        }
+        print!("{}", toml::to_string(&c)?);
+        return Ok(());
+    }
+
+    let config_path = sysroot::path(&args.config);
+    let mut config: Config = toml::from_str(&fs::read_to_string(&config_path).map_err(|e| {
+        anyhow!(
+            "failed to read configuration file {}: {e}",
+            config_path.to_string_lossy()
+        )
+    })?)?;
+    // boot and efi are mounted within sysroot
+    config.boot.mountpoint = sysroot::path(&config.boot.mountpoint);
+    config.boot.efi.mountpoint = sysroot::path(&config.boot.efi.mountpoint);
+
+    // before grub-install fails deep inside a command
+    if matches!(
+        args.command,
+        Some(Command::GrubInstall(_) | Command::Keys(_))
+    ) {
+        let target = Some(config.grub.target.as_str())
+            .filter(|_| config.bootloader == bootloader::Kind::Grub);
+        platform::check_efi(target, args.sysroot.is_some(), args.force)?;
+    }
 
-    let cryptboot = Cryptboot::from_config(config);
+    config.check_secrets()?;
+
+    let cryptboot = Cryptboot::from_config(config)
+        .config_path(&config_path)
+        .force(args.force);
+
+    if args.check {
+        if let Some(command) = &args.command {
+            if cryptboot.check(command)? {
+                println!("changes pending");
+                std::process::exit(2);
+            }
+        }
+        println!("no change");
+        return Ok(());
+    }
 
     if let Some(command) = args.command {
-        match command {
-            Command::Configure(_) => {}
-            Command::Mount => cryptboot.mount().map(|_| ())?,
-            Command::Umount => cryptboot.umount()?,
-            Command::GrubInstall(o) => cryptboot.grub_install(o)?,
-            Command::HardenSbctl => cryptboot.harden_sbctl()?,
-            Command::Run(o) => cryptboot.run(o)?,
+        // reports print their own JSON
+        let report = matches!(
+            command,
+            Command::Status
+                | Command::Healthcheck
+                | Command::Audit
+                | Command::State
+                | Command::Plan(_)
+                | Command::ReproduceCheck
+                | Command::Verify
+        );
+        // commands mounting boot must not race (i.e. a hook unmounting it
+        // during a manual grub-install)
+        let read_only = matches!(
+            command,
+            Command::Status | Command::Healthcheck | Command::Audit | Command::State
+        );
+        let _lock = match read_only {
+            true => None,
+            false => Some(lock::acquire(args.wait)?),
+        };
+        let res = run_command(&cryptboot, command);
+        if let Err(e) = &res {
+            notify::notify(&cryptboot.config.notify, "failure", &e.to_string());
         }
+
+        if output::json() && !report {
+            output::CommandResult::new(a.subcommand_name().unwrap_or_default(), &res)
+                .devices(
+                    &cryptboot.config.boot.device,
+                    &cryptboot.config.boot.efi.device,
+                )
+                .print()?;
+            if res.is_err() {
+                std::process::exit(1);
+            }
+        }
+        return res;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_specifications() {
+        let parse = |s: &str| s.parse::<Device>().unwrap();
+        assert_eq!(
+            parse("UUID=0f2c8b9e-4d61-4a3f-9b7e-2c5d8e1a6f30"),
+            Device::Uuid("0f2c8b9e-4d61-4a3f-9b7e-2c5d8e1a6f30".into())
+        );
+        assert_eq!(
+            parse("PARTUUID=5A1E2B3C-01"),
+            Device::PartUuid("5a1e2b3c-01".into())
+        );
+        assert_eq!(parse("LABEL=boot"), Device::Label("boot".into()));
+        assert_eq!(parse("/dev/sda2"), Device::Path("/dev/sda2".into()));
+        // '=' within a path is not a specification
+        assert_eq!(
+            parse("/dev/disk/by-id/dm-uuid=x"),
+            Device::Path("/dev/disk/by-id/dm-uuid=x".into())
+        );
+
+        assert_eq!(
+            parse("UUID=ABCD-EF01").full_path(),
+            PathBuf::from("/dev/disk/by-uuid/ABCD-EF01")
+        );
+        assert_eq!(
+            parse("PARTUUID=5a1e2b3c-01").full_path(),
+            PathBuf::from("/dev/disk/by-partuuid/5a1e2b3c-01")
+        );
+
+        for s in ["UUID=", "PARTUUID=", "LABEL=", "ID=foo"] {
+            assert!(s.parse::<Device>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn device_labels_are_udev_escaped() {
+        let dev: Device = "LABEL=EFI System/boot".parse().unwrap();
+        assert_eq!(dev, Device::Label("EFI System/boot".into()));
+        assert_eq!(
+            dev.full_path(),
+            PathBuf::from("/dev/disk/by-label/EFI\\x20System\\x2fboot")
+        );
+        // configuration keeps the label as written
+        assert_eq!(String::from(dev), "LABEL=EFI System/boot");
+        assert_eq!(udev_escape("a-b_c.d:e"), "a-b_c.d:e");
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const PROC_MOUNTS: &str = "/proc/mounts";
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: String,
+    pub options: String,
+}
+
+/// Unescapes octal sequences used by /proc/mounts and /proc/swaps for
+/// spaces, tabs, newlines and backslashes
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let oct: String = chars.clone().take(3).collect();
+            if let Ok(b) = u8::from_str_radix(&oct, 8) {
+                out.push(b as char);
+                chars.nth(2);
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+pub fn mounts() -> anyhow::Result<Vec<MountEntry>> {
+    let mut entries = vec![];
+    for line in fs::read_to_string(PROC_MOUNTS)?.lines() {
+        let mut fields = line.split_whitespace();
+        if let (Some(source), Some(target), Some(fstype), Some(options)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        {
+            entries.push(MountEntry {
+                source: unescape(source),
+                target: PathBuf::from(unescape(target)),
+                fstype: fstype.into(),
+                options: options.into(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns the last mount entry found for `target`, which is the one visible
+/// when several filesystems are stacked on the same mountpoint
+pub fn find_by_target<P: AsRef<Path>>(target: P) -> anyhow::Result<Option<MountEntry>> {
+    Ok(mounts()?
+        .into_iter()
+        .filter(|e| e.target == target.as_ref())
+        .last())
+}
+
+pub fn is_mounted<P: AsRef<Path>>(target: P) -> anyhow::Result<bool> {
+    Ok(find_by_target(target)?.is_some())
+}
+
+// whether `p` is `dir` or lies under it
+fn is_under(p: &Path, dirs: &[&Path]) -> bool {
+    dirs.iter().any(|d| p.starts_with(d))
+}
+
+/// Processes named after one of `names` (i.e. pacman) with their working
+/// directory or an open file under one of `dirs`, as (pid, name)
+pub fn users(dirs: &[&Path], names: &[&str]) -> anyhow::Result<Vec<(u32, String)>> {
+    let me = std::process::id();
+    let mut users = vec![];
+
+    for e in fs::read_dir("/proc")? {
+        let Ok(pid) = e?.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        let proc = PathBuf::from("/proc").join(pid.to_string());
+        // processes may exit while we look at them
+        let Ok(comm) = fs::read_to_string(proc.join("comm")) else {
+            continue;
+        };
+        let comm = comm.trim().to_string();
+        if pid == me || !names.contains(&comm.as_str()) {
+            continue;
+        }
+
+        let cwd = fs::read_link(proc.join("cwd")).ok();
+        let fds = fs::read_dir(proc.join("fd"))
+            .into_iter()
+            .flatten()
+            .filter_map(|e| fs::read_link(e.ok()?.path()).ok());
+        if cwd.into_iter().chain(fds).any(|p| is_under(&p, dirs)) {
+            users.push((pid, comm));
+        }
+    }
+    Ok(users)
+}
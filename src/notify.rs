@@ -0,0 +1,108 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::{self, command},
+    events, output,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // shell command run with CRYPTBOOT_EVENT and CRYPTBOOT_MESSAGE in environment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    // URL receiving a JSON {"event": ..., "message": ...} POST request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    // notify desktop users when healthcheck finds boot decrypted for more
+    // than exposure_threshold seconds
+    pub desktop: bool,
+    pub exposure_threshold: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            command: None,
+            webhook: None,
+            desktop: false,
+            exposure_threshold: 600,
+        }
+    }
+}
+
+/// Notifies about an event, failing to notify is reported but never fatal
+pub fn notify(cfg: &Config, event: &str, message: &str) {
+    if let Some(cmd) = &cfg.command {
+        let res = command::status(
+            command("sh")
+                .arg("-c")
+                .arg(cmd)
+                .env("CRYPTBOOT_EVENT", event)
+                .env("CRYPTBOOT_MESSAGE", message),
+        );
+        match res {
+            Ok(s) if s.success() => {}
+            Ok(s) => events::warn(format!("notification command failed: {s}")),
+            Err(e) => events::warn(format!("notification command failed: {e}")),
+        }
+    }
+
+    if let Some(url) = &cfg.webhook {
+        let body = format!(
+            r#"{{"event":"{}","message":"{}"}}"#,
+            output::json_escape(event),
+            output::json_escape(message)
+        );
+        let res = command::status(
+            command("curl")
+                .args(["-fsS", "-X", "POST", "-H", "Content-Type: application/json"])
+                .arg("--data")
+                .arg(body)
+                .arg(url),
+        );
+        match res {
+            Ok(s) if s.success() => {}
+            Ok(s) => events::warn(format!("webhook notification failed: {s}")),
+            Err(e) => events::warn(format!("webhook notification failed: {e}")),
+        }
+    }
+}
+
+/// Shows a desktop notification to every user having a session bus, we run
+/// as root so notify-send must run as the user on its bus
+pub fn desktop(summary: &str, body: &str) {
+    let Ok(rd) = fs::read_dir("/run/user") else {
+        return;
+    };
+
+    for e in rd.flatten() {
+        let bus = e.path().join("bus");
+        if !bus.exists() {
+            continue;
+        }
+
+        let uid = e.file_name().to_string_lossy().to_string();
+        let user = match command("id").args(["-nu", &uid]).output() {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).trim().to_string(),
+            _ => continue,
+        };
+
+        let res = command::status(
+            command("runuser")
+                .args(["-u", &user, "--", "notify-send"])
+                .args(["--urgency=critical", "--icon=drive-harddisk", summary, body])
+                .env(
+                    "DBUS_SESSION_BUS_ADDRESS",
+                    format!("unix:path={}", bus.to_string_lossy()),
+                ),
+        );
+        match res {
+            Ok(s) if s.success() => {}
+            Ok(s) => events::warn(format!("desktop notification to {user} failed: {s}")),
+            Err(e) => events::warn(format!("desktop notification to {user} failed: {e}")),
+        }
+    }
+}
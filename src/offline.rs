@@ -0,0 +1,145 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{boot, command, events, kernel, pool, signer, state, timing};
+
+const MANIFEST: &str = "manifest.toml";
+
+/// File to be signed on an offline machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    // where the file is installed
+    pub path: PathBuf,
+    // name of the copy in the bundle, replaced by its signed version
+    pub name: String,
+    // hash of the unsigned file, so that files changed since the export
+    // are not overwritten
+    pub sha256: String,
+}
+
+/// Signing requests bundle, a directory holding the files to sign and a manifest
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub requests: Vec<Request>,
+}
+
+impl Manifest {
+    pub fn load<P: AsRef<Path>>(bundle: P) -> anyhow::Result<Self> {
+        let path = bundle.as_ref().join(MANIFEST);
+        toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| anyhow!("failed to parse manifest {}: {e}", path.to_string_lossy()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, bundle: P) -> anyhow::Result<()> {
+        state::write_atomic(
+            bundle.as_ref().join(MANIFEST),
+            toml::to_string(self)?,
+            false,
+        )
+    }
+}
+
+// unsigned EFI binaries of EFI partition and kernel images of boot partition
+fn unsigned_files(cfg: &boot::Config) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = signer::esp_binaries(cfg)?;
+    files.extend(kernel::images(cfg)?);
+    files.sort();
+    files.dedup();
+
+    let mut unsigned = vec![];
+    for f in files {
+        if !kernel::is_signed(&fs::read(&f)?) {
+            unsigned.push(f);
+        }
+    }
+    Ok(unsigned)
+}
+
+/// Copies unsigned files to `bundle` along with a manifest, boot must be mounted
+pub fn export<P: AsRef<Path>>(cfg: &boot::Config, bundle: P) -> anyhow::Result<Manifest> {
+    let bundle = bundle.as_ref();
+    let mut manifest = Manifest::default();
+
+    let files = unsigned_files(cfg)?;
+    let hashes = pool::map("hash", &files, pool::jobs(), |f| command::sha256sum(f))?;
+
+    for (i, (path, sha256)) in files.into_iter().zip(hashes).enumerate() {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = format!("{i:03}-{file_name}");
+
+        if command::dry_run() {
+            command::dry_run_note(format!(
+                "copy {} to {}",
+                path.to_string_lossy(),
+                bundle.join(&name).to_string_lossy()
+            ));
+        } else {
+            fs::create_dir_all(bundle)?;
+            fs::copy(&path, bundle.join(&name))?;
+        }
+
+        manifest.requests.push(Request { path, name, sha256 });
+    }
+
+    manifest.save(bundle)?;
+    Ok(manifest)
+}
+
+/// Installs the signed files of `bundle`, checked against `cert`, returns the
+/// number of files installed. The bundle comes back from another machine, so
+/// only files which would be exported now may be replaced.
+pub fn import<P: AsRef<Path>>(cfg: &boot::Config, bundle: P, cert: &Path) -> anyhow::Result<usize> {
+    let bundle = bundle.as_ref();
+    let manifest = Manifest::load(bundle)?;
+    let total = manifest.requests.len() as u64;
+
+    let expected = unsigned_files(cfg)?;
+    if let Some(r) = manifest
+        .requests
+        .iter()
+        .find(|r| !expected.contains(&r.path))
+    {
+        return Err(anyhow!(
+            "{} is not an unsigned EFI binary nor kernel image, refusing bundle",
+            r.path.to_string_lossy()
+        ));
+    }
+
+    let mut installed = 0;
+    for (i, r) in manifest.requests.iter().enumerate() {
+        let signed = bundle.join(&r.name);
+        let data = fs::read(&signed)
+            .map_err(|e| anyhow!("failed to read {}: {e}", signed.to_string_lossy()))?;
+
+        if !kernel::is_signed(&data) {
+            return Err(anyhow!("{} is not signed", signed.to_string_lossy()));
+        }
+        if !command::sbverify(cert, &signed)? {
+            return Err(anyhow!(
+                "signature verification failed: {}",
+                signed.to_string_lossy()
+            ));
+        }
+
+        // the file may have been updated (i.e. new kernel) since the export
+        if !r.path.is_file() || command::sha256sum(&r.path)? != r.sha256 {
+            events::warn(format!(
+                "{} changed since signing requests were exported, skipping",
+                r.path.to_string_lossy()
+            ));
+            continue;
+        }
+
+        state::write_atomic(&r.path, data, false)?;
+        timing::count("files signed", 1);
+        events::progress("import", i as u64 + 1, total);
+        installed += 1;
+    }
+
+    Ok(installed)
+}
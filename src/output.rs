@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::Serialize;
+
+use crate::timing;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed)
+}
+
+/// Whether results must be printed as JSON on stdout
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn write_json(v: &toml::Value, out: &mut String) {
+    match v {
+        toml::Value::String(s) => out.push_str(&format!("\"{}\"", json_escape(s))),
+        toml::Value::Integer(i) => out.push_str(&i.to_string()),
+        toml::Value::Float(f) => out.push_str(&f.to_string()),
+        toml::Value::Boolean(b) => out.push_str(&b.to_string()),
+        toml::Value::Datetime(d) => out.push_str(&format!("\"{d}\"")),
+        toml::Value::Array(a) => {
+            out.push('[');
+            for (i, v) in a.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(v, out);
+            }
+            out.push(']');
+        }
+        toml::Value::Table(t) => {
+            out.push('{');
+            for (i, (k, v)) in t.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&format!("\"{}\":", json_escape(k)));
+                write_json(v, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// Serializes to JSON going through toml values as we already depend on toml,
+/// None values are omitted
+pub fn to_json<T: Serialize>(v: &T) -> anyhow::Result<String> {
+    let mut out = String::new();
+    write_json(&toml::Value::try_from(v)?, &mut out);
+    Ok(out)
+}
+
+#[derive(Debug, Serialize)]
+struct Step {
+    name: String,
+    seconds: f64,
+}
+
+/// Outcome of a command printed in JSON mode
+#[derive(Debug, Serialize)]
+pub struct CommandResult {
+    pub command: String,
+    // ok or error
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub boot_device: String,
+    pub efi_device: String,
+    steps: Vec<Step>,
+    counters: BTreeMap<String, u64>,
+}
+
+impl CommandResult {
+    pub fn new<S: Into<String>>(command: S, res: &anyhow::Result<()>) -> Self {
+        Self {
+            command: command.into(),
+            status: if res.is_ok() { "ok" } else { "error" },
+            error: res.as_ref().err().map(|e| e.to_string()),
+            boot_device: String::new(),
+            efi_device: String::new(),
+            steps: timing::steps()
+                .into_iter()
+                .map(|(name, d)| Step {
+                    name,
+                    seconds: d.as_secs_f64(),
+                })
+                .collect(),
+            counters: timing::counters().into_iter().collect(),
+        }
+    }
+
+    pub fn devices<S: ToString, T: ToString>(mut self, boot: S, efi: T) -> Self {
+        self.boot_device = boot.to_string();
+        self.efi_device = efi.to_string();
+        self
+    }
+
+    pub fn print(&self) -> anyhow::Result<()> {
+        println!("{}", to_json(self)?);
+        Ok(())
+    }
+}
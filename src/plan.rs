@@ -0,0 +1,98 @@
+use std::{fs, path::Path};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{command, state};
+
+/// Ordered actions a command would perform, computed in dry-run mode
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    // command line the plan was computed for
+    pub args: Vec<String>,
+    pub actions: Vec<String>,
+    // hash of the actions without their volatile values, compared by apply
+    #[serde(default)]
+    pub digest: String,
+}
+
+// numbers not part of a name (i.e. sda1) are timestamps, process ids or
+// benchmarked values which differ each time a command is planned
+fn stable(action: &str) -> String {
+    let mut out = String::new();
+    let (mut prev, mut masked) = (' ', false);
+    for c in action.chars() {
+        if c.is_ascii_digit() && !prev.is_ascii_digit() {
+            masked = !prev.is_ascii_alphabetic();
+            if masked {
+                out.push('#');
+            }
+        }
+        if !(c.is_ascii_digit() && masked) {
+            out.push(c);
+        }
+        prev = c;
+    }
+    out
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "plan for: {}", self.args.join(" "))?;
+        if self.actions.is_empty() {
+            return writeln!(f, "no action");
+        }
+        for (i, a) in self.actions.iter().enumerate() {
+            writeln!(f, "{:>3}. {a}", i + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Plan {
+    pub fn new(args: Vec<String>, actions: Vec<String>) -> anyhow::Result<Self> {
+        let stable: Vec<String> = actions.iter().map(|a| stable(a)).collect();
+        let digest = command::sha256(stable.join("\n").as_bytes())?;
+        Ok(Self {
+            args,
+            actions,
+            digest,
+        })
+    }
+
+    /// Returns true if `other` performs the same actions for the same command
+    pub fn matches(&self, other: &Plan) -> bool {
+        self.args == other.args && self.digest == other.digest
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("failed to parse plan {}: {e}", path.to_string_lossy()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        state::write_atomic(path, toml::to_string(self)?, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_masks_volatile_numbers() {
+        assert_eq!(
+            stable("cryptsetup luksHeaderBackup /dev/sda2 --header-backup-file /root/cryptboot-header-1760520000.img"),
+            "cryptsetup luksHeaderBackup /dev/sda2 --header-backup-file /root/cryptboot-header-#.img"
+        );
+        assert_eq!(
+            stable("mount /dev/nvme0n1p2 /boot"),
+            "mount /dev/nvme0n1p2 /boot"
+        );
+        assert_ne!(
+            stable("mount /dev/sda1 /boot"),
+            stable("mount /dev/sda2 /boot")
+        );
+    }
+}
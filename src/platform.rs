@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::events;
+
+const EFI_FIRMWARE: &str = "/sys/firmware/efi";
+// markers left by docker and podman
+const CONTAINER_MARKERS: &[&str] = &["/.dockerenv", "/run/.containerenv"];
+const SUPPORTED: &str = "see \"Supported platforms\" in README";
+
+// unsupported platforms are errors unless forced, experts then get a warning
+fn unsupported(force: bool, msg: String) -> anyhow::Result<()> {
+    match force {
+        true => {
+            events::warn(format!("{msg}, proceeding as forced"));
+            Ok(())
+        }
+        false => Err(anyhow!("{msg} ({SUPPORTED}, --force to proceed anyway)")),
+    }
+}
+
+/// Fails early on systems cryptboot cannot work on, instead of letting
+/// cryptsetup, mount or grub-install fail halfway through a command. Offline
+/// trees (i.e. sysroot) may be configured from a container.
+pub fn check_host(offline: bool, force: bool) -> anyhow::Result<()> {
+    match std::env::consts::OS {
+        "linux" => {}
+        // loader(8) unlocks GELI encrypted boot by itself
+        "freebsd" => {
+            return unsupported(
+                force,
+                "FreeBSD is not supported: its loader boots from GELI encrypted partitions natively (geli init -g)"
+                    .into(),
+            )
+        }
+        os => return unsupported(force, format!("{os} is not supported, only Linux is")),
+    }
+
+    for (dir, what) in [
+        ("/proc/self/mounts", "procfs"),
+        ("/sys/class/block", "sysfs"),
+    ] {
+        if !Path::new(dir).exists() {
+            unsupported(
+                force,
+                format!(
+                    "{what} is not mounted ({dir} missing), mounts and devices cannot be inspected"
+                ),
+            )?;
+        }
+    }
+
+    if let Some(m) = CONTAINER_MARKERS
+        .iter()
+        .find(|m| !offline && Path::new(m).exists())
+    {
+        unsupported(
+            force,
+            format!("running in a container ({m} found), boot devices and EFI variables of the host are not reachable"),
+        )?;
+    }
+    Ok(())
+}
+
+/// Fails when installing a bootloader or enrolling keys on a system not
+/// booted through UEFI, or when grub targets a legacy BIOS platform. Firmware
+/// of the running system is irrelevant when `offline` (i.e. sysroot).
+pub fn check_efi(grub_target: Option<&str>, offline: bool, force: bool) -> anyhow::Result<()> {
+    if let Some(target) = grub_target.filter(|t| !t.ends_with("-efi")) {
+        unsupported(
+            force,
+            format!("grub target {target} is not an EFI target, legacy BIOS boot cannot be signed nor use an EFI partition"),
+        )?;
+    }
+
+    if !offline && !Path::new(EFI_FIRMWARE).is_dir() {
+        unsupported(
+            force,
+            format!("system was not booted through UEFI ({EFI_FIRMWARE} missing): boot entries and secure boot keys cannot be set, enable UEFI boot (disable CSM) in firmware settings"),
+        )?;
+    }
+    Ok(())
+}
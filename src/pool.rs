@@ -0,0 +1,79 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use anyhow::anyhow;
+
+use crate::{command, events};
+
+/// Number of threads independent steps run on
+pub fn jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Runs `f` on every item with up to `jobs` threads, reporting progress of
+/// `step` and returning results in the order of items. Once an item failed
+/// or the deadline is exceeded, remaining items are not started.
+pub fn map<T, R, F>(step: &str, items: &[T], jobs: usize, f: F) -> anyhow::Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> anyhow::Result<R> + Sync,
+{
+    // dry run notes are printed in the order of items
+    let jobs = match command::dry_run() {
+        true => 1,
+        false => jobs.clamp(1, items.len().max(1)),
+    };
+
+    let next = AtomicUsize::new(0);
+    let done = AtomicU64::new(0);
+    let cancelled = AtomicBool::new(false);
+    let error = Mutex::new(None);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let total = items.len() as u64;
+
+    let fail = |e: anyhow::Error| {
+        cancelled.store(true, Ordering::SeqCst);
+        error.lock().unwrap().get_or_insert(e);
+    };
+
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                if cancelled.load(Ordering::SeqCst) {
+                    break;
+                }
+                if command::deadline_exceeded() {
+                    fail(anyhow!("deadline exceeded during {step}"));
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                match f(item) {
+                    Ok(r) => {
+                        results.lock().unwrap()[i] = Some(r);
+                        events::progress(step, done.fetch_add(1, Ordering::SeqCst) + 1, total);
+                    }
+                    Err(e) => fail(e),
+                }
+            });
+        }
+    });
+
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+    Ok(results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect())
+}
@@ -0,0 +1,67 @@
+use std::fs;
+
+use crate::health::Report;
+
+const LOCKDOWN: &str = "/sys/kernel/security/lockdown";
+const SIG_ENFORCE: &str = "/sys/module/module/parameters/sig_enforce";
+const KEXEC_LOAD_DISABLED: &str = "/proc/sys/kernel/kexec_load_disabled";
+
+/// Active kernel lockdown mode (none, integrity or confidentiality)
+pub fn lockdown() -> anyhow::Result<String> {
+    // active mode is the bracketed one (i.e. none [integrity] confidentiality)
+    let modes = fs::read_to_string(LOCKDOWN)?;
+    Ok(modes
+        .split_whitespace()
+        .find_map(|m| m.strip_prefix('[')?.strip_suffix(']'))
+        .unwrap_or("none")
+        .to_string())
+}
+
+fn is_set(path: &str) -> anyhow::Result<bool> {
+    Ok(matches!(fs::read_to_string(path)?.trim(), "1" | "Y"))
+}
+
+/// Whether loading a kernel with kexec is forbidden until reboot
+pub fn kexec_disabled() -> bool {
+    is_set(KEXEC_LOAD_DISABLED).unwrap_or(false)
+}
+
+/// Flags runtime kernel settings letting unsigned code run, which would
+/// undermine a signed boot chain
+pub fn check(r: &mut Report) {
+    let lockdown = match lockdown() {
+        Ok(l) => l,
+        // kernel built without lockdown LSM
+        Err(_) => "none".into(),
+    };
+    let locked = lockdown != "none";
+    match locked {
+        true => r.ok("lockdown", format!("kernel lockdown is {lockdown}")),
+        false => r.warn(
+            "lockdown",
+            "kernel lockdown is disabled, root can modify the running kernel",
+        ),
+    }
+
+    // lockdown enforces module signatures whatever sig_enforce is
+    match is_set(SIG_ENFORCE) {
+        Ok(true) => r.ok("module-signatures", "module signatures are enforced"),
+        Ok(false) | Err(_) if locked => r.ok(
+            "module-signatures",
+            "module signatures enforced by lockdown",
+        ),
+        Ok(false) => r.warn("module-signatures", "unsigned modules can be loaded"),
+        Err(_) => r.warn(
+            "module-signatures",
+            "kernel does not support module signatures",
+        ),
+    }
+
+    match is_set(KEXEC_LOAD_DISABLED) {
+        Ok(true) => r.ok("kexec", "kexec is disabled"),
+        Ok(false) if locked => r.ok("kexec", "kexec is restricted to signed kernels by lockdown"),
+        Ok(false) => r.warn("kexec", "unsigned kernels can be loaded with kexec"),
+        // kernel built without kexec
+        Err(_) => r.ok("kexec", "kexec is not supported"),
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::Mutex,
+};
+
+use crate::{command, i18n};
+
+// boot passphrase given on command line, see [set_passphrase]
+static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Passphrase used to unlock boot instead of prompting (i.e. provisioning pipelines)
+pub fn set_passphrase(mut passphrase: String) {
+    if passphrase.ends_with('\n') {
+        passphrase.pop();
+    }
+    *PASSPHRASE.lock().unwrap() = Some(passphrase);
+}
+
+/// Whether prompts must go through systemd password agents (i.e. desktop
+/// dialogs, pacman hooks, systemd units) rather than the terminal
+pub fn use_agent(forced: bool) -> bool {
+    forced || !io::stdin().is_terminal()
+}
+
+/// Passphrase to pipe to cryptsetup, None when cryptsetup can prompt on the
+/// terminal by itself. `agent` forces asking through password agents.
+pub fn passphrase(id: &str, message: &str, agent: bool) -> anyhow::Result<Option<String>> {
+    if let Some(p) = PASSPHRASE.lock().unwrap().clone() {
+        return Ok(Some(p));
+    }
+    if !use_agent(agent) || command::dry_run() {
+        return Ok(None);
+    }
+    Ok(Some(command::ask_password(id, message, false)?))
+}
+
+/// Asks a yes/no question
+pub fn confirm(prompt: &str) -> anyhow::Result<bool> {
+    let prompt = format!("{prompt} {}", i18n::tr("confirm-choices", &[]));
+    let answer = if use_agent(false) {
+        command::ask_password("confirm", &prompt, true)?
+    } else {
+        print!("{prompt} ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        answer
+    };
+    let answer = answer.trim().to_lowercase();
+    Ok(i18n::tr("confirm-yes", &[]).split('|').any(|y| y == answer))
+}
@@ -0,0 +1,227 @@
+use std::{
+    fs,
+    os::unix,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{copy, sysroot};
+
+/// Returns true if both directory trees have the same structure and content
+fn same_tree(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    let mut a_entries = fs::read_dir(a)?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut b_entries = fs::read_dir(b)?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    a_entries.sort();
+    b_entries.sort();
+
+    if a_entries != b_entries {
+        return Ok(false);
+    }
+
+    for name in a_entries {
+        let (pa, pb) = (a.join(&name), b.join(&name));
+        let (ma, mb) = (fs::symlink_metadata(&pa)?, fs::symlink_metadata(&pb)?);
+
+        let same = if ma.is_symlink() && mb.is_symlink() {
+            fs::read_link(&pa)? == fs::read_link(&pb)?
+        } else if ma.is_dir() && mb.is_dir() {
+            same_tree(&pa, &pb)?
+        } else if ma.is_file() && mb.is_file() {
+            ma.len() == mb.len() && fs::read(&pa)? == fs::read(&pb)?
+        } else {
+            false
+        };
+
+        if !same {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Copies the content of `src` into a new `dst` directory and makes sure the
+/// copy is identical. On failure the partial copy is removed.
+fn verified_copy(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    if dst.exists() {
+        return Err(anyhow!(
+            "destination already exists: {}",
+            dst.to_string_lossy()
+        ));
+    }
+
+    fs::create_dir_all(dst)?;
+
+    let res = copy::copy_tree(src, dst).and_then(|_| same_tree(src, dst));
+
+    match res {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            fs::remove_dir_all(dst)?;
+            Err(anyhow!(
+                "copy of {} to {} differs from source",
+                src.to_string_lossy(),
+                dst.to_string_lossy()
+            ))
+        }
+        Err(e) => {
+            fs::remove_dir_all(dst)?;
+            Err(e)
+        }
+    }
+}
+
+/// Returns true if `src` is a symlink to `dst`
+pub fn is_relocated(src: &Path, dst: &Path) -> bool {
+    fs::read_link(src).is_ok_and(|p| p == sysroot::strip(dst))
+}
+
+/// Moves `src` directory to `dst` and replaces it with a symlink to `dst`.
+/// Source is removed only once the copy has been verified.
+pub fn relocate(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    // nothing to do
+    if is_relocated(src, dst) {
+        return Ok(());
+    }
+
+    if !src.is_dir() {
+        return Err(anyhow!("not a directory: {}", src.to_string_lossy()));
+    }
+
+    verified_copy(src, dst)?;
+
+    fs::remove_dir_all(src)?;
+    unix::fs::symlink(sysroot::strip(dst), src)?;
+
+    Ok(())
+}
+
+/// Reverts a [relocate], moving `dst` back to `src` and removing the symlink
+pub fn restore(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    if !is_relocated(src, dst) {
+        return Err(anyhow!(
+            "{} is not a symlink to {}",
+            src.to_string_lossy(),
+            dst.to_string_lossy()
+        ));
+    }
+
+    // we copy next to the symlink first so that the symlink is replaced only
+    // when a verified copy is available
+    let mut tmp = PathBuf::from(src);
+    tmp.set_extension("cryptboot-restore");
+    verified_copy(dst, &tmp)?;
+
+    fs::remove_file(src)?;
+    fs::rename(&tmp, src)?;
+    fs::remove_dir_all(dst)?;
+
+    Ok(())
+}
+
+/// Moves into `dst` the entries of `src` which do not exist in `dst`,
+/// entries already in `dst` are authoritative and are kept
+fn merge_tree(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    for e in fs::read_dir(src)? {
+        let e = e?;
+        let (s, d) = (e.path(), dst.join(e.file_name()));
+
+        if !d.exists() {
+            if e.file_type()?.is_dir() {
+                verified_copy(&s, &d)?;
+            } else {
+                copy::copy_file(&s, &d)?;
+            }
+        } else if e.file_type()?.is_dir() && d.is_dir() {
+            merge_tree(&s, &d)?;
+        }
+    }
+    Ok(())
+}
+
+/// Merges a directory recreated at `src` (i.e. by a package update) into
+/// `dst` and restores the symlink
+pub fn merge(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    if health(src, dst) != Health::Replaced {
+        return Err(anyhow!(
+            "{} is not a directory replacing {}",
+            src.to_string_lossy(),
+            dst.to_string_lossy()
+        ));
+    }
+
+    merge_tree(src, dst)?;
+    fs::remove_dir_all(src)?;
+    Ok(unix::fs::symlink(sysroot::strip(dst), src)?)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Health {
+    // src is a symlink to an existing dst
+    Ok,
+    // neither src was relocated nor dst exists
+    NotRelocated,
+    // src is a symlink to dst but dst does not exist
+    Dangling,
+    // src is a symlink to another location
+    WrongTarget(PathBuf),
+    // src has been recreated as a directory while dst exists
+    Replaced,
+    // src does not exist while dst exists
+    Missing,
+}
+
+impl std::fmt::Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::NotRelocated => write!(f, "not relocated"),
+            Self::Dangling => write!(f, "dangling symlink"),
+            Self::WrongTarget(p) => write!(f, "symlink to unexpected {}", p.to_string_lossy()),
+            Self::Replaced => write!(f, "replaced by a directory"),
+            Self::Missing => write!(f, "symlink missing"),
+        }
+    }
+}
+
+/// Checks the state of a relocation, `dst` must be reachable (i.e. boot mounted)
+pub fn health(src: &Path, dst: &Path) -> Health {
+    let dst_exists = dst.is_dir();
+
+    match fs::symlink_metadata(src) {
+        Ok(m) if m.is_symlink() => match fs::read_link(src) {
+            Ok(p) if p == sysroot::strip(dst) && dst_exists => Health::Ok,
+            Ok(p) if p == sysroot::strip(dst) => Health::Dangling,
+            Ok(p) => Health::WrongTarget(p),
+            Err(_) => Health::Dangling,
+        },
+        Ok(_) if dst_exists => Health::Replaced,
+        Err(_) if dst_exists => Health::Missing,
+        _ => Health::NotRelocated,
+    }
+}
+
+/// Fixes a broken relocation by recreating the symlink to `dst`. Only
+/// situations where no data can be lost are repaired.
+pub fn repair(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    match health(src, dst) {
+        Health::Ok | Health::NotRelocated => Ok(()),
+        Health::Dangling => Err(anyhow!(
+            "cannot repair {}: {} does not exist",
+            src.to_string_lossy(),
+            dst.to_string_lossy()
+        )),
+        Health::WrongTarget(_) => {
+            fs::remove_file(src)?;
+            Ok(unix::fs::symlink(sysroot::strip(dst), src)?)
+        }
+        Health::Replaced => merge(src, dst),
+        Health::Missing => Ok(unix::fs::symlink(sysroot::strip(dst), src)?),
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot,
+    bootloader::Bootloader,
+    command::{self, command},
+};
+
+// prefix of the loader entries we generate
+const ENTRY_PREFIX: &str = "cryptboot-";
+
+// initramfs naming conventions of the different distributions
+const INITRD_NAMES: &[(&str, &str)] =
+    &[("initramfs-", ".img"), ("initrd.img-", ""), ("initrd-", "")];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    // title of generated loader entries
+    pub title: String,
+    // kernel command line of generated loader entries
+    pub cmdline: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            title: "Linux".into(),
+            cmdline: String::new(),
+        }
+    }
+}
+
+/// systemd-boot can only read EFI partition so kernels it boots must live
+/// there, encrypted boot keeps secrets (i.e. sbctl keys) and UKIs found in
+/// EFI/Linux are discovered by systemd-boot without loader entries.
+pub(crate) struct SystemdBoot(Config);
+
+impl SystemdBoot {
+    pub fn from_config(config: Config) -> Self {
+        Self(config)
+    }
+
+    fn entries_dir(cfg: &boot::Config) -> PathBuf {
+        cfg.efi.mountpoint.join("loader").join("entries")
+    }
+
+    /// Returns (version, kernel, initrd) file names found at the root of EFI partition
+    fn kernels(cfg: &boot::Config) -> anyhow::Result<Vec<(String, String, Option<String>)>> {
+        let esp = &cfg.efi.mountpoint;
+        let mut kernels = vec![];
+        for e in fs::read_dir(esp)? {
+            let name = e?.file_name().to_string_lossy().to_string();
+            let Some(version) = name.strip_prefix("vmlinuz-") else {
+                continue;
+            };
+
+            let initrd = INITRD_NAMES
+                .iter()
+                .map(|(prefix, suffix)| format!("{prefix}{version}{suffix}"))
+                .find(|n| esp.join(n).is_file());
+
+            kernels.push((version.to_string(), name.clone(), initrd));
+        }
+        kernels.sort_by(|a, b| b.0.cmp(&a.0));
+        Ok(kernels)
+    }
+
+    fn stubs(cfg: &boot::Config) -> Vec<PathBuf> {
+        let efi = cfg.efi.mountpoint.join("EFI");
+        vec![
+            efi.join("systemd").join("systemd-bootx64.efi"),
+            efi.join("BOOT").join("BOOTX64.EFI"),
+        ]
+    }
+}
+
+impl Bootloader for SystemdBoot {
+    fn mkconfig(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let dir = Self::entries_dir(cfg);
+        let mut entries = vec![];
+        for (version, kernel, initrd) in Self::kernels(cfg)? {
+            let mut entry = format!("title {} ({version})\nlinux /{kernel}\n", self.0.title);
+            if let Some(initrd) = initrd {
+                entry.push_str(&format!("initrd /{initrd}\n"));
+            }
+            if !self.0.cmdline.is_empty() {
+                entry.push_str(&format!("options {}\n", self.0.cmdline));
+            }
+            entries.push((dir.join(format!("{ENTRY_PREFIX}{version}.conf")), entry));
+        }
+
+        if command::dry_run() {
+            for (path, entry) in entries {
+                command::dry_run_note(format!("write {}\n{entry}", path.to_string_lossy()));
+            }
+            return Ok(());
+        }
+
+        fs::create_dir_all(&dir)?;
+        // entries of removed kernels must go away
+        for e in fs::read_dir(&dir)? {
+            let e = e?;
+            if e.file_name().to_string_lossy().starts_with(ENTRY_PREFIX) {
+                fs::remove_file(e.path())?;
+            }
+        }
+        for (path, entry) in entries {
+            fs::write(path, entry)?;
+        }
+        Ok(())
+    }
+
+    fn install(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let esp = &cfg.efi.mountpoint;
+        if !esp.is_dir() {
+            return Err(anyhow!(
+                "esp directory not found: {}",
+                esp.to_string_lossy()
+            ));
+        }
+
+        let status = command::status(
+            command("bootctl")
+                .arg("install")
+                .arg(format!("--esp-path={}", esp.to_string_lossy())),
+        )?;
+        if !status.success() {
+            return Err(anyhow!("bootctl install failed: {status}"));
+        }
+        Ok(())
+    }
+
+    fn signed_files(&self, cfg: &boot::Config) -> Vec<PathBuf> {
+        Self::stubs(cfg)
+            .into_iter()
+            .filter(|s| s.is_file() || command::dry_run())
+            .collect()
+    }
+
+    fn managed_files(&self, cfg: &boot::Config) -> Vec<PathBuf> {
+        let mut files = Self::stubs(cfg);
+        if let Ok(rd) = fs::read_dir(Self::entries_dir(cfg)) {
+            for e in rd.flatten() {
+                if e.file_name().to_string_lossy().starts_with(ENTRY_PREFIX) {
+                    files.push(e.path());
+                }
+            }
+        }
+        files
+    }
+
+    fn version(&self) -> anyhow::Result<String> {
+        let output = command("bootctl").arg("--version").output()?;
+        if !output.status.success() {
+            return Err(anyhow!("bootctl --version failed: {}", output.status));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string())
+    }
+}
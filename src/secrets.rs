@@ -0,0 +1,72 @@
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::boot;
+
+const SECRET_PREFIX: &str = "secret:";
+
+/// Secrets stored in a toml file on the encrypted boot partition, so they
+/// can only be read once it is unlocked. Configuration values reference them
+/// with `secret:<name>`.
+#[derive(Debug, Default)]
+pub struct Secrets(HashMap<String, String>);
+
+impl Secrets {
+    pub fn path(cfg: &boot::Config) -> PathBuf {
+        cfg.mountpoint.join("cryptboot").join("secrets.toml")
+    }
+
+    /// Loads secrets from a mounted encrypted boot, a missing file means no secrets
+    pub fn load(cfg: &boot::Config) -> anyhow::Result<Self> {
+        let path = Self::path(cfg);
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let meta = fs::metadata(&path)?;
+        if meta.uid() != 0 || meta.mode() & 0o077 != 0 {
+            return Err(anyhow!(
+                "secrets file {} must be owned by root and not accessible to group or others",
+                path.to_string_lossy()
+            ));
+        }
+
+        Ok(Self(toml::from_str(&fs::read_to_string(&path)?)?))
+    }
+
+    /// Returns the secret referenced by value or value itself if it is not a reference
+    pub fn resolve(&self, value: &str) -> anyhow::Result<String> {
+        match value.strip_prefix(SECRET_PREFIX) {
+            Some(name) => self.0.get(name).cloned().ok_or(anyhow!(
+                "secret not found: {name} (secrets are only readable once boot is mounted)"
+            )),
+            None => Ok(value.into()),
+        }
+    }
+
+    pub fn resolve_opt(&self, value: &mut Option<String>) -> anyhow::Result<()> {
+        if let Some(v) = value {
+            *v = self.resolve(v)?;
+        }
+        Ok(())
+    }
+
+    pub fn resolve_path(&self, value: &mut Option<PathBuf>) -> anyhow::Result<()> {
+        if let Some(v) = value {
+            *v = self.resolve(&v.to_string_lossy())?.into();
+        }
+        Ok(())
+    }
+
+    /// Whether `value` is a secret reference
+    pub fn is_reference<P: AsRef<Path>>(value: P) -> bool {
+        value.as_ref().to_string_lossy().starts_with(SECRET_PREFIX)
+    }
+}
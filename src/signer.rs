@@ -0,0 +1,293 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{boot, command, i18n, pool, prompt, sysroot, timing, SBCTL_DIR};
+
+// default NSS database of pesign (efikeygen)
+const PESIGN_NSS_DB: &str = "/etc/pki/pesign";
+
+// token PIN is asked once even if several signers are built
+static PIN: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Kind {
+    #[default]
+    Sbctl,
+    // for distributions not shipping sbctl
+    Sbsign,
+    // Fedora/RHEL signing tool using an NSS database
+    Pesign,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub backend: Kind,
+    // signing key and certificate used by sbsign, relative paths are
+    // resolved against boot mountpoint so that keys stay encrypted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    // key held by a hardware token (i.e. pkcs11:token=YubiKey;object=db),
+    // replaces key so that it never touches disk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pkcs11_uri: Option<String>,
+    // NSS database and certificate nickname used by pesign
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nss_db: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<String>,
+}
+
+/// Secure boot signing backend
+pub(crate) trait Signer {
+    /// Signs `file`, backends keeping a database of files remember it so
+    /// that [Signer::sign_all] keeps it signed
+    fn sign(&self, file: &Path) -> anyhow::Result<()>;
+
+    /// Signs every EFI binary known to the backend along with `files`
+    /// installed by cryptboot (i.e. bootloader stubs, unified kernel image)
+    fn sign_all(&self, cfg: &boot::Config, files: &[PathBuf]) -> anyhow::Result<()>;
+
+    /// Files known to the backend which are not signed
+    fn unsigned(&self, cfg: &boot::Config) -> anyhow::Result<Vec<String>>;
+
+    /// Identifies signing keys so that key changes are detected, None when
+    /// keys cannot be identified
+    fn fingerprint(&self) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+impl Config {
+    /// Builds the configured backend, boot must be mounted for keys to be readable
+    pub fn signer(&self, cfg: &boot::Config) -> anyhow::Result<Box<dyn Signer>> {
+        match self.backend {
+            Kind::Sbctl => Ok(Box::new(Sbctl(sysroot::path(SBCTL_DIR)))),
+            Kind::Sbsign => {
+                let Some(cert) = &self.cert else {
+                    return Err(anyhow!("sbsign signer requires cert"));
+                };
+                let key = match (&self.pkcs11_uri, &self.key) {
+                    (Some(uri), _) => SbsignKey::Pkcs11 {
+                        uri: uri.clone(),
+                        ask_pin: ask_pin(uri, cfg),
+                    },
+                    (None, Some(key)) => SbsignKey::File(cfg.mountpoint.join(key)),
+                    (None, None) => {
+                        return Err(anyhow!("sbsign signer requires key or pkcs11_uri"))
+                    }
+                };
+                Ok(Box::new(Sbsign {
+                    key,
+                    cert: cfg.mountpoint.join(cert),
+                }))
+            }
+            Kind::Pesign => {
+                let Some(certificate) = &self.certificate else {
+                    return Err(anyhow!("pesign signer requires certificate"));
+                };
+                Ok(Box::new(Pesign {
+                    nss_db: cfg
+                        .mountpoint
+                        .join(self.nss_db.as_deref().unwrap_or(Path::new(PESIGN_NSS_DB))),
+                    certificate: certificate.clone(),
+                }))
+            }
+        }
+    }
+}
+
+/// sbctl keeps its keys and a database of files to sign in its directory
+pub(crate) struct Sbctl(PathBuf);
+
+impl Signer for Sbctl {
+    fn sign(&self, file: &Path) -> anyhow::Result<()> {
+        command::sbctl_sign(file)
+    }
+
+    fn sign_all(&self, _cfg: &boot::Config, files: &[PathBuf]) -> anyhow::Result<()> {
+        // saved to the database so that sign-all keeps them signed
+        for f in files {
+            command::sbctl_sign(f)?;
+        }
+        command::sbctl(&["sign-all"])
+    }
+
+    fn unsigned(&self, _cfg: &boot::Config) -> anyhow::Result<Vec<String>> {
+        command::sbctl_unsigned()
+    }
+
+    fn fingerprint(&self) -> anyhow::Result<Option<String>> {
+        let (guid, db) = (self.0.join("GUID"), self.0.join("keys/db/db.pem"));
+        if !db.is_file() {
+            return Ok(None);
+        }
+        let guid = fs::read_to_string(guid).unwrap_or_default();
+        Ok(Some(format!("{}:{}", guid.trim(), command::sha256sum(db)?)))
+    }
+}
+
+/// Signs with sbsign every EFI binary found on EFI partition, as there is no
+/// database of files to sign
+pub(crate) struct Sbsign {
+    key: SbsignKey,
+    cert: PathBuf,
+}
+
+// without terminal the pkcs11 engine cannot prompt for the PIN, so it is
+// asked through password agents
+fn ask_pin(uri: &str, cfg: &boot::Config) -> bool {
+    let has_pin = uri.contains("pin-value=") || uri.contains("pin-source=");
+    !has_pin && prompt::use_agent(cfg.ask_password)
+}
+
+// PIN is asked when first signing, not when building a signer only listing files
+fn pkcs11_pin(uri: &str) -> anyhow::Result<String> {
+    let mut cached = PIN.lock().unwrap();
+    if let Some(pin) = cached.as_ref() {
+        return Ok(pin.clone());
+    }
+    let pin = command::ask_password("pkcs11", i18n::tr("ask-pin", &[("uri", uri)]), false)?;
+    Ok(cached.insert(pin).clone())
+}
+
+enum SbsignKey {
+    File(PathBuf),
+    // signing happens on the token, which may require a touch
+    Pkcs11 { uri: String, ask_pin: bool },
+}
+
+fn efi_binaries(dir: &Path, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        let path = e.path();
+        if e.file_type()?.is_dir() {
+            efi_binaries(&path, out)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("efi"))
+        {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// EFI binaries found on EFI partition, which backends without database of
+/// files sign
+pub(crate) fn esp_binaries(cfg: &boot::Config) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    efi_binaries(&cfg.efi.mountpoint, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+// signs EFI binaries of EFI partition and `files` on up to `jobs` threads
+// reporting progress
+fn sign_esp<S: Signer + Sync + ?Sized>(
+    signer: &S,
+    cfg: &boot::Config,
+    files: &[PathBuf],
+    jobs: usize,
+) -> anyhow::Result<()> {
+    let mut all = esp_binaries(cfg)?;
+    all.extend(files.iter().filter(|f| f.is_file()).cloned());
+    all.sort();
+    all.dedup();
+    pool::map("sign", &all, jobs, |f| signer.sign(f))?;
+    Ok(())
+}
+
+// EFI binaries of EFI partition for which `signed` is false, checked concurrently
+fn unsigned_esp<F>(cfg: &boot::Config, signed: F) -> anyhow::Result<Vec<String>>
+where
+    F: Fn(&Path) -> anyhow::Result<bool> + Sync,
+{
+    let unsigned = pool::map("verify", &esp_binaries(cfg)?, pool::jobs(), |f| {
+        Ok((!signed(f)?).then(|| f.to_string_lossy().to_string()))
+    })?;
+    Ok(unsigned.into_iter().flatten().collect())
+}
+
+impl Signer for Sbsign {
+    fn sign(&self, file: &Path) -> anyhow::Result<()> {
+        match &self.key {
+            SbsignKey::File(key) => command::sbsign(None, key, &self.cert, file)?,
+            SbsignKey::Pkcs11 { uri, ask_pin } if !ask_pin || command::dry_run() => {
+                command::sbsign(Some("pkcs11"), uri, &self.cert, file)?
+            }
+            // the PIN is passed in a file only root can read, which exists
+            // only while signing
+            SbsignKey::Pkcs11 { uri, .. } => {
+                let pin = pkcs11_pin(uri)?;
+                let pin_file = PathBuf::from(format!("/run/cryptboot-pin-{}", std::process::id()));
+                boot::write_keyfile(&pin_file, &pin)?;
+                // pin-source is a query attribute (RFC 7512)
+                let sep = if uri.contains('?') { '&' } else { '?' };
+                let uri = format!("{uri}{sep}pin-source=file:{}", pin_file.to_string_lossy());
+                let res = command::sbsign(Some("pkcs11"), uri, &self.cert, file);
+                let _ = fs::remove_file(&pin_file);
+                res?
+            }
+        }
+        timing::count("files signed", 1);
+
+        if !command::dry_run() && !command::sbverify(&self.cert, file)? {
+            return Err(anyhow!(
+                "signature verification failed: {}",
+                file.to_string_lossy()
+            ));
+        }
+        Ok(())
+    }
+
+    fn sign_all(&self, cfg: &boot::Config, files: &[PathBuf]) -> anyhow::Result<()> {
+        // tokens sign one file at a time
+        let jobs = match self.key {
+            SbsignKey::File(_) => pool::jobs(),
+            SbsignKey::Pkcs11 { .. } => 1,
+        };
+        sign_esp(self, cfg, files, jobs)
+    }
+
+    fn unsigned(&self, cfg: &boot::Config) -> anyhow::Result<Vec<String>> {
+        unsigned_esp(cfg, |f| command::sbverify(&self.cert, f))
+    }
+
+    fn fingerprint(&self) -> anyhow::Result<Option<String>> {
+        Ok(Some(command::sha256sum(&self.cert)?))
+    }
+}
+
+/// Signs with pesign every EFI binary found on EFI partition, keys being
+/// held in an NSS database (i.e. created with efikeygen)
+pub(crate) struct Pesign {
+    nss_db: PathBuf,
+    certificate: String,
+}
+
+impl Signer for Pesign {
+    fn sign(&self, file: &Path) -> anyhow::Result<()> {
+        command::pesign(&self.nss_db, &self.certificate, file)?;
+        timing::count("files signed", 1);
+        Ok(())
+    }
+
+    fn sign_all(&self, cfg: &boot::Config, files: &[PathBuf]) -> anyhow::Result<()> {
+        // pesign serializes access to the NSS database itself
+        sign_esp(self, cfg, files, 1)
+    }
+
+    fn unsigned(&self, cfg: &boot::Config) -> anyhow::Result<Vec<String>> {
+        unsigned_esp(cfg, |f| command::pesign_signed(f))
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{command, events, sysroot};
+
+const STATE_PATH: &str = "/var/lib/cryptboot/state.toml";
+// seconds during which probe results are reused
+const PROBE_TTL: u64 = 60;
+
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = OsString::from(path.as_os_str());
+    p.push(suffix);
+    PathBuf::from(p)
+}
+
+/// Writes a file so that a crash never leaves it partially written: content
+/// goes to a temporary file which is synced and renamed over the target. If
+/// `backup` is true the previous version is kept with a .bak suffix to
+/// recover from corruption.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    content: C,
+    backup: bool,
+) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if command::dry_run() {
+        command::dry_run_note(format!("write {}", path.to_string_lossy()));
+        return Ok(());
+    }
+
+    let tmp = with_suffix(path, ".tmp");
+
+    let mut f = File::create(&tmp)?;
+    f.write_all(content.as_ref())?;
+    f.sync_all()?;
+    drop(f);
+
+    if backup && path.exists() {
+        fs::copy(path, with_suffix(path, ".bak"))?;
+    }
+
+    fs::rename(&tmp, path)?;
+
+    // rename must be persisted too
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Exclusive lock released when the returned file is dropped
+fn lock_file<P: AsRef<Path>>(path: P) -> anyhow::Result<File> {
+    let f = File::create(path)?;
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(f)
+}
+
+/// Seconds since UNIX epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the result of probe `key` cached less than PROBE_TTL seconds ago
+/// or runs `f` and caches its result. Package manager hooks often run several
+/// times per transaction so we avoid running the same probes again and again.
+/// Only informational probes may be cached, device identity checks are not.
+pub fn cached_probe<F>(key: &str, f: F) -> anyhow::Result<Vec<String>>
+where
+    F: FnOnce() -> anyhow::Result<Vec<String>>,
+{
+    let now = now();
+    if let Some(p) = State::load().ok().and_then(|s| s.probes.get(key).cloned()) {
+        if now.saturating_sub(p.at) < PROBE_TTL {
+            return Ok(p.values);
+        }
+    }
+
+    let values = f()?;
+    let res = State::update(|s| {
+        // expired entries are dropped so that state does not grow forever
+        s.probes.retain(|_, p| now.saturating_sub(p.at) < PROBE_TTL);
+        s.probes.insert(
+            key.into(),
+            Probe {
+                values: values.clone(),
+                at: now,
+            },
+        );
+    });
+    if let Err(e) = res {
+        events::warn(format!("failed to cache probe {key}: {e}"));
+    }
+    Ok(values)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Relocation {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bootloader {
+    pub version: String,
+    pub installed_at: u64,
+}
+
+/// Inputs of the last grub core image build, see reproduce-check
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GrubBuild {
+    pub version: String,
+    pub target: String,
+    pub modules: Vec<String>,
+    // sha256 of the embedded config and memdisk
+    pub early_config: Option<String>,
+    pub memdisk: Option<String>,
+    // sha256 of an image built from these inputs
+    pub image: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BootChain {
+    // no boot chain installed by cryptboot
+    #[default]
+    Unknown,
+    // installed but not yet marked as good
+    Pending,
+    // marked as good after a successful boot
+    Good,
+}
+
+/// Package manager transaction sharing a single mount window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub started_at: u64,
+    // signing requested by commands run within the transaction
+    pub sign_pending: bool,
+}
+
+/// Result of an external probe (blkid, sbctl ...) cached between invocations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Probe {
+    pub values: Vec<String>,
+    pub at: u64,
+}
+
+/// Outcome of the check made after unmounting boot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UmountCheck {
+    pub at: u64,
+    // mapper and mounts still active, empty when nothing is left exposed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub leftovers: Vec<String>,
+}
+
+/// What cryptboot did to the system
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub relocations: Vec<Relocation>,
+    pub managed_files: Vec<PathBuf>,
+    pub bootloader: Option<Bootloader>,
+    pub grub_build: Option<GrubBuild>,
+    pub boot_chain: BootChain,
+    pub last_sign: Option<u64>,
+    // fingerprint of the keys used by the last signature
+    pub signing_keys: Option<String>,
+    // signing keys changed since the last signature, files must be signed again
+    pub stale_signatures: bool,
+    // version strings of the kernel images signed by the last signature
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub signed_kernels: Vec<String>,
+    // time at which boot was decrypted, set only while it is mounted
+    pub mounted_at: Option<u64>,
+    // duration in seconds of the last time boot was decrypted
+    pub last_exposure: Option<u64>,
+    // consecutive failed attempts to unlock boot
+    pub failed_unlocks: u32,
+    pub last_failed_unlock: Option<u64>,
+    pub transaction: Option<Transaction>,
+    pub last_umount: Option<UmountCheck>,
+    // cached probe results, see [cached_probe]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub probes: BTreeMap<String, Probe>,
+}
+
+impl State {
+    pub fn path() -> PathBuf {
+        sysroot::path(STATE_PATH)
+    }
+
+    fn parse(path: &Path) -> anyhow::Result<Self> {
+        toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| anyhow!("failed to parse state file {}: {e}", path.to_string_lossy()))
+    }
+
+    /// Loads state, a missing file means nothing was done yet. A corrupted
+    /// state file is recovered from its backup.
+    pub fn load() -> anyhow::Result<Self> {
+        let path = &Self::path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Self::parse(path).or_else(|e| {
+            let bak = with_suffix(path, ".bak");
+            if !bak.exists() {
+                return Err(e);
+            }
+            events::warn(format!("{e}, recovering from {}", bak.to_string_lossy()));
+            Self::parse(&bak)
+        })
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let path = &Self::path();
+        // a corrupted state must not replace a good backup
+        let backup = Self::parse(path).is_ok();
+        write_atomic(path, toml::to_string(self)?, backup)
+    }
+
+    /// Loads state, applies `f` and saves the result while holding a lock
+    /// so that concurrent updates are not lost
+    pub fn update<F: FnOnce(&mut Self)>(f: F) -> anyhow::Result<()> {
+        // state is not saved in dry-run mode
+        if command::dry_run() {
+            f(&mut Self::load()?);
+            return Ok(());
+        }
+
+        let path = &Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let _lock = lock_file(with_suffix(path, ".lock"))?;
+        let mut state = Self::load()?;
+        f(&mut state);
+        state.save()
+    }
+
+    pub fn add_relocation(&mut self, src: &Path, dst: &Path) {
+        let r = Relocation {
+            src: src.into(),
+            dst: dst.into(),
+        };
+        if !self.relocations.contains(&r) {
+            self.relocations.push(r);
+        }
+    }
+
+    pub fn remove_relocation(&mut self, src: &Path, dst: &Path) {
+        self.relocations.retain(|r| r.src != src || r.dst != dst);
+    }
+
+    pub fn exposure_started(&mut self) {
+        self.mounted_at = Some(now());
+    }
+
+    pub fn exposure_ended(&mut self) {
+        if let Some(start) = self.mounted_at.take() {
+            self.last_exposure = Some(now().saturating_sub(start));
+        }
+    }
+
+    pub fn unlock_failed(&mut self) {
+        self.failed_unlocks += 1;
+        self.last_failed_unlock = Some(now());
+    }
+
+    pub fn unlock_succeeded(&mut self) {
+        self.failed_unlocks = 0;
+        self.last_failed_unlock = None;
+    }
+
+    /// Drops cached probes whose key starts with `prefix`
+    pub fn invalidate_probes(&mut self, prefix: &str) {
+        self.probes.retain(|k, _| !k.starts_with(prefix));
+    }
+
+    pub fn add_managed_file<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref().to_path_buf();
+        if !self.managed_files.contains(&path) {
+            self.managed_files.push(path);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+static SYSROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Host paths (configuration, state, /etc/default/grub, relocated directories
+/// ...) are resolved under `root` so that an offline root filesystem tree can
+/// be configured without chrooting into it
+pub fn set<P: AsRef<Path>>(root: P) {
+    *SYSROOT.lock().unwrap() = Some(root.as_ref().into());
+}
+
+/// Resolves absolute host path `p` under sysroot
+pub fn path<P: AsRef<Path>>(p: P) -> PathBuf {
+    let p = p.as_ref();
+    match (SYSROOT.lock().unwrap().as_ref(), p.strip_prefix("/")) {
+        (Some(root), Ok(rel)) if !p.starts_with(root) => root.join(rel),
+        _ => p.into(),
+    }
+}
+
+/// Path `p` as seen from within sysroot, symlinks and state must not
+/// reference the sysroot location
+pub fn strip<P: AsRef<Path>>(p: P) -> PathBuf {
+    let p = p.as_ref();
+    match SYSROOT.lock().unwrap().as_ref() {
+        Some(root) => p
+            .strip_prefix(root)
+            .map(|rel| Path::new("/").join(rel))
+            .unwrap_or_else(|_| p.into()),
+        None => p.into(),
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+
+use crate::{boot, state};
+
+// units mounting boot and efi rely on the mapper opened by this service
+const UNLOCK_SERVICE: &str = "cryptboot-unlock.service";
+
+/// Unit name of mountpoint `path` with suffix `kind` (i.e. boot-efi.mount),
+/// as systemd-escape --path does
+pub fn unit_name(path: &Path, kind: &str) -> String {
+    let mut name = String::new();
+    for (i, b) in path.to_string_lossy().trim_matches('/').bytes().enumerate() {
+        match b {
+            b'/' => name.push('-'),
+            b'.' if i == 0 => name.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' => name.push(b as char),
+            b => name.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    if name.is_empty() {
+        name.push('-');
+    }
+    format!("{name}.{kind}")
+}
+
+fn mount_unit(
+    what: &Path,
+    r#where: &Path,
+    fstype: Option<&str>,
+    options: &str,
+    deps: &str,
+) -> String {
+    let options = match options.is_empty() {
+        true => String::new(),
+        false => format!("Options={options}\n"),
+    };
+    format!(
+        "[Unit]\nDescription=Encrypted boot ({})\n{deps}\n\n[Mount]\nWhat={}\nWhere={}\nType={}\n{options}",
+        r#where.to_string_lossy(),
+        what.to_string_lossy(),
+        r#where.to_string_lossy(),
+        fstype.unwrap_or("auto"),
+    )
+}
+
+fn automount_unit(r#where: &Path, idle: u64) -> String {
+    format!(
+        "[Unit]\nDescription=Encrypted boot ({}) on demand\n\n[Automount]\nWhere={}\nTimeoutIdleSec={idle}\n\n[Install]\nWantedBy=local-fs.target\n",
+        r#where.to_string_lossy(),
+        r#where.to_string_lossy(),
+    )
+}
+
+/// Units mounting boot and efi the first time they are accessed and
+/// unmounting them after `idle` seconds, as (file name, content)
+pub fn automount_units(cfg: &boot::Config, cryptboot: &Path, idle: u64) -> Vec<(String, String)> {
+    let (boot, efi) = (&cfg.mountpoint, &cfg.efi.mountpoint);
+    let boot_mount = unit_name(boot, "mount");
+
+    // the passphrase is asked through password agents and the mapper is
+    // closed once boot is unmounted
+    let service = format!(
+        "[Unit]\nDescription=Unlock encrypted boot\nDefaultDependencies=no\nStopWhenUnneeded=yes\n\n[Service]\nType=oneshot\nRemainAfterExit=yes\nExecStart={} mount --unlock-only\nExecStop=/usr/bin/cryptsetup close {}\n",
+        cryptboot.to_string_lossy(),
+        cfg.mapper_name(),
+    );
+
+    vec![
+        (UNLOCK_SERVICE.into(), service),
+        (
+            boot_mount.clone(),
+            mount_unit(
+                &PathBuf::from("/dev/mapper").join(cfg.mapper_name()),
+                boot,
+                cfg.fstype.as_deref(),
+                &cfg.mount_options,
+                &format!(
+                    "Requires={UNLOCK_SERVICE}\nAfter={UNLOCK_SERVICE}\nBindsTo={UNLOCK_SERVICE}"
+                ),
+            ),
+        ),
+        (unit_name(boot, "automount"), automount_unit(boot, idle)),
+        (
+            unit_name(efi, "mount"),
+            mount_unit(
+                &cfg.efi.device.full_path(),
+                efi,
+                cfg.efi.fstype.as_deref(),
+                &cfg.efi.mount_options,
+                &format!("Requires={boot_mount}\nAfter={boot_mount}"),
+            ),
+        ),
+        (unit_name(efi, "automount"), automount_unit(efi, idle)),
+    ]
+}
+
+/// Writes units in `dir`, returning their names
+pub fn install(dir: &Path, units: &[(String, String)]) -> anyhow::Result<Vec<String>> {
+    for (name, content) in units {
+        state::write_atomic(dir.join(name), content, false)?;
+    }
+    Ok(units.iter().map(|(n, _)| n.clone()).collect())
+}
@@ -0,0 +1,56 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::events::{self, Event};
+
+// steps are recorded globally as they happen at different layers
+static STEPS: Mutex<Vec<(String, Duration)>> = Mutex::new(vec![]);
+static COUNTERS: Mutex<Vec<(String, u64)>> = Mutex::new(vec![]);
+
+/// Runs `f` and records how long it took under `step`
+pub fn time<T, F: FnOnce() -> T>(step: &str, f: F) -> T {
+    events::emit(Event::StepStarted { step });
+    let start = Instant::now();
+    let res = f();
+    let elapsed = start.elapsed();
+    STEPS.lock().unwrap().push((step.into(), elapsed));
+    events::emit(Event::StepFinished {
+        step,
+        seconds: elapsed.as_secs_f64(),
+    });
+    res
+}
+
+/// Adds `n` to counter `name`
+pub fn count(name: &str, n: u64) {
+    let mut counters = COUNTERS.lock().unwrap();
+    match counters.iter_mut().find(|(c, _)| c == name) {
+        Some((_, v)) => *v += n,
+        None => counters.push((name.into(), n)),
+    }
+}
+
+pub fn steps() -> Vec<(String, Duration)> {
+    STEPS.lock().unwrap().clone()
+}
+
+pub fn counters() -> Vec<(String, u64)> {
+    COUNTERS.lock().unwrap().clone()
+}
+
+/// Human readable summary of recorded steps and counters
+pub fn summary() -> String {
+    let steps = STEPS.lock().unwrap();
+    let width = steps.iter().map(|(s, _)| s.len()).max().unwrap_or_default();
+
+    let mut out = String::new();
+    for (step, d) in steps.iter() {
+        out.push_str(&format!("{step:width$}  {:.1}s\n", d.as_secs_f64()));
+    }
+    for (name, n) in COUNTERS.lock().unwrap().iter() {
+        out.push_str(&format!("{name}: {n}\n"));
+    }
+    out
+}
@@ -0,0 +1,83 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+const SYS_BLOCK: &str = "/sys/class/block";
+
+// kernel name of a block device (i.e. /dev/mapper/root -> dm-0)
+fn kernel_name<P: AsRef<Path>>(dev: P) -> anyhow::Result<String> {
+    let dev = dev.as_ref();
+    fs::canonicalize(dev)?
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or(anyhow!("invalid block device: {}", dev.to_string_lossy()))
+}
+
+fn disks_by_name(name: &str, out: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let sys = PathBuf::from(SYS_BLOCK).join(name);
+
+    // device mapper, md ... are stacked on top of other devices
+    let slaves: Vec<_> = match fs::read_dir(sys.join("slaves")) {
+        Ok(rd) => rd.collect::<Result<_, _>>()?,
+        Err(_) => vec![],
+    };
+    if !slaves.is_empty() {
+        for s in slaves {
+            disks_by_name(&s.file_name().to_string_lossy(), out)?;
+        }
+        return Ok(());
+    }
+
+    // partitions live under their disk in sysfs
+    let disk = if sys.join("partition").exists() {
+        fs::canonicalize(&sys)?
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().to_string())
+            .ok_or(anyhow!("failed to find disk of {name}"))?
+    } else {
+        name.to_string()
+    };
+
+    let disk = PathBuf::from("/dev").join(disk);
+    if !out.contains(&disk) {
+        out.push(disk);
+    }
+    Ok(())
+}
+
+/// Returns the disks holding a block device, following partitions and stacked
+/// devices (device mapper, raid) down to the physical disks
+pub fn disks<P: AsRef<Path>>(dev: P) -> anyhow::Result<Vec<PathBuf>> {
+    let mut out = vec![];
+    disks_by_name(&kernel_name(dev)?, &mut out)?;
+    Ok(out)
+}
+
+/// Returns true if both devices have at least one disk in common
+pub fn share_disk<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> anyhow::Result<bool> {
+    let b = disks(b)?;
+    Ok(disks(a)?.iter().any(|d| b.contains(d)))
+}
+
+/// Returns the disk and the partition number of partition `dev`
+pub fn partition<P: AsRef<Path>>(dev: P) -> anyhow::Result<(PathBuf, u32)> {
+    let dev = dev.as_ref();
+    let name = kernel_name(dev)?;
+    let sys = PathBuf::from(SYS_BLOCK).join(&name);
+
+    let number = fs::read_to_string(sys.join("partition"))
+        .map_err(|_| anyhow!("not a partition: {}", dev.to_string_lossy()))?
+        .trim()
+        .parse()?;
+
+    let mut disks = vec![];
+    disks_by_name(&name, &mut disks)?;
+    let disk = disks
+        .pop()
+        .ok_or(anyhow!("failed to find disk of {name}"))?;
+    Ok((disk, number))
+}
@@ -0,0 +1,151 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{boot, command, events, kernel, signer, state, sysroot};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // append-only log of signed artifacts, disabled when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    // URL new entries are posted to (one per request)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+}
+
+/// Signed artifact recorded in the log, written as one line:
+/// `<time> <sha256> <hash of previous line or -> <path>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub at: u64,
+    pub sha256: String,
+    // chains entries so that removing or editing one is detected
+    pub prev: Option<String>,
+    pub path: String,
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prev = self.prev.as_deref().unwrap_or("-");
+        write!(f, "{} {} {prev} {}", self.at, self.sha256, self.path)
+    }
+}
+
+impl std::str::FromStr for Entry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(4, ' ');
+        let mut next = || fields.next().ok_or(anyhow!("invalid log entry: {s}"));
+        Ok(Self {
+            at: next()?.parse()?,
+            sha256: next()?.into(),
+            prev: Some(next()?).filter(|p| *p != "-").map(String::from),
+            path: next()?.into(),
+        })
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(s.lines().map(String::from).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Checks the chain of log entries, returning their count
+pub fn verify<P: AsRef<Path>>(path: P) -> anyhow::Result<usize> {
+    let lines = read(path.as_ref())?;
+    let mut prev = None;
+    for (i, l) in lines.iter().enumerate() {
+        let entry: Entry = l.parse()?;
+        if entry.prev != prev {
+            return Err(anyhow!(
+                "log entry {} does not follow the previous one",
+                i + 1
+            ));
+        }
+        prev = Some(command::sha256(l.as_bytes())?);
+    }
+    Ok(lines.len())
+}
+
+impl Config {
+    /// Appends the signed artifacts of boot and efi partitions whose hash
+    /// changed since they were last logged
+    pub fn record(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let path = sysroot::path(path);
+
+        let lines = read(&path)?;
+        let entries = lines
+            .iter()
+            .map(|l| l.parse())
+            .collect::<anyhow::Result<Vec<Entry>>>()?;
+        let mut prev = match lines.last() {
+            Some(l) => Some(command::sha256(l.as_bytes())?),
+            None => None,
+        };
+
+        let mut files = signer::esp_binaries(cfg)?;
+        files.extend(kernel::images(cfg)?);
+        let mut new = vec![];
+        for f in files {
+            if !kernel::is_signed(&fs::read(&f)?) {
+                continue;
+            }
+            let sha256 = command::sha256sum(&f)?;
+            let path = f.to_string_lossy().to_string();
+            let last = entries.iter().rev().find(|e| e.path == path);
+            if last.is_some_and(|e| e.sha256 == sha256) {
+                continue;
+            }
+            let entry = Entry {
+                at: state::now(),
+                sha256,
+                prev: prev.take(),
+                path,
+            };
+            let line = entry.to_string();
+            prev = Some(command::sha256(line.as_bytes())?);
+            new.push(line);
+        }
+
+        if new.is_empty() {
+            return Ok(());
+        }
+        if command::dry_run() {
+            command::dry_run_note(format!(
+                "append {} entries to {}",
+                new.len(),
+                path.to_string_lossy()
+            ));
+            return Ok(());
+        }
+
+        let mut f = OpenOptions::new().create(true).append(true).open(&path)?;
+        for l in &new {
+            writeln!(f, "{l}")?;
+        }
+        f.sync_all()?;
+
+        // the local log is authoritative, remote copy is best effort
+        if let Some(url) = &self.remote {
+            let body = new.join("\n") + "\n";
+            if let Err(e) = command::curl_post(url, body.as_bytes()) {
+                events::warn(format!("failed to post transparency log entries: {e}"));
+            }
+        }
+        Ok(())
+    }
+}
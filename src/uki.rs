@@ -0,0 +1,184 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot,
+    command::{self, command},
+    sysroot,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Builder {
+    #[default]
+    Ukify,
+    // for systems without ukify, sections are added to systemd-stub
+    Objcopy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub builder: Builder,
+    // kernel and initramfs images, relative to boot mountpoint
+    pub kernel: PathBuf,
+    pub initrd: Vec<PathBuf>,
+    pub cmdline: String,
+    pub os_release: PathBuf,
+    // bmp image shown while booting
+    pub splash: Option<PathBuf>,
+    pub stub: PathBuf,
+    // file name of the image in EFI/Linux, where systemd-boot discovers it
+    pub name: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            builder: Builder::default(),
+            kernel: "vmlinuz-linux".into(),
+            initrd: vec!["initramfs-linux.img".into()],
+            cmdline: String::new(),
+            os_release: "/etc/os-release".into(),
+            splash: None,
+            stub: "/usr/lib/systemd/boot/efi/linuxx64.efi.stub".into(),
+            name: "linux.efi".into(),
+        }
+    }
+}
+
+/// Unified kernel image bundling kernel, initramfs and command line in a
+/// single signed EFI binary, so that nothing unsigned is loaded from EFI
+/// partition and the whole boot payload can be measured
+pub(crate) struct Uki(Config);
+
+impl Uki {
+    pub fn from_config(config: Config) -> Self {
+        Self(config)
+    }
+
+    /// Where the image is written
+    pub fn output(&self, cfg: &boot::Config) -> PathBuf {
+        cfg.efi
+            .mountpoint
+            .join("EFI")
+            .join("Linux")
+            .join(&self.0.name)
+    }
+
+    fn check_inputs(&self, cfg: &boot::Config) -> anyhow::Result<()> {
+        let mut inputs = vec![cfg.mountpoint.join(&self.0.kernel), self.0.stub.clone()];
+        inputs.extend(self.0.initrd.iter().map(|i| cfg.mountpoint.join(i)));
+        inputs.push(sysroot::path(&self.0.os_release));
+        inputs.extend(self.0.splash.clone());
+
+        for i in inputs {
+            if !i.is_file() {
+                return Err(anyhow!("uki input not found: {}", i.to_string_lossy()));
+            }
+        }
+        Ok(())
+    }
+
+    fn ukify(&self, cfg: &boot::Config, output: &Path) -> anyhow::Result<()> {
+        let mut cmd = command("ukify");
+        cmd.arg("build")
+            .arg(format!(
+                "--linux={}",
+                cfg.mountpoint.join(&self.0.kernel).to_string_lossy()
+            ))
+            .arg(format!("--stub={}", self.0.stub.to_string_lossy()))
+            .arg(format!(
+                "--os-release=@{}",
+                sysroot::path(&self.0.os_release).to_string_lossy()
+            ))
+            .arg(format!("--cmdline={}", self.0.cmdline))
+            .arg(format!("--output={}", output.to_string_lossy()));
+        for i in self.0.initrd.iter() {
+            cmd.arg(format!(
+                "--initrd={}",
+                cfg.mountpoint.join(i).to_string_lossy()
+            ));
+        }
+        if let Some(splash) = &self.0.splash {
+            cmd.arg(format!("--splash={}", splash.to_string_lossy()));
+        }
+
+        let status = command::status(&mut cmd)?;
+        if !status.success() {
+            return Err(anyhow!("ukify build failed: {status}"));
+        }
+        Ok(())
+    }
+
+    fn objcopy(&self, cfg: &boot::Config, output: &Path) -> anyhow::Result<()> {
+        // command line and concatenated initramfs need files, they are kept on
+        // encrypted boot while building
+        let work = cfg.mountpoint.join("cryptboot").join("uki");
+        let (cmdline, initrd) = (work.join("cmdline"), work.join("initrd"));
+        if !command::dry_run() {
+            fs::create_dir_all(&work)?;
+            fs::write(&cmdline, format!("{}\0", self.0.cmdline))?;
+            let mut f = fs::File::create(&initrd)?;
+            for i in self.0.initrd.iter() {
+                f.write_all(&fs::read(cfg.mountpoint.join(i))?)?;
+            }
+        }
+
+        // section addresses must not overlap, kernel and initramfs come last
+        // as they are the largest
+        let mut sections = vec![
+            (".osrel", sysroot::path(&self.0.os_release), "0x20000"),
+            (".cmdline", cmdline, "0x30000"),
+        ];
+        if let Some(splash) = &self.0.splash {
+            sections.push((".splash", splash.clone(), "0x40000"));
+        }
+        sections.push((".linux", cfg.mountpoint.join(&self.0.kernel), "0x2000000"));
+        sections.push((".initrd", initrd, "0x3000000"));
+
+        let mut cmd = command("objcopy");
+        for (name, file, vma) in sections {
+            cmd.arg("--add-section")
+                .arg(format!("{name}={}", file.to_string_lossy()))
+                .arg("--change-section-vma")
+                .arg(format!("{name}={vma}"));
+        }
+        cmd.arg(&self.0.stub).arg(output);
+
+        let res = command::status(&mut cmd);
+        if !command::dry_run() {
+            fs::remove_dir_all(&work)?;
+        }
+        let status = res?;
+        if !status.success() {
+            return Err(anyhow!("objcopy failed: {status}"));
+        }
+        Ok(())
+    }
+
+    /// Builds the image on EFI partition, it still needs to be signed
+    pub fn build(&self, cfg: &boot::Config) -> anyhow::Result<PathBuf> {
+        self.check_inputs(cfg)?;
+
+        let output = self.output(cfg);
+        if let Some(parent) = output.parent() {
+            if !command::dry_run() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        match self.0.builder {
+            Builder::Ukify => self.ukify(cfg, &output)?,
+            Builder::Objcopy => self.objcopy(cfg, &output)?,
+        }
+
+        Ok(output)
+    }
+}